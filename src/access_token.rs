@@ -0,0 +1,66 @@
+use std::time::{Duration, SystemTime};
+
+use openidconnect::core::{CoreIdTokenClaims, CoreJsonWebKeySet, CoreJwsSigningAlgorithm};
+use openidconnect::IssuerUrl;
+use serde::Deserialize;
+
+use crate::jws::{verify_signature, verify_times, Audiences};
+
+/// Media types that identify a JWT as an [OAuth 2.0 access
+/// token](https://www.rfc-editor.org/rfc/rfc9068#section-2.1).
+const ACCESS_TOKEN_TYPES: [&str; 2] = ["at+jwt", "application/at+jwt"];
+
+/// Expected issuer, audience, keys, and time used to validate a JWT
+/// access token that is presented as a bearer token.
+pub(crate) struct AccessTokenVerifier<'a> {
+    pub(crate) issuer: &'a IssuerUrl,
+    pub(crate) audience: &'a str,
+    pub(crate) keys: &'a CoreJsonWebKeySet,
+    pub(crate) allowed_algs: &'a [CoreJwsSigningAlgorithm],
+    pub(crate) now: SystemTime,
+    pub(crate) leeway: Duration,
+}
+
+impl AccessTokenVerifier<'_> {
+    /// Verifies the signature, type, and claims of a [JWT access
+    /// token](https://www.rfc-editor.org/rfc/rfc9068), returning its
+    /// claims. ID tokens are rejected by their type, even if they were
+    /// issued for the expected audience.
+    pub(crate) fn verify(&self, access_token: &str) -> Result<CoreIdTokenClaims, String> {
+        #[derive(Deserialize)]
+        struct Claims {
+            iss: IssuerUrl,
+            aud: Audiences,
+            iat: u64,
+            exp: u64,
+        }
+
+        let (header, payload) =
+            verify_signature(access_token, "access token", self.keys, self.allowed_algs)?;
+        if !matches!(
+            header.typ,
+            Some(typ) if ACCESS_TOKEN_TYPES.iter().any(|t| typ.eq_ignore_ascii_case(t))
+        ) {
+            return Err("token is not a JWT access token".to_string());
+        }
+
+        let claims: Claims = serde_json::from_slice(&payload)
+            .map_err(|error| format!("access token has invalid claims: {}", error))?;
+        if claims.iss != *self.issuer {
+            return Err("access token was issued by a different issuer".to_string());
+        }
+        if !claims.aud.contains(self.audience) {
+            return Err("access token was issued for a different audience".to_string());
+        }
+        verify_times(
+            "access token",
+            claims.iat,
+            Some(claims.exp),
+            self.now,
+            self.leeway,
+        )?;
+
+        serde_json::from_slice(&payload)
+            .map_err(|error| format!("access token has invalid claims: {}", error))
+    }
+}
@@ -0,0 +1,148 @@
+//! Authorization decisions.
+//!
+//! Authentication establishes *who* the user is; authorization decides
+//! what that user is allowed to do. The functions in this module make
+//! those decisions based on the scopes granted to the user and the
+//! claims asserted by the Identity Provider. They are pure functions --
+//! they do not depend on Tide requests or session state -- and so can
+//! be used (and tested) in isolation from the middleware:
+//!
+//! ```
+//! use serde_json::json;
+//! use tide_openidconnect::authorization::{authorize, AuthzError, Claims, Requirements};
+//!
+//! let claims = Claims::new(
+//!     vec!["openid".to_string(), "reports:read".to_string()],
+//!     json!({ "sub": "user", "groups": ["staff"] }),
+//! );
+//!
+//! let requirements = Requirements::new()
+//!     .with_scope("reports:read")
+//!     .with_claim("groups", "staff");
+//! assert_eq!(authorize(&claims, &requirements), Ok(()));
+//!
+//! let requirements = Requirements::new().with_claim("groups", "admin");
+//! assert_eq!(
+//!     authorize(&claims, &requirements),
+//!     Err(AuthzError::MissingClaim {
+//!         claim: "groups".to_string(),
+//!         value: "admin".to_string(),
+//!     })
+//! );
+//! ```
+
+use serde_json::Value;
+
+/// The scopes and claims on which authorization decisions are based.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Claims {
+    scopes: Vec<String>,
+    claims: Value,
+}
+
+impl Claims {
+    /// Create a new instance from the scopes granted to the user and
+    /// the user's claims, the latter of which should be a JSON object.
+    pub fn new(scopes: Vec<String>, claims: Value) -> Self {
+        Self { scopes, claims }
+    }
+
+    /// Returns the value of the given claim, or `None` if the user does
+    /// not have the claim.
+    pub fn get(&self, claim: &str) -> Option<&Value> {
+        self.claims.get(claim)
+    }
+
+    /// Returns `true` if the given scope was granted to the user.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    /// Returns `true` if the given claim has the given value, or --
+    /// for multi-valued claims such as `groups` or `roles` -- if the
+    /// claim contains the given value.
+    pub fn has_claim(&self, claim: &str, value: &str) -> bool {
+        fn matches(v: &Value, value: &str) -> bool {
+            match v {
+                Value::String(s) => s == value,
+                Value::Bool(b) => b.to_string() == value,
+                Value::Number(n) => n.to_string() == value,
+                _ => false,
+            }
+        }
+
+        match self.claims.get(claim) {
+            Some(Value::Array(values)) => values.iter().any(|v| matches(v, value)),
+            Some(v) => matches(v, value),
+            None => false,
+        }
+    }
+}
+
+/// Scopes and claims that a user must have in order to be authorized.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Requirements {
+    scopes: Vec<String>,
+    claims: Vec<(String, String)>,
+}
+
+impl Requirements {
+    /// Create a new, empty set of requirements (which authorizes every
+    /// user).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requires that the given scope was granted to the user.
+    pub fn with_scope(mut self, scope: impl AsRef<str>) -> Self {
+        self.scopes.push(scope.as_ref().to_string());
+        self
+    }
+
+    /// Requires that the given claim has (or contains) the given value.
+    pub fn with_claim(mut self, claim: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        self.claims
+            .push((claim.as_ref().to_string(), value.as_ref().to_string()));
+        self
+    }
+}
+
+/// Reasons for which a user was not authorized.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum AuthzError {
+    /// A required scope was not granted to the user.
+    #[error("Missing required scope `{0}`.")]
+    MissingScope(String),
+
+    /// A required claim was missing or did not have the required value.
+    #[error("Claim `{claim}` does not contain `{value}`.")]
+    MissingClaim {
+        /// Name of the claim.
+        claim: String,
+        /// Required value of the claim.
+        value: String,
+    },
+}
+
+/// Decides if a user with the given claims satisfies *all* of the
+/// given requirements, returning the first requirement that was not
+/// satisfied.
+pub fn authorize(claims: &Claims, requirements: &Requirements) -> Result<(), AuthzError> {
+    if let Some(scope) = requirements.scopes.iter().find(|s| !claims.has_scope(s)) {
+        return Err(AuthzError::MissingScope(scope.clone()));
+    }
+
+    if let Some((claim, value)) = requirements
+        .claims
+        .iter()
+        .find(|(claim, value)| !claims.has_claim(claim, value))
+    {
+        return Err(AuthzError::MissingClaim {
+            claim: claim.clone(),
+            value: value.clone(),
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use std::time::{Duration, SystemTime};
+
+use openidconnect::core::{CoreJsonWebKeySet, CoreJwsSigningAlgorithm};
+use openidconnect::{ClientId, IssuerUrl};
+use serde::Deserialize;
+
+use crate::jws::{verify_signature, verify_times, Audiences};
+
+/// Event type that identifies a JWT as a logout token.
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// Provider session (`sid`) and/or user (`sub`) that was logged out by
+/// a back-channel logout request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BackchannelLogout {
+    pub(crate) session_id: Option<String>,
+    pub(crate) subject: Option<String>,
+}
+
+/// Expected issuer, audience, keys, and time used to validate a logout
+/// token.
+pub(crate) struct LogoutTokenVerifier<'a> {
+    pub(crate) issuer: &'a IssuerUrl,
+    pub(crate) client_id: &'a ClientId,
+    pub(crate) keys: &'a CoreJsonWebKeySet,
+    pub(crate) allowed_algs: &'a [CoreJwsSigningAlgorithm],
+    pub(crate) now: SystemTime,
+    pub(crate) leeway: Duration,
+}
+
+impl LogoutTokenVerifier<'_> {
+    /// Verifies the signature and claims of a [logout
+    /// token](https://openid.net/specs/openid-connect-backchannel-1_0.html#LogoutToken),
+    /// returning the logout that it describes.
+    pub(crate) fn verify(&self, logout_token: &str) -> Result<BackchannelLogout, String> {
+        #[derive(Deserialize)]
+        struct Claims {
+            iss: IssuerUrl,
+            aud: Audiences,
+            iat: u64,
+            exp: Option<u64>,
+            events: serde_json::Map<String, serde_json::Value>,
+            sid: Option<String>,
+            sub: Option<String>,
+            nonce: Option<serde_json::Value>,
+        }
+
+        // Verify the signature against the provider's keys.
+        let (_, payload) =
+            verify_signature(logout_token, "logout token", self.keys, self.allowed_algs)?;
+
+        // Verify the claims.
+        let claims: Claims = serde_json::from_slice(&payload)
+            .map_err(|error| format!("logout token has invalid claims: {}", error))?;
+        if claims.iss != *self.issuer {
+            return Err("logout token was issued by a different issuer".to_string());
+        }
+        if !claims.aud.contains(self.client_id.as_str()) {
+            return Err("logout token was issued for a different client".to_string());
+        }
+        verify_times(
+            "logout token",
+            claims.iat,
+            claims.exp,
+            self.now,
+            self.leeway,
+        )?;
+        if !claims.events.contains_key(BACKCHANNEL_LOGOUT_EVENT) {
+            return Err("logout token does not contain the logout event".to_string());
+        }
+        if claims.nonce.is_some() {
+            return Err("logout token must not contain a nonce".to_string());
+        }
+        if claims.sid.is_none() && claims.sub.is_none() {
+            return Err("logout token must contain a session id or subject".to_string());
+        }
+
+        Ok(BackchannelLogout {
+            session_id: claims.sid,
+            subject: claims.sub,
+        })
+    }
+}
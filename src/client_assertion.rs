@@ -0,0 +1,108 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openidconnect::{ClientId, TokenUrl};
+use rand::RngCore;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::{Pkcs1v15Sign, RsaPrivateKey};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::error::ConfigError;
+
+/// Value of the `client_assertion_type` parameter for JWT client
+/// assertions.
+pub(crate) const CLIENT_ASSERTION_TYPE: &str =
+    "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Time for which a client assertion is valid; assertions are created
+/// immediately before each token request, so this only needs to cover
+/// the request itself (and any clock skew).
+const CLIENT_ASSERTION_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Private key used to sign the client assertions that authenticate
+/// this client to the token endpoint with the [`private_key_jwt`]
+/// client authentication method.
+///
+/// The key must be an RSA key (assertions are signed with `RS256`), and
+/// its public key must be registered with the Identity Provider.
+///
+/// [`private_key_jwt`]: https://openid.net/specs/openid-connect-core-1_0.html#ClientAuthentication
+#[derive(Clone)]
+pub struct ClientAssertionKey {
+    key: RsaPrivateKey,
+    key_id: Option<String>,
+}
+
+impl std::fmt::Debug for ClientAssertionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientAssertionKey")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientAssertionKey {
+    /// Loads the key from a PEM-encoded PKCS#1 (`RSA PRIVATE KEY`) or
+    /// PKCS#8 (`PRIVATE KEY`) RSA private key.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError::InvalidClientAssertionKey`] if the PEM does
+    /// not contain an RSA private key.
+    pub fn from_rsa_pem(pem: &str) -> Result<Self, ConfigError> {
+        RsaPrivateKey::from_pkcs1_pem(pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs8_pem(pem))
+            .map(|key| Self { key, key_id: None })
+            .map_err(|_| ConfigError::InvalidClientAssertionKey)
+    }
+
+    /// Sets the key id (the `kid` header of the client assertions),
+    /// which the Identity Provider uses to find the registered public
+    /// key if the client has registered more than one key.
+    ///
+    /// Defaults to none
+    pub fn with_key_id(mut self, key_id: impl AsRef<str>) -> Self {
+        self.key_id = Some(key_id.as_ref().to_owned());
+        self
+    }
+
+    /// Creates a signed client assertion for a request to the given
+    /// token endpoint.
+    pub(crate) fn sign(
+        &self,
+        client_id: &ClientId,
+        token_url: &TokenUrl,
+        now: SystemTime,
+    ) -> Result<String, rsa::Error> {
+        let encode = |part: &[u8]| base64::encode_config(part, base64::URL_SAFE_NO_PAD);
+
+        let mut jti = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut jti);
+        let issued_at = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        let mut header = json!({ "alg": "RS256", "typ": "JWT" });
+        if let Some(key_id) = &self.key_id {
+            header["kid"] = json!(key_id);
+        }
+        let claims = json!({
+            "iss": client_id.as_str(),
+            "sub": client_id.as_str(),
+            "aud": token_url.as_str(),
+            "jti": encode(&jti),
+            "iat": issued_at,
+            "exp": issued_at + CLIENT_ASSERTION_LIFETIME.as_secs(),
+        });
+
+        let message = format!(
+            "{}.{}",
+            encode(header.to_string().as_bytes()),
+            encode(claims.to_string().as_bytes())
+        );
+        let signature = self.key.sign(
+            Pkcs1v15Sign::new::<Sha256>(),
+            &Sha256::digest(message.as_bytes()),
+        )?;
+        Ok(format!("{}.{}", message, encode(&signature)))
+    }
+}
@@ -0,0 +1,25 @@
+//! Sources of the current time.
+
+use std::time::SystemTime;
+
+/// Source of the current time, which the middleware uses for all of its
+/// time-based decisions (expiring authorization state, idle timeouts,
+/// token expiration times, key set refreshes, etc.).
+///
+/// The default [`SystemClock`] is almost always what you want; a custom
+/// clock is primarily useful in tests that need to advance time without
+/// actually waiting.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// The operating system's wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
@@ -0,0 +1,107 @@
+use crate::isahc::{http_client, Error};
+use openidconnect::{
+    core::{
+        CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClientAuthMethod, CoreGrantType,
+        CoreJsonWebKey, CoreJsonWebKeySet, CoreJsonWebKeyType, CoreJsonWebKeyUse,
+        CoreJweContentEncryptionAlgorithm, CoreJweKeyManagementAlgorithm, CoreJwsSigningAlgorithm,
+        CoreProviderMetadata, CoreResponseMode, CoreResponseType, CoreSubjectIdentifierType,
+    },
+    url::Url,
+    AdditionalProviderMetadata, DiscoveryError, HttpRequest, ProviderMetadata,
+};
+use serde::{Deserialize, Serialize};
+
+/// Provider metadata fields that are used by the middleware, but that
+/// are not part of [`CoreProviderMetadata`].
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct ExtraProviderMetadata {
+    /// URL of the [pushed authorization
+    /// request](https://www.rfc-editor.org/rfc/rfc9126) endpoint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pushed_authorization_request_endpoint: Option<Url>,
+}
+
+impl AdditionalProviderMetadata for ExtraProviderMetadata {}
+
+/// Provider metadata, including the [extra
+/// fields](ExtraProviderMetadata) used by the middleware.
+pub(crate) type DiscoveredMetadata = ProviderMetadata<
+    ExtraProviderMetadata,
+    CoreAuthDisplay,
+    CoreClientAuthMethod,
+    CoreClaimName,
+    CoreClaimType,
+    CoreGrantType,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+    CoreJsonWebKeyUse,
+    CoreJsonWebKey,
+    CoreResponseMode,
+    CoreResponseType,
+    CoreSubjectIdentifierType,
+>;
+
+/// Converts metadata that was provided by the application (and which
+/// therefore cannot include any of the extra fields) to
+/// [`DiscoveredMetadata`].
+pub(crate) fn from_core_metadata(
+    provider_metadata: CoreProviderMetadata,
+) -> Result<DiscoveredMetadata, serde_json::Error> {
+    let jwks = provider_metadata.jwks().clone();
+    let provider_metadata: DiscoveredMetadata =
+        serde_json::from_value(serde_json::to_value(provider_metadata)?)?;
+    Ok(provider_metadata.set_jwks(jwks))
+}
+
+/// Retrieves the Identity Provider's metadata from an explicit URL,
+/// instead of from the `.well-known` location relative to the issuer.
+///
+/// The issuer in the metadata document must have the same origin
+/// (scheme, host, and port) as the metadata URL itself, which prevents
+/// a document from asserting the identity of an unrelated issuer.
+pub(crate) async fn discover_from_metadata_url(
+    metadata_url: Url,
+) -> Result<DiscoveredMetadata, DiscoveryError<Error>> {
+    let response = http_client(HttpRequest {
+        url: metadata_url.clone(),
+        method: http::Method::GET,
+        headers: vec![(
+            http::header::ACCEPT,
+            http::HeaderValue::from_static("application/json"),
+        )]
+        .into_iter()
+        .collect(),
+        body: Vec::new(),
+    })
+    .await
+    .map_err(DiscoveryError::Request)?;
+
+    if response.status_code != http::StatusCode::OK {
+        return Err(DiscoveryError::Response(
+            response.status_code,
+            response.body,
+            format!(
+                "HTTP status code {} at {}",
+                response.status_code, metadata_url
+            ),
+        ));
+    }
+
+    let provider_metadata: DiscoveredMetadata =
+        serde_json::from_slice(&response.body).map_err(|error| {
+            DiscoveryError::Other(format!("Failed to parse provider metadata: {}", error))
+        })?;
+
+    if provider_metadata.issuer().url().origin() != metadata_url.origin() {
+        return Err(DiscoveryError::Validation(format!(
+            "unexpected issuer URI `{}` (metadata retrieved from `{}`)",
+            provider_metadata.issuer().as_str(),
+            metadata_url
+        )));
+    }
+
+    let jwks = CoreJsonWebKeySet::fetch_async(provider_metadata.jwks_uri(), http_client).await?;
+    Ok(provider_metadata.set_jwks(jwks))
+}
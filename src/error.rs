@@ -0,0 +1,183 @@
+//! Error types.
+
+use std::time::Duration;
+
+/// Errors caused by an invalid or insecure middleware configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The session store keeps the session data (including the access
+    /// token) in the session cookie itself, instead of keeping it
+    /// server-side and only placing the session id in the cookie.
+    #[error("Session store `{store}` stores the session data in the session cookie; use a server-side session store or call `with_require_signed_session(false)`.")]
+    UnsignedSessionStore {
+        /// Type name of the rejected session store.
+        store: &'static str,
+    },
+
+    /// The timing policy's clock skew leeway is longer than the time
+    /// allowed for the browser to complete the authorization process.
+    #[error("Timing policy leeway ({leeway:?}) must not be longer than the authorize TTL ({authorize_ttl:?}).")]
+    InconsistentTimingPolicy {
+        /// Configured authorize TTL.
+        authorize_ttl: Duration,
+        /// Configured leeway.
+        leeway: Duration,
+    },
+
+    /// The [ID token decryption key](crate::IdTokenDecryptionKey) is not
+    /// a PEM-encoded RSA private key.
+    #[error("ID token decryption key must be a PEM-encoded RSA private key.")]
+    InvalidDecryptionKey,
+
+    /// The [client assertion key](crate::ClientAssertionKey) is not a
+    /// PEM-encoded RSA private key.
+    #[error("Client assertion key must be a PEM-encoded RSA private key.")]
+    InvalidClientAssertionKey,
+}
+
+/// Reasons for which the login process failed to complete.
+///
+/// These errors are provided to the
+/// [`on_login_failure`](crate::OpenIdConnectMiddleware::on_login_failure)
+/// hook, and are also the source of the error responses returned by the
+/// middleware's callback route, which means that they can be recovered
+/// from the response by (outer) middleware:
+///
+/// ```
+/// use tide_openidconnect::OpenIdConnectError;
+/// # let res = tide::Response::new(200);
+///
+/// if let Some(OpenIdConnectError::NonceMismatch) = res
+///     .error()
+///     .and_then(|error| error.downcast_ref::<OpenIdConnectError>())
+/// {
+///     tide::log::warn!("Possible ID token replay.");
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum OpenIdConnectError {
+    /// The session does not contain the authorization state that was
+    /// created by the login route, usually because the session cookie
+    /// was not sent with the callback request.
+    #[error("Missing authorization state.")]
+    MissingState,
+
+    /// The browser took too long to complete the sign in process.
+    #[error("Expired authorization state.")]
+    ExpiredState,
+
+    /// The callback request did not include the expected parameters.
+    #[error("Invalid callback request: {0}")]
+    InvalidCallback(String),
+
+    /// The CSRF state in the callback request does not match the state
+    /// in the session.
+    #[error("Invalid CSRF state.")]
+    CsrfMismatch,
+
+    /// The callback request is a replay of a callback for a login that
+    /// was already completed (or that already failed), usually because
+    /// the browser's back button was used. The middleware redirects
+    /// the browser instead of failing the request.
+    #[error("Authorization response has already been used.")]
+    ReplayedCallback,
+
+    /// The nonce in the ID token does not match the nonce that was sent
+    /// to the Identity Provider by the login route.
+    #[error("Invalid nonce.")]
+    NonceMismatch,
+
+    /// The Identity Provider returned an error instead of an
+    /// authorization code.
+    #[error("OpenID Connect provider returned an error: {error}")]
+    ProviderError {
+        /// Error code, such as `access_denied`.
+        error: String,
+        /// Optional human-readable description of the error.
+        description: Option<String>,
+    },
+
+    /// The authorization code could not be exchanged for a token.
+    #[error("Unable to exchange the authorization code: {0}")]
+    TokenExchange(String),
+
+    /// The access token was not issued for the requested resource.
+    #[error("Access token audience does not match the requested resource.")]
+    AccessTokenAudience,
+
+    /// The Identity Provider did not return an ID token, which usually
+    /// means that the provider is misconfigured (or is a plain OAuth 2.0
+    /// server).
+    #[error("OpenID Connect server did not return an ID token.")]
+    MissingIdToken,
+
+    /// The ID token could not be verified.
+    #[error("Invalid ID token: {0}")]
+    ClaimVerification(String),
+
+    /// The ID token is signed with a key that is not in the Identity
+    /// Provider's JSON Web Key Set, which usually means that the
+    /// provider publishes an empty (or outdated) key set.
+    #[error("Invalid ID token: no key in the provider's JWKS matches the token's signing key.")]
+    NoMatchingKey,
+
+    /// The ID token was rejected by the application's [claims
+    /// validator](crate::OpenIdConnectMiddleware::with_claims_validator),
+    /// the user's email address has not been
+    /// [verified](crate::OpenIdConnectMiddleware::with_require_email_verified),
+    /// or the Identity Provider did not grant all of the [requested
+    /// scopes](crate::OpenIdConnectMiddleware::with_require_requested_scopes).
+    #[error("Login rejected: {0}")]
+    ClaimsRejected(String),
+
+    /// The user info could not be retrieved from the Identity Provider.
+    #[error("Unable to retrieve the user info: {0}")]
+    UserInfo(String),
+
+    /// The Identity Provider did not respond within the [HTTP
+    /// timeout](crate::OpenIdConnectMiddleware::with_http_timeout).
+    #[error("OpenID Connect provider did not respond in time.")]
+    ProviderTimeout,
+
+    /// The Identity Provider (or its JSON Web Key Set) could not be
+    /// retrieved.
+    #[error("OpenID Connect provider is unavailable: {0}")]
+    ProviderUnavailable(String),
+
+    /// The session is not available -- usually because Tide's
+    /// [`SessionMiddleware`](tide::sessions::SessionMiddleware) has not
+    /// been installed before this middleware -- or the authentication
+    /// state could not be stored in the session.
+    #[error("Session is unavailable: {0}")]
+    SessionUnavailable(String),
+}
+
+impl OpenIdConnectError {
+    /// Returns the status code of the response to a callback request
+    /// that failed with this error.
+    pub fn status(&self) -> tide::StatusCode {
+        use tide::StatusCode;
+
+        match self {
+            Self::InvalidCallback(_) => StatusCode::BadRequest,
+            Self::ExpiredState
+            | Self::CsrfMismatch
+            | Self::NonceMismatch
+            | Self::ReplayedCallback
+            | Self::ProviderError { .. }
+            | Self::AccessTokenAudience
+            | Self::ClaimVerification(_)
+            | Self::NoMatchingKey
+            | Self::ClaimsRejected(_) => StatusCode::Unauthorized,
+            Self::MissingIdToken | Self::ProviderTimeout | Self::ProviderUnavailable(_) => {
+                StatusCode::BadGateway
+            }
+            Self::MissingState
+            | Self::TokenExchange(_)
+            | Self::UserInfo(_)
+            | Self::SessionUnavailable(_) => StatusCode::InternalServerError,
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use tide::{Middleware, Next, Request};
+
+/// Name of the header in which reverse proxies pass on the scheme of the
+/// client's connection.
+const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+
+/// Returns the scheme of the client's connection as reported by a
+/// reverse proxy in the `X-Forwarded-Proto` header, or `None` if the
+/// request does not have a (valid) header. Proxies append to the
+/// header, and so the first value is the scheme that the client used.
+pub(crate) fn forwarded_proto<State>(req: &Request<State>) -> Option<&'static str> {
+    let proto = req.header(X_FORWARDED_PROTO)?.as_str().split(',').next()?;
+    match proto.trim() {
+        proto if proto.eq_ignore_ascii_case("https") => Some("https"),
+        proto if proto.eq_ignore_ascii_case("http") => Some("http"),
+        _ => None,
+    }
+}
+
+/// Middleware that replaces the scheme of the request URL with the
+/// scheme that a TLS-terminating reverse proxy reports in the
+/// `X-Forwarded-Proto` header.
+///
+/// Tide's [`SessionMiddleware`](tide::sessions::SessionMiddleware) only
+/// marks the session cookie as `Secure` if the request URL uses https,
+/// which is never the case behind a proxy that forwards requests over
+/// plain http. Install this middleware *before* the session middleware
+/// so that the session middleware sees the client's scheme:
+///
+/// ```
+/// use tide_openidconnect::ForwardedProtoMiddleware;
+///
+/// let mut app = tide::new();
+/// app.with(ForwardedProtoMiddleware);
+/// app.with(tide::sessions::SessionMiddleware::new(
+///     tide::sessions::MemoryStore::new(),
+///     b"don't actually use this as your secret",
+/// ));
+/// ```
+///
+/// The header is only trustworthy if every request passes through a
+/// proxy that overwrites it; otherwise, clients can claim to use https
+/// when they do not. Only install this middleware behind such a proxy
+/// (and see
+/// [`with_trusted_proxy`](crate::OpenIdConnectMiddleware::with_trusted_proxy)
+/// for the corresponding setting of the OpenID Connect middleware).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ForwardedProtoMiddleware;
+
+#[tide::utils::async_trait]
+impl<State> Middleware<State> for ForwardedProtoMiddleware
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if let Some(proto) = forwarded_proto(&req) {
+            let request: &mut tide::http::Request = req.as_mut();
+            // Switching between http and https cannot fail.
+            let _ = request.url_mut().set_scheme(proto);
+        }
+        Ok(next.run(req).await)
+    }
+}
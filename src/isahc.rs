@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use futures_lite::{io::Cursor, AsyncRead};
 use isahc::{config::RedirectPolicy, prelude::*, HttpClient, Request};
 use once_cell::sync::Lazy;
@@ -17,8 +19,32 @@ pub(crate) enum Error {
     /// I/O error.
     #[error("I/O error")]
     Io(#[source] std::io::Error),
+    /// The encrypted ID token in the token response could not be
+    /// decrypted.
+    #[error("Unable to decrypt the ID token")]
+    IdTokenDecryption(#[source] crate::jwe::JweError),
 }
 
+impl Error {
+    /// Returns `true` if the request failed because it did not complete
+    /// within the configured timeout.
+    pub(crate) fn is_timeout(&self) -> bool {
+        matches!(self, Self::Isahc(error) if error.is_timeout())
+            || matches!(self, Self::Io(error) if error.kind() == std::io::ErrorKind::TimedOut)
+    }
+
+    /// Returns `true` if the request failed because the server could not
+    /// be reached (or the connection failed), as opposed to timing out or
+    /// returning an invalid response.
+    pub(crate) fn is_network(&self) -> bool {
+        !self.is_timeout() && matches!(self, Self::Isahc(error) if error.is_network())
+    }
+}
+
+/// Timeout applied to provider requests unless the middleware was
+/// configured with a different one.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
 // Isahc recommends that you create a single client per "area of application"
 // and reuse that client through your code. We have to create a global client
 // instance (instead of putting the client in a struct and then having the
@@ -33,7 +59,17 @@ static HTTP_CLIENT: Lazy<HttpClient> = Lazy::new(|| {
 });
 
 pub(crate) async fn http_client(openid_request: HttpRequest) -> Result<HttpResponse, Error> {
+    http_client_with_timeout(openid_request, DEFAULT_TIMEOUT).await
+}
+
+/// Sends the request, failing if the complete response (including the
+/// body) has not been received within `timeout`.
+pub(crate) async fn http_client_with_timeout(
+    openid_request: HttpRequest,
+    timeout: Duration,
+) -> Result<HttpResponse, Error> {
     let mut request_builder = Request::builder()
+        .timeout(timeout)
         .method(openid_request.method)
         .uri(openid_request.url.as_str());
     for (name, value) in &openid_request.headers {
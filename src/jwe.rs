@@ -0,0 +1,134 @@
+use std::convert::TryInto;
+
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::{Oaep, RsaPrivateKey};
+use serde::Deserialize;
+
+use crate::error::ConfigError;
+
+///
+/// Error returned when an encrypted ID token could not be decrypted.
+///
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum JweError {
+    /// The token response could not be re-encoded after decrypting the
+    /// ID token.
+    #[error("Invalid token response")]
+    TokenResponse(#[source] serde_json::Error),
+    /// The encrypted token is not a valid compact JWE.
+    #[error("Malformed JWE: {0}")]
+    Malformed(&'static str),
+    /// The token was encrypted with an algorithm that is not supported.
+    #[error("Unsupported JWE algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    /// The token could not be decrypted with the configured key.
+    #[error("Unable to decrypt JWE")]
+    Decryption,
+}
+
+/// Private key used to decrypt encrypted (JWE) ID tokens.
+///
+/// The key must be an RSA key, and the Identity Provider must encrypt
+/// the ID tokens with the `RSA-OAEP-256` key management algorithm and the
+/// `A128GCM` or `A256GCM` content encryption algorithm.
+#[derive(Clone)]
+pub struct IdTokenDecryptionKey(RsaPrivateKey);
+
+impl std::fmt::Debug for IdTokenDecryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("IdTokenDecryptionKey")
+            .finish_non_exhaustive()
+    }
+}
+
+impl IdTokenDecryptionKey {
+    /// Loads the key from a PEM-encoded PKCS#1 (`RSA PRIVATE KEY`) or
+    /// PKCS#8 (`PRIVATE KEY`) RSA private key.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError::InvalidDecryptionKey`] if the PEM does not
+    /// contain an RSA private key.
+    pub fn from_rsa_pem(pem: &str) -> Result<Self, ConfigError> {
+        RsaPrivateKey::from_pkcs1_pem(pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs8_pem(pem))
+            .map(Self)
+            .map_err(|_| ConfigError::InvalidDecryptionKey)
+    }
+
+    /// Decrypts the ID token in a token response body, returning the
+    /// body with the ID token replaced by the signed token that it
+    /// contains. Bodies without an encrypted ID token are returned
+    /// unchanged.
+    pub(crate) fn decrypt_token_response(&self, body: Vec<u8>) -> Result<Vec<u8>, JweError> {
+        let mut token_response: serde_json::Value = match serde_json::from_slice(&body) {
+            Ok(token_response) => token_response,
+            Err(_) => return Ok(body),
+        };
+        let id_token = match token_response
+            .get("id_token")
+            .and_then(|id_token| id_token.as_str())
+        {
+            Some(id_token) if id_token.split('.').count() == 5 => self.decrypt(id_token)?,
+            _ => return Ok(body),
+        };
+        token_response["id_token"] = serde_json::Value::String(id_token);
+        serde_json::to_vec(&token_response).map_err(JweError::TokenResponse)
+    }
+
+    /// Decrypts a compact JWE, returning the plaintext (which, for an ID
+    /// token, is the signed JWT).
+    fn decrypt(&self, jwe: &str) -> Result<String, JweError> {
+        #[derive(Deserialize)]
+        struct JweHeader {
+            alg: String,
+            enc: String,
+        }
+
+        let parts: Vec<&str> = jwe.split('.').collect();
+        let (encoded_header, encrypted_key, iv, ciphertext, tag) = match parts[..] {
+            [header, encrypted_key, iv, ciphertext, tag] => {
+                (header, encrypted_key, iv, ciphertext, tag)
+            }
+            _ => return Err(JweError::Malformed("expected five parts")),
+        };
+        let decode = |part: &str| {
+            base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+                .map_err(|_| JweError::Malformed("invalid base64url encoding"))
+        };
+
+        let header: JweHeader = serde_json::from_slice(&decode(encoded_header)?)
+            .map_err(|_| JweError::Malformed("invalid header"))?;
+        if header.alg != "RSA-OAEP-256" {
+            return Err(JweError::UnsupportedAlgorithm(header.alg));
+        }
+
+        let content_key = self
+            .0
+            .decrypt(Oaep::new::<sha2::Sha256>(), &decode(encrypted_key)?)
+            .map_err(|_| JweError::Decryption)?;
+        let iv: [u8; 12] = decode(iv)?
+            .try_into()
+            .map_err(|_| JweError::Malformed("invalid initialization vector"))?;
+        let mut message = decode(ciphertext)?;
+        message.extend(decode(tag)?);
+        let payload = Payload {
+            msg: &message,
+            aad: encoded_header.as_bytes(),
+        };
+
+        let plaintext = match header.enc.as_str() {
+            "A128GCM" => Aes128Gcm::new_varkey(&content_key)
+                .and_then(|cipher| cipher.decrypt(&iv.into(), payload)),
+            "A256GCM" => Aes256Gcm::new_varkey(&content_key)
+                .and_then(|cipher| cipher.decrypt(&iv.into(), payload)),
+            _ => return Err(JweError::UnsupportedAlgorithm(header.enc)),
+        }
+        .map_err(|_| JweError::Decryption)?;
+
+        String::from_utf8(plaintext).map_err(|_| JweError::Malformed("invalid plaintext"))
+    }
+}
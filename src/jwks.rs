@@ -0,0 +1,89 @@
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::isahc::{http_client_with_timeout, Error};
+use openidconnect::{core::CoreJsonWebKeySet, DiscoveryError, JsonWebKeySetUrl};
+
+/// Cached copy of the Identity Provider's JSON Web Key Set, which can
+/// be re-fetched from the provider's `jwks_uri` in order to pick up
+/// rotated signing keys.
+pub(crate) struct JwksCache {
+    jwks_uri: JsonWebKeySetUrl,
+    state: RwLock<JwksCacheState>,
+}
+
+struct JwksCacheState {
+    keys: CoreJsonWebKeySet,
+    fetched_at: SystemTime,
+}
+
+impl JwksCache {
+    /// Creates a new cache, seeded with the key set that was retrieved
+    /// during provider discovery (at the given time).
+    pub(crate) fn new(
+        jwks_uri: JsonWebKeySetUrl,
+        keys: CoreJsonWebKeySet,
+        fetched_at: SystemTime,
+    ) -> Self {
+        warn_if_empty(&jwks_uri, &keys);
+        Self {
+            jwks_uri,
+            state: RwLock::new(JwksCacheState { keys, fetched_at }),
+        }
+    }
+
+    /// Returns the currently-cached key set.
+    pub(crate) fn keys(&self) -> CoreJsonWebKeySet {
+        self.state
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys
+            .clone()
+    }
+
+    /// Returns `true` if the cached key set is older than `max_age` as of
+    /// `now`.
+    pub(crate) fn is_stale(&self, max_age: Duration, now: SystemTime) -> bool {
+        let fetched_at = self
+            .state
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .fetched_at;
+        now.duration_since(fetched_at).unwrap_or_default() >= max_age
+    }
+
+    /// Re-fetches the key set from the provider, replacing the cached
+    /// keys (which are then considered to have been fetched at `now`).
+    /// The existing keys are retained if the fetch fails (or does not
+    /// complete within `timeout`).
+    pub(crate) async fn refresh(
+        &self,
+        timeout: Duration,
+        now: SystemTime,
+    ) -> Result<(), DiscoveryError<Error>> {
+        let keys = CoreJsonWebKeySet::fetch_async(&self.jwks_uri, |request| {
+            http_client_with_timeout(request, timeout)
+        })
+        .await?;
+        warn_if_empty(&self.jwks_uri, &keys);
+
+        let mut state = self.state.write().unwrap_or_else(PoisonError::into_inner);
+        state.keys = keys;
+        state.fetched_at = now;
+
+        Ok(())
+    }
+}
+
+/// Logs a configuration warning if the key set does not contain any
+/// keys, in which case no token that is signed with the provider's keys
+/// can be verified.
+fn warn_if_empty(jwks_uri: &JsonWebKeySetUrl, keys: &CoreJsonWebKeySet) {
+    if keys.keys().is_empty() {
+        crate::log::event!(
+            warn,
+            "OpenID Connect provider's JWKS does not contain any keys; ID tokens signed with the provider's keys cannot be verified. Check the provider's signing key configuration.",
+            { jwks_uri: jwks_uri.as_str() }
+        );
+    }
+}
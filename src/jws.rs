@@ -0,0 +1,94 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openidconnect::core::{CoreJsonWebKeySet, CoreJwsSigningAlgorithm};
+use openidconnect::JsonWebKey;
+use serde::Deserialize;
+
+/// JOSE header of a signed JWT.
+#[derive(Deserialize)]
+pub(crate) struct Header {
+    pub(crate) alg: CoreJwsSigningAlgorithm,
+    pub(crate) kid: Option<String>,
+    pub(crate) typ: Option<String>,
+}
+
+/// The `aud` claim, which may be a single audience or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Audiences {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audiences {
+    /// Returns `true` if the given audience is (one of) the audiences.
+    pub(crate) fn contains(&self, audience: &str) -> bool {
+        match self {
+            Self::Single(aud) => aud == audience,
+            Self::Multiple(auds) => auds.iter().any(|aud| aud == audience),
+        }
+    }
+}
+
+/// Verifies the signature of a JWT against the provider's keys,
+/// returning the token's header and its (decoded, but otherwise
+/// unverified) payload. `kind` names the token in the error messages.
+pub(crate) fn verify_signature(
+    token: &str,
+    kind: &str,
+    keys: &CoreJsonWebKeySet,
+    allowed_algs: &[CoreJwsSigningAlgorithm],
+) -> Result<(Header, Vec<u8>), String> {
+    let decode = |part: &str| {
+        base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| format!("{} is not a valid JWT", kind))
+    };
+    let (header, payload, signature) = match token.split('.').collect::<Vec<_>>()[..] {
+        [header, payload, signature] => (header, payload, signature),
+        _ => return Err(format!("{} is not a signed JWT", kind)),
+    };
+
+    let jose_header: Header = serde_json::from_slice(&decode(header)?)
+        .map_err(|_| format!("{} has an invalid header", kind))?;
+    if jose_header.alg == CoreJwsSigningAlgorithm::None || !allowed_algs.contains(&jose_header.alg)
+    {
+        return Err(format!("{} is signed with a disallowed algorithm", kind));
+    }
+    let message = format!("{}.{}", header, payload);
+    let signature = decode(signature)?;
+    let verified = keys
+        .keys()
+        .iter()
+        .filter(|key| match &jose_header.kid {
+            Some(kid) => matches!(key.key_id(), Some(key_id) if **key_id == *kid),
+            None => true,
+        })
+        .any(|key| {
+            key.verify_signature(&jose_header.alg, message.as_bytes(), &signature)
+                .is_ok()
+        });
+    if !verified {
+        return Err(format!("{} signature is invalid", kind));
+    }
+
+    Ok((jose_header, decode(payload)?))
+}
+
+/// Verifies the `iat` and (optional) `exp` claims of a JWT against the
+/// current time, allowing for the given clock skew.
+pub(crate) fn verify_times(
+    kind: &str,
+    iat: u64,
+    exp: Option<u64>,
+    now: SystemTime,
+    leeway: Duration,
+) -> Result<(), String> {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    if iat > now.saturating_add(leeway.as_secs()) {
+        return Err(format!("{} was issued in the future", kind));
+    }
+    if matches!(exp, Some(exp) if exp.saturating_add(leeway.as_secs()) < now) {
+        return Err(format!("{} has expired", kind));
+    }
+    Ok(())
+}
@@ -11,16 +11,49 @@
     clippy::unwrap_used
 )]
 
+mod access_token;
+pub mod authorization;
+mod backchannel;
+mod client_assertion;
+pub mod clock;
+mod discovery;
+mod error;
+mod forwarded_proto;
 mod isahc;
+mod jwe;
+mod jwks;
+mod jws;
+mod log;
+mod metrics;
 mod middleware;
+mod par;
 pub mod redirect_strategy;
 mod request_ext;
 mod route_ext;
+mod session_index;
+mod timing;
+mod token_encryption;
 
+pub use crate::client_assertion::ClientAssertionKey;
+pub use crate::error::{ConfigError, OpenIdConnectError};
+pub use crate::forwarded_proto::ForwardedProtoMiddleware;
+pub use crate::jwe::IdTokenDecryptionKey;
+pub use crate::metrics::MetricEvent;
 pub use crate::middleware::Config;
-pub use crate::middleware::OpenIdConnectMiddleware;
-pub use crate::request_ext::OpenIdConnectRequestExt;
+pub use crate::middleware::{
+    CallbackResponse, NonceMode, OpenIdConnectConfig, OpenIdConnectMiddleware, ReloginBehavior,
+    ResponseMode, SessionBinding, UnauthenticatedBehavior,
+};
+pub use crate::request_ext::{AuthenticatedUser, GrantedScopes, OpenIdConnectRequestExt};
 pub use crate::route_ext::OpenIdConnectRouteExt;
+pub use crate::timing::TimingPolicy;
 
 #[doc(no_inline)]
-pub use openidconnect::{ClientId, ClientSecret, IssuerUrl, RedirectUrl};
+pub use openidconnect::core::{
+    CoreClientAuthMethod, CoreClientRegistrationRequest, CoreIdTokenClaims,
+    CoreJwsSigningAlgorithm, CoreProviderMetadata, CoreResponseType,
+};
+#[doc(no_inline)]
+pub use openidconnect::url::Url;
+#[doc(no_inline)]
+pub use openidconnect::{AuthenticationFlow, ClientId, ClientSecret, IssuerUrl, RedirectUrl};
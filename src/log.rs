@@ -0,0 +1,47 @@
+//! Internal logging macros.
+//!
+//! Events are emitted through Tide's logger by default, or as `tracing`
+//! events if the `tracing` feature is enabled. Both flavors accept the
+//! same arguments: a level, a message, and optional key-value pairs.
+//!
+//! Note that these macros must never be given secrets (access tokens,
+//! ID tokens, client secrets, etc.) as values; URLs of the login flow
+//! must be passed through [`redact_url`] first.
+
+use std::borrow::Cow;
+
+use openidconnect::url::Url;
+
+macro_rules! event {
+    ($level:ident, $msg:literal $(, { $($key:ident : $value:expr),* $(,)? })?) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::$level!($($($key = %$value,)*)? $msg);
+        #[cfg(not(feature = "tracing"))]
+        ::tide::log::$level!($msg $(, { $($key: $value),* })?);
+    }};
+}
+
+pub(crate) use event;
+
+/// Query parameters that carry the one-time secrets of the login flow.
+const REDACTED_PARAMS: &[&str] = &["state", "nonce", "code", "code_challenge"];
+
+/// Returns the URL with the values of the login flow's one-time secrets
+/// (the CSRF state, nonce, authorization code, and PKCE challenge)
+/// replaced, so that authorize and callback URLs can be logged.
+pub(crate) fn redact_url(url: &Url) -> String {
+    let mut redacted = url.clone();
+    if url.query().is_some() {
+        redacted
+            .query_pairs_mut()
+            .clear()
+            .extend_pairs(url.query_pairs().map(|(name, value)| {
+                if REDACTED_PARAMS.contains(&name.as_ref()) {
+                    (name, Cow::Borrowed("REDACTED"))
+                } else {
+                    (name, value)
+                }
+            }));
+    }
+    redacted.to_string()
+}
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+/// Timing measurements reported to the middleware's [metrics
+/// hook](crate::OpenIdConnectMiddleware::with_metrics).
+///
+/// Durations are measured with a monotonic clock (and not with the
+/// middleware's [`Clock`](crate::clock::Clock)), so that they reflect
+/// the actual time spent on each step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MetricEvent {
+    /// The login route generated the redirect to the Identity
+    /// Provider's authorization endpoint.
+    AuthorizeRedirect {
+        /// Time taken to generate the redirect.
+        duration: Duration,
+    },
+
+    /// The authorization code was exchanged for a token (successfully
+    /// or not).
+    TokenExchange {
+        /// Time taken by the token request.
+        duration: Duration,
+    },
+
+    /// An expired access token was refreshed using the refresh token
+    /// (successfully or not).
+    TokenRefresh {
+        /// Time taken by the token request.
+        duration: Duration,
+    },
+
+    /// The Identity Provider's JSON Web Key Set was re-fetched (or the
+    /// attempt to do so failed).
+    JwksRefresh {
+        /// Time taken by the JWKS request.
+        duration: Duration,
+    },
+
+    /// The user info was retrieved from the Identity Provider (or the
+    /// attempt to do so failed).
+    UserInfo {
+        /// Time taken by the user info request.
+        duration: Duration,
+    },
+
+    /// The callback route finished processing a login.
+    Callback {
+        /// Total time taken to process the callback request.
+        duration: Duration,
+        /// `true` if the login completed successfully.
+        success: bool,
+    },
+}
@@ -1,18 +1,131 @@
-use std::sync::Arc;
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::isahc::http_client;
+use crate::access_token::AccessTokenVerifier;
+use crate::authorization::Claims;
+use crate::backchannel::{BackchannelLogout, LogoutTokenVerifier};
+use crate::client_assertion::{ClientAssertionKey, CLIENT_ASSERTION_TYPE};
+use crate::clock::{Clock, SystemClock};
+use crate::discovery::{discover_from_metadata_url, from_core_metadata, DiscoveredMetadata};
+use crate::error::{ConfigError, OpenIdConnectError};
+use crate::forwarded_proto::forwarded_proto;
+use crate::isahc::{
+    http_client, http_client_with_timeout, Error as HttpClientError, DEFAULT_TIMEOUT,
+};
+use crate::jwe::IdTokenDecryptionKey;
+use crate::jwks::JwksCache;
+use crate::metrics::MetricEvent;
+use crate::par::{push_authorization_request, ParClientAuth};
 use crate::redirect_strategy::{HttpRedirect, RedirectStrategy};
-use crate::request_ext::OpenIdConnectRequestExtData;
-use openidconnect::core::{CoreGenderClaim, CoreUserInfoClaims};
+use crate::request_ext::{
+    AuthenticatedUser, FlashMessage, GrantedScopes, JustLoggedIn, LoginRequiredMessage,
+    OpenIdConnectRequestExtData,
+};
+use crate::session_index::{IndexKey, SessionIndex};
+use crate::timing::TimingPolicy;
+use crate::token_encryption::TokenEncryptionKey;
+use async_io::Timer;
+use openidconnect::core::{
+    CoreAuthPrompt, CoreClientAuthMethod, CoreClientRegistrationRequest, CoreErrorResponseType,
+    CoreGenderClaim, CoreIdToken, CoreIdTokenClaims, CoreUserInfoClaims,
+};
 use openidconnect::{
-    core::{CoreClient, CoreProviderMetadata, CoreResponseType},
-    AccessToken, AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken,
-    IssuerUrl, Nonce, OAuth2TokenResponse, RedirectUrl, Scope, StandardClaims, SubjectIdentifier,
+    core::{
+        CoreClient, CoreIdTokenVerifier, CoreJwsSigningAlgorithm, CoreProviderMetadata,
+        CoreResponseType,
+    },
+    url::Url,
+    AccessToken, AccessTokenHash, AuthType, AuthenticationContextClass, AuthenticationFlow,
+    AuthorizationCode, AuthorizationCodeHash, ClaimsVerificationError, ClientId, ClientSecret,
+    CsrfToken, DiscoveryError, IssuerUrl, LoginHint, Nonce, NonceVerifier, OAuth2TokenResponse,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, RequestTokenError, Scope,
+    SignatureVerificationError, StandardClaims, SubjectIdentifier, TokenUrl, UserInfoError,
 };
+use percent_encoding::percent_decode_str;
 use serde::{Deserialize, Serialize};
-use tide::{http::Method, Middleware, Next, Redirect, Request, StatusCode};
+use sha2::{Digest, Sha256};
+use tide::{
+    http::{mime, Method},
+    sessions::{CookieStore, Session, SessionStore},
+    Middleware, Next, Request, Response, StatusCode,
+};
+
+/// Default prefix of the keys under which the middleware stores its
+/// state in the session.
+const DEFAULT_SESSION_NAMESPACE: &str = "tide.oidc";
+/// Longest time to wait before retrying a failed lazy discovery.
+const MAX_DISCOVERY_BACKOFF: Duration = Duration::from_secs(60);
+/// Time to wait before the first retry of a failed token exchange; the
+/// wait doubles after every subsequent failure.
+const TOKEN_EXCHANGE_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Longest time to wait before retrying a failed token exchange.
+const MAX_TOKEN_EXCHANGE_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Largest number of retries of a failed token exchange, which bounds
+/// how long the callback request is held open.
+const MAX_TOKEN_EXCHANGE_RETRIES: u32 = 5;
+/// Minimum age of the cached key set before it is re-fetched because a
+/// token was signed with an unknown key.
+const JWKS_REFETCH_MIN_INTERVAL: Duration = Duration::from_secs(60);
+/// Authorization request parameters that are managed by the middleware,
+/// and which therefore cannot be overridden with
+/// [`with_extra_authorize_params`](OpenIdConnectMiddleware::with_extra_authorize_params).
+const RESERVED_AUTHORIZE_PARAMS: &[&str] = &[
+    "response_type",
+    "client_id",
+    "redirect_uri",
+    "scope",
+    "state",
+    "nonce",
+    "code_challenge",
+    "code_challenge_method",
+    "response_mode",
+];
+/// Names of the session keys (within the session namespace) that hold
+/// the middleware's transient state.
+const FLASH_SESSION_KEY: &str = "flash";
+const JUST_LOGGED_IN_SESSION_KEY: &str = "just_logged_in";
+const FINGERPRINT_SESSION_KEY: &str = "fingerprint";
+const LAST_SEEN_SESSION_KEY: &str = "last_seen";
+const LOGIN_REQUIRED_SESSION_KEY: &str = "login_required";
+const PENDING_LOGINS_SESSION_KEY: &str = "pending";
+const INDEXED_SESSION_KEY: &str = "indexed";
+const MAX_PENDING_LOGINS: usize = 8;
+const LOGIN_HINT_MAX_LEN: usize = 256;
+const RETURN_TO_MAX_LEN: usize = 2048;
+
+/// Application-specific check applied to the ID token claims of every
+/// login; see [`OpenIdConnectMiddleware::with_claims_validator`].
+type ClaimsValidator = dyn Fn(&CoreIdTokenClaims) -> Result<(), String> + Send + Sync;
+
+/// Function that derives additional claims from the ID token claims of
+/// every login; see [`OpenIdConnectMiddleware::with_claim_enricher`].
+type ClaimEnricher = dyn Fn(&CoreIdTokenClaims) -> HashMap<String, serde_json::Value> + Send + Sync;
+
+/// Function that adapts the identity provider's logout URL to the
+/// provider; see [`OpenIdConnectMiddleware::with_logout_url_fn`].
+type LogoutUrlFn = dyn Fn(Url) -> Url + Send + Sync;
 
-const SESSION_KEY: &str = "tide.oidc";
+/// Hook invoked after every successful login.
+type LoginHook = dyn Fn(&CoreIdTokenClaims) + Send + Sync;
+
+/// Hook invoked after every failed login.
+type LoginFailureHook = dyn Fn(&OpenIdConnectError) + Send + Sync;
+
+/// Function that renders the response for a failed login; see
+/// [`OpenIdConnectMiddleware::with_error_renderer`].
+type ErrorRenderer = dyn Fn(&OpenIdConnectError) -> Response + Send + Sync;
+
+/// Hook that receives timing measurements; see
+/// [`OpenIdConnectMiddleware::with_metrics`].
+type MetricsHook = dyn Fn(MetricEvent) + Send + Sync;
+
+/// Source of the random values used as the login's state and nonce; see
+/// [`OpenIdConnectMiddleware::with_random_source`].
+type RandomSource = dyn Fn() -> String + Send + Sync;
 
 /// Middleware configuration.
 #[derive(Debug, Deserialize, Clone)]
@@ -49,48 +162,657 @@ pub struct Config {
     /// site's
     /// [`logout_landing_path`](OpenIdConnectMiddleware::with_logout_landing_path).
     ///
+    /// The ID token that was issued when the user logged in is added to
+    /// this URL as the `id_token_hint` query parameter, which allows the
+    /// provider to skip its logout confirmation page.
+    ///
     /// Finally, identity providers often require you to register the
     /// logout URL in their configuration, usually in the same place where
     /// you register your [redirect URL](Self::redirect_url).
     pub idp_logout_url: Option<String>,
 }
 
+/// Complete middleware configuration, which includes the
+/// [provider configuration](Config) as well as all of the optional
+/// middleware settings.
+///
+/// This struct can be deserialized from an application's configuration
+/// system (environment variables, configuration files, etc.) and then
+/// used to construct the middleware with
+/// [`from_config`](OpenIdConnectMiddleware::from_config). The provider
+/// configuration fields are flattened into this struct, and every other
+/// field is optional; missing fields use the
+/// [middleware defaults](OpenIdConnectMiddleware::new).
+#[derive(Debug, Deserialize, Clone)]
+pub struct OpenIdConnectConfig {
+    /// Identity Provider and client configuration.
+    #[serde(flatten)]
+    pub provider: Config,
+
+    /// See [`with_provider`](OpenIdConnectMiddleware::with_provider).
+    pub provider_name: Option<String>,
+
+    /// See
+    /// [`with_session_namespace`](OpenIdConnectMiddleware::with_session_namespace).
+    pub session_namespace: Option<String>,
+
+    /// See [`with_mount_path`](OpenIdConnectMiddleware::with_mount_path).
+    pub mount_path: Option<String>,
+
+    /// See [`with_scope_path`](OpenIdConnectMiddleware::with_scope_path).
+    pub scope_path: Option<String>,
+
+    /// See [`with_scopes`](OpenIdConnectMiddleware::with_scopes).
+    pub scopes: Option<Vec<String>>,
+
+    /// See
+    /// [`with_offline_access`](OpenIdConnectMiddleware::with_offline_access).
+    pub offline_access: Option<bool>,
+
+    /// See [`with_acr_values`](OpenIdConnectMiddleware::with_acr_values).
+    pub acr_values: Option<Vec<String>>,
+
+    /// See [`with_resource`](OpenIdConnectMiddleware::with_resource),
+    /// which is called for each resource.
+    #[serde(default)]
+    pub resources: Vec<String>,
+
+    /// See
+    /// [`with_session_claims`](OpenIdConnectMiddleware::with_session_claims).
+    pub session_claims: Option<Vec<String>>,
+
+    /// See [`with_user_id_claim`](OpenIdConnectMiddleware::with_user_id_claim).
+    pub user_id_claim: Option<String>,
+
+    /// See
+    /// [`with_additional_audiences`](OpenIdConnectMiddleware::with_additional_audiences).
+    pub additional_audiences: Option<Vec<String>>,
+
+    /// See [`with_login_path`](OpenIdConnectMiddleware::with_login_path).
+    pub login_path: Option<String>,
+
+    /// See
+    /// [`with_login_landing_path`](OpenIdConnectMiddleware::with_login_landing_path).
+    pub login_landing_path: Option<String>,
+
+    /// See [`with_return_param`](OpenIdConnectMiddleware::with_return_param).
+    pub return_param: Option<String>,
+
+    /// See
+    /// [`with_intercept_callback`](OpenIdConnectMiddleware::with_intercept_callback).
+    pub intercept_callback: Option<bool>,
+
+    /// See [`with_login_flash`](OpenIdConnectMiddleware::with_login_flash).
+    pub login_flash: Option<String>,
+
+    /// See
+    /// [`with_login_required_message`](OpenIdConnectMiddleware::with_login_required_message).
+    pub login_required_message: Option<String>,
+
+    /// See [`with_logout_path`](OpenIdConnectMiddleware::with_logout_path).
+    pub logout_path: Option<String>,
+
+    /// See
+    /// [`with_frontchannel_logout_path`](OpenIdConnectMiddleware::with_frontchannel_logout_path).
+    pub frontchannel_logout_path: Option<String>,
+
+    /// See
+    /// [`with_backchannel_logout_path`](OpenIdConnectMiddleware::with_backchannel_logout_path).
+    pub backchannel_logout_path: Option<String>,
+
+    /// See
+    /// [`with_logout_destroys_session`](OpenIdConnectMiddleware::with_logout_destroys_session).
+    pub logout_destroys_session: Option<bool>,
+
+    /// See
+    /// [`with_logout_landing_path`](OpenIdConnectMiddleware::with_logout_landing_path).
+    pub logout_landing_path: Option<String>,
+
+    /// See
+    /// [`with_login_rejected_path`](OpenIdConnectMiddleware::with_login_rejected_path).
+    pub login_rejected_path: Option<String>,
+
+    /// See [`with_public_paths`](OpenIdConnectMiddleware::with_public_paths).
+    pub public_paths: Option<Vec<String>>,
+
+    /// See
+    /// [`with_require_signed_session`](OpenIdConnectMiddleware::with_require_signed_session).
+    pub require_signed_session: Option<bool>,
+
+    /// See
+    /// [`with_strict_authentication`](OpenIdConnectMiddleware::with_strict_authentication).
+    pub strict_authentication: Option<bool>,
+
+    /// See
+    /// [`with_bearer_token_auth`](OpenIdConnectMiddleware::with_bearer_token_auth).
+    pub bearer_token_auth: Option<bool>,
+
+    /// See
+    /// [`with_bearer_audience`](OpenIdConnectMiddleware::with_bearer_audience).
+    pub bearer_audience: Option<String>,
+
+    /// See
+    /// [`with_trusted_proxy`](OpenIdConnectMiddleware::with_trusted_proxy).
+    pub trusted_proxy: Option<bool>,
+
+    /// See
+    /// [`with_htmx_support`](OpenIdConnectMiddleware::with_htmx_support).
+    pub htmx_support: Option<bool>,
+
+    /// See [`with_trace_user`](OpenIdConnectMiddleware::with_trace_user).
+    pub trace_user: Option<bool>,
+
+    /// See
+    /// [`with_require_email_verified`](OpenIdConnectMiddleware::with_require_email_verified).
+    pub require_email_verified: Option<bool>,
+
+    /// See
+    /// [`with_require_requested_scopes`](OpenIdConnectMiddleware::with_require_requested_scopes).
+    pub require_requested_scopes: Option<bool>,
+
+    /// See [`with_verify_at_hash`](OpenIdConnectMiddleware::with_verify_at_hash).
+    pub verify_at_hash: Option<bool>,
+
+    /// See [`with_auth_method`](OpenIdConnectMiddleware::with_auth_method).
+    pub auth_method: Option<CoreClientAuthMethod>,
+
+    /// See [`with_par`](OpenIdConnectMiddleware::with_par).
+    pub par: Option<bool>,
+}
+
+/// How the middleware responds to unauthenticated requests for routes
+/// that require authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UnauthenticatedBehavior {
+    /// Send the browser through the login process, using the
+    /// [redirect strategy](OpenIdConnectMiddleware::with_unauthenticated_redirect_strategy).
+    Redirect,
+
+    /// Return a `401 Unauthorized` response, leaving it up to the client
+    /// (for example, a single-page application calling an API) to decide
+    /// when to start the login process.
+    Unauthorized,
+
+    /// Decide for each request based on its `Accept` header: requests
+    /// that accept JSON but not HTML (API clients) receive a `401
+    /// Unauthorized` response, whereas all other requests (browsers)
+    /// are sent through the login process.
+    Negotiate,
+}
+
+/// How the middleware responds to requests for the login path from a
+/// session that has already been authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ReloginBehavior {
+    /// Always send the browser through the login process, which
+    /// replaces the session's authentication state with the result of
+    /// the new login.
+    ForceReauth,
+
+    /// Redirect the browser straight to the [login landing
+    /// path](OpenIdConnectMiddleware::with_login_landing_path) without
+    /// contacting the Identity Provider.
+    SkipIfAuthenticated,
+}
+
+/// Whether or not ID tokens must contain the `nonce` claim.
+///
+/// The nonce binds the ID token to the browser session that started the
+/// login, which prevents an attacker from replaying an ID token that was
+/// issued for a different login.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NonceMode {
+    /// Reject ID tokens that do not contain the nonce that was sent to
+    /// the Identity Provider.
+    Required,
+
+    /// Accept ID tokens that do not contain a nonce claim at all, for
+    /// providers that do not echo the nonce back. ID tokens that *do*
+    /// contain a nonce claim must still contain the expected nonce.
+    ///
+    /// This weakens the protection against ID token replay, and should
+    /// only be used with providers that are known to omit the claim.
+    Optional,
+}
+
+/// The client fingerprint to which an authenticated session is bound.
+///
+/// The fingerprint is recorded when the login completes and checked on
+/// every subsequent request; a session whose fingerprint no longer
+/// matches is treated as stolen, so its authentication state is cleared
+/// and the browser is sent back through the login process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SessionBinding {
+    /// Do not bind sessions to the client.
+    None,
+
+    /// Bind sessions to (a hash of) the client's `User-Agent` header.
+    UserAgent,
+
+    /// Bind sessions to the network prefix of the client's IP address:
+    /// the `/24` prefix for IPv4 addresses and the `/48` prefix for IPv6
+    /// addresses. Behind a [trusted
+    /// proxy](OpenIdConnectMiddleware::with_trusted_proxy), the address
+    /// reported by the proxy is used instead of the peer address.
+    IpPrefix,
+}
+
+/// Reason why a session is no longer authenticated; see
+/// [`OpenIdConnectMiddleware::check_session`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InvalidSession {
+    /// The session has not been authenticated by this middleware.
+    Unauthenticated,
+    /// The session is used by a different client than the one that
+    /// logged in.
+    Rebound,
+    /// The session has exceeded the idle timeout.
+    Idle,
+    /// The access token has expired (with strict authentication).
+    Expired,
+}
+
+/// "Redirect" strategy that rejects the request instead.
+struct UnauthorizedResponse;
+
+impl RedirectStrategy for UnauthorizedResponse {
+    fn redirect(&self) -> Response {
+        Response::new(StatusCode::Unauthorized)
+    }
+}
+
+/// Header that HTMX adds to the requests that it makes.
+const HX_REQUEST: &str = "HX-Request";
+
+/// Header that tells HTMX to navigate to the given location.
+const HX_REDIRECT: &str = "HX-Redirect";
+
+/// "Redirect" strategy that tells HTMX to navigate to the login path,
+/// since HTMX would follow an HTTP redirect within the XHR request.
+struct HtmxRedirect(String);
+
+impl RedirectStrategy for HtmxRedirect {
+    fn redirect(&self) -> Response {
+        let mut res = Response::new(StatusCode::Ok);
+        res.insert_header(HX_REDIRECT, self.0.as_str());
+        res
+    }
+}
+
+/// How the Identity Provider returns the authorization response (the
+/// authorization code and CSRF state) to the redirect URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ResponseMode {
+    /// The response is encoded in the query string of a `GET` request
+    /// (this is the default response mode for the authorization code
+    /// flow).
+    Query,
+
+    /// The response is encoded in the form-encoded body of a `POST`
+    /// request, which keeps the authorization code out of browser
+    /// history and server logs.
+    ///
+    /// Note that the `POST` request is sent from the Identity Provider's
+    /// site, which means that browsers will not include `SameSite::Lax`
+    /// session cookies in the request; the session cookie must use the
+    /// `SameSite::None` policy instead (with all of the CSRF caveats
+    /// that come with that policy).
+    ///
+    /// Browsers also reject `SameSite::None` cookies that do not have
+    /// the `Secure` attribute. The middleware does not create the
+    /// session cookie itself; Tide's
+    /// [`SessionMiddleware`](tide::sessions::SessionMiddleware) sets the
+    /// `Secure` attribute only on responses to requests whose URL uses
+    /// the `https` scheme, so servers behind a TLS-terminating proxy
+    /// must restore the original scheme before the session middleware
+    /// runs. The login route logs a warning if it receives a request
+    /// over plain `http` while this response mode is enabled.
+    FormPost,
+}
+
+/// How the callback route responds once it has processed the
+/// authorization response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CallbackResponse {
+    /// Redirect the browser to the login landing path (or, if the login
+    /// was rejected, to the logout landing path).
+    Redirect,
+
+    /// Return a `200 OK` response with a JSON body that describes the
+    /// outcome of the login, for single-page applications that handle
+    /// the callback themselves and manage their own navigation:
+    ///
+    /// ```json
+    /// {"authenticated": true, "redirect": "/"}
+    /// ```
+    ///
+    /// `redirect` is the path to which the browser would otherwise have
+    /// been redirected.
+    Json,
+}
+
+/// Transient state of a login that has been started, but not yet
+/// completed, keyed in the session by the login's CSRF state so that
+/// the browser can have more than one login in flight (in multiple tabs,
+/// for example).
+///
+/// Completed logins are kept (without their PKCE verifier) until they
+/// expire, so that replays of their callback URL can be recognized.
+#[derive(Debug, Deserialize, Serialize)]
+struct PendingLogin {
+    nonce: Nonce,
+    issued_at: SystemTime,
+    pkce_verifier: Option<PkceCodeVerifier>,
+    #[serde(default)]
+    consumed: bool,
+    #[serde(default)]
+    return_to: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 enum MiddlewareSessionState {
-    PreAuth(CsrfToken, Nonce),
-    PostAuth(
-        SubjectIdentifier,
-        AccessToken,
-        Vec<Scope>,
-        StandardClaims<CoreGenderClaim>,
-    ),
+    PostAuth {
+        subject: SubjectIdentifier,
+        access_token: AccessToken,
+        scopes: Vec<Scope>,
+        user_info: Box<StandardClaims<CoreGenderClaim>>,
+        expires_at: Option<SystemTime>,
+        authenticated_at: SystemTime,
+        #[serde(default)]
+        refresh_token: Option<RefreshToken>,
+        #[serde(default)]
+        provider: Option<String>,
+        #[serde(default)]
+        issuer: Option<IssuerUrl>,
+        #[serde(default)]
+        session_id: Option<String>,
+        #[serde(default)]
+        id_token: Option<String>,
+        #[serde(default)]
+        user_id: Option<String>,
+        /// Time at which the login completed (as opposed to
+        /// `authenticated_at`, which may be the time at which the user
+        /// last authenticated with the Identity Provider).
+        #[serde(default)]
+        logged_in_at: Option<SystemTime>,
+        /// Time at which the user info was last retrieved from the
+        /// Identity Provider (if after the login).
+        #[serde(default)]
+        claims_refreshed_at: Option<SystemTime>,
+        /// Claims added by the [claim
+        /// enricher](OpenIdConnectMiddleware::with_claim_enricher).
+        #[serde(default)]
+        enriched_claims: HashMap<String, serde_json::Value>,
+    },
+}
+
+impl MiddlewareSessionState {
+    /// Replaces the access token and refresh token with the result of
+    /// `f`, which is given the name of each token's field along with the
+    /// token itself. Returns `None` if `f` fails for either token.
+    fn map_tokens(mut self, f: impl Fn(&str, &str) -> Option<String>) -> Option<Self> {
+        let Self::PostAuth {
+            access_token,
+            refresh_token,
+            ..
+        } = &mut self;
+        *access_token = AccessToken::new(f("access_token", access_token.secret())?);
+        if let Some(refresh_token) = refresh_token {
+            *refresh_token = RefreshToken::new(f("refresh_token", refresh_token.secret())?);
+        }
+        Some(self)
+    }
+}
+
+/// Provider-specific state that is initialized from the Identity
+/// Provider's metadata.
+struct DiscoveredProvider {
+    issuer_url: IssuerUrl,
+    id_token_signing_algs: Vec<CoreJwsSigningAlgorithm>,
+    jwks: JwksCache,
+    client: CoreClient,
+    /// Client that does not send the client secret, for token requests
+    /// that authenticate with a client assertion instead.
+    assertion_client: CoreClient,
+    token_url: Option<TokenUrl>,
+    par_url: Option<Url>,
+}
+
+impl DiscoveredProvider {
+    fn new(
+        provider_metadata: DiscoveredMetadata,
+        client_id: ClientId,
+        client_secret: Option<ClientSecret>,
+        redirect_url: RedirectUrl,
+        discovered_at: SystemTime,
+    ) -> Self {
+        // Seed the JWKS cache with the keys that were retrieved as part
+        // of the discovery process.
+        let jwks = JwksCache::new(
+            provider_metadata.jwks_uri().clone(),
+            provider_metadata.jwks().clone(),
+            discovered_at,
+        );
+        let issuer_url = provider_metadata.issuer().clone();
+        let token_url = provider_metadata.token_endpoint().cloned();
+        let par_url = provider_metadata
+            .additional_metadata()
+            .pushed_authorization_request_endpoint
+            .clone();
+        // Unsigned ID tokens are never accepted, even if the provider
+        // claims to issue them.
+        let id_token_signing_algs = provider_metadata
+            .id_token_signing_alg_values_supported()
+            .iter()
+            .filter(|alg| **alg != CoreJwsSigningAlgorithm::None)
+            .cloned()
+            .collect();
+
+        // Create the OpenID Connect client.
+        let assertion_client =
+            CoreClient::from_provider_metadata(provider_metadata.clone(), client_id.clone(), None)
+                .set_redirect_uri(redirect_url.clone());
+        let client =
+            CoreClient::from_provider_metadata(provider_metadata, client_id, client_secret)
+                .set_redirect_uri(redirect_url);
+
+        Self {
+            issuer_url,
+            id_token_signing_algs,
+            jwks,
+            client,
+            assertion_client,
+            token_url,
+            par_url,
+        }
+    }
+}
+
+/// Discovery that has been deferred until the first request by
+/// [`OpenIdConnectMiddleware::lazy`].
+struct LazyDiscovery {
+    backoff: Mutex<DiscoveryBackoff>,
+}
+
+/// Failed discovery attempts, and the time before which discovery will
+/// not be attempted again.
+#[derive(Default)]
+struct DiscoveryBackoff {
+    failures: u32,
+    retry_at: Option<SystemTime>,
 }
 
 /// Open ID Connect Middleware.
 pub struct OpenIdConnectMiddleware {
     login_path: String,
+    login_method: Method,
+    redirect_status: StatusCode,
+    interstitial_login: bool,
     redirect_url: RedirectUrl,
+    provider: Option<String>,
+    session_namespace: String,
+    mount_path: String,
+    scope_path: String,
     scopes: Vec<Scope>,
+    offline_access: bool,
+    acr_values: Vec<AuthenticationContextClass>,
+    extra_authorize_params: Vec<(String, String)>,
+    session_claims: Option<Vec<String>>,
+    user_id_claim: Option<String>,
+    resources: Vec<String>,
+    response_mode: ResponseMode,
+    authentication_flow: AuthenticationFlow<CoreResponseType>,
+    par: bool,
+    callback_response: CallbackResponse,
     login_landing_path: String,
+    return_param: String,
+    intercept_callback: bool,
+    login_flash: Option<String>,
+    login_required_message: Option<String>,
     logout_path: String,
+    frontchannel_logout_path: Option<String>,
+    backchannel_logout_path: Option<String>,
     logout_destroys_session: bool,
     idp_logout_url: Option<String>,
     logout_landing_path: String,
-    client: CoreClient,
+    login_rejected_path: Option<String>,
+    public_paths: Vec<String>,
+    require_signed_session: bool,
+    session_store: Option<(TypeId, &'static str)>,
+    session_index: Option<SessionIndex>,
+    token_encryption_key: Option<TokenEncryptionKey>,
+    timing_policy: TimingPolicy,
+    jwks_refresh_interval: Duration,
+    http_timeout: Duration,
+    token_exchange_retries: u32,
+    idle_timeout: Option<Duration>,
+    session_binding: SessionBinding,
+    claims_refresh_interval: Option<Duration>,
+    strict_authentication: bool,
+    bearer_token_auth: bool,
+    bearer_audience: Option<String>,
+    trusted_proxy: bool,
+    htmx_support: bool,
+    trace_user: bool,
+    clock: Arc<dyn Clock>,
+    issuer_url: IssuerUrl,
+    client_id: ClientId,
+    client_secret: Option<ClientSecret>,
+    auth_method: CoreClientAuthMethod,
+    client_assertion_key: Option<ClientAssertionKey>,
+    pkce: bool,
+    discovered: RwLock<Option<Arc<DiscoveredProvider>>>,
+    lazy_discovery: Option<LazyDiscovery>,
+    allowed_algorithms: Option<Vec<CoreJwsSigningAlgorithm>>,
+    nonce_mode: NonceMode,
+    verify_at_hash: bool,
+    insecure_skip_signature_verification: bool,
+    id_token_decryption_key: Option<IdTokenDecryptionKey>,
+    additional_audiences: Vec<String>,
     redirect_strategy: Arc<dyn RedirectStrategy>,
+    unauthenticated_behavior: UnauthenticatedBehavior,
+    relogin_behavior: ReloginBehavior,
+    require_email_verified: bool,
+    require_requested_scopes: bool,
+    claims_validator: Option<Arc<ClaimsValidator>>,
+    claim_enricher: Option<Arc<ClaimEnricher>>,
+    logout_url_fn: Option<Arc<LogoutUrlFn>>,
+    on_login: Option<Arc<LoginHook>>,
+    on_login_failure: Option<Arc<LoginFailureHook>>,
+    error_renderer: Option<Arc<ErrorRenderer>>,
+    metrics: Option<Arc<MetricsHook>>,
+    random_source: Option<Arc<RandomSource>>,
 }
 
 impl std::fmt::Debug for OpenIdConnectMiddleware {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("OpenIdConnectMiddleware")
             .field("login_path", &self.login_path)
+            .field("login_method", &self.login_method)
+            .field("redirect_status", &self.redirect_status)
+            .field("unauthenticated_behavior", &self.unauthenticated_behavior)
+            .field("relogin_behavior", &self.relogin_behavior)
+            .field("interstitial_login", &self.interstitial_login)
             .field("scopes", &self.scopes)
+            .field("offline_access", &self.offline_access)
+            .field("acr_values", &self.acr_values)
+            .field("extra_authorize_params", &self.extra_authorize_params)
+            .field("session_claims", &self.session_claims)
+            .field("user_id_claim", &self.user_id_claim)
+            .field("resources", &self.resources)
+            .field("response_mode", &self.response_mode)
+            .field("authentication_flow", &self.authentication_flow)
+            .field("par", &self.par)
+            .field("callback_response", &self.callback_response)
             .field("redirect_url", &self.redirect_url)
+            .field("provider", &self.provider)
+            .field("session_namespace", &self.session_namespace)
+            .field("mount_path", &self.mount_path)
+            .field("scope_path", &self.scope_path)
             .field("login_landing_path", &self.login_landing_path)
+            .field("return_param", &self.return_param)
+            .field("intercept_callback", &self.intercept_callback)
+            .field("login_flash", &self.login_flash)
+            .field("login_required_message", &self.login_required_message)
             .field("idp_logout_url", &self.idp_logout_url)
             .field("logout_path", &self.logout_path)
+            .field("frontchannel_logout_path", &self.frontchannel_logout_path)
+            .field("backchannel_logout_path", &self.backchannel_logout_path)
             .field("logout_destroys_session", &self.logout_destroys_session)
             .field("logout_landing_path", &self.logout_landing_path)
+            .field("login_rejected_path", &self.login_rejected_path)
+            .field("public_paths", &self.public_paths)
+            .field("require_signed_session", &self.require_signed_session)
+            .field(
+                "session_store",
+                &self.session_store.map(|(_, store_type)| store_type),
+            )
+            .field("token_encryption", &self.token_encryption_key.is_some())
+            .field("timing_policy", &self.timing_policy)
+            .field("jwks_refresh_interval", &self.jwks_refresh_interval)
+            .field("http_timeout", &self.http_timeout)
+            .field("token_exchange_retries", &self.token_exchange_retries)
+            .field("allowed_algorithms", &self.allowed_algorithms)
+            .field("nonce_mode", &self.nonce_mode)
+            .field("verify_at_hash", &self.verify_at_hash)
+            .field(
+                "insecure_skip_signature_verification",
+                &self.insecure_skip_signature_verification,
+            )
+            .field(
+                "id_token_decryption_key",
+                &self.id_token_decryption_key.is_some(),
+            )
+            .field("additional_audiences", &self.additional_audiences)
+            .field("auth_method", &self.auth_method)
+            .field("client_assertion_key", &self.client_assertion_key)
+            .field("pkce", &self.pkce)
+            .field("lazy_discovery", &self.lazy_discovery.is_some())
+            .field("idle_timeout", &self.idle_timeout)
+            .field("session_binding", &self.session_binding)
+            .field("claims_refresh_interval", &self.claims_refresh_interval)
+            .field("strict_authentication", &self.strict_authentication)
+            .field("bearer_token_auth", &self.bearer_token_auth)
+            .field("bearer_audience", &self.bearer_audience)
+            .field("trusted_proxy", &self.trusted_proxy)
+            .field("htmx_support", &self.htmx_support)
+            .field("trace_user", &self.trace_user)
+            .field("require_email_verified", &self.require_email_verified)
+            .field("require_requested_scopes", &self.require_requested_scopes)
+            .field("claims_validator", &self.claims_validator.is_some())
+            .field("claim_enricher", &self.claim_enricher.is_some())
+            .field("logout_url_fn", &self.logout_url_fn.is_some())
+            .field("on_login", &self.on_login.is_some())
+            .field("on_login_failure", &self.on_login_failure.is_some())
+            .field("error_renderer", &self.error_renderer.is_some())
+            .field("metrics", &self.metrics.is_some())
+            .field("random_source", &self.random_source.is_some())
             .finish()
     }
 }
@@ -105,18 +827,84 @@ impl OpenIdConnectMiddleware {
     ///
     /// Panics if the OpenID Connect provider metadata could not be
     /// retrieved or does not match the configured
-    /// [`issuer_url`](Config::issuer_url).
+    /// [`issuer_url`](Config::issuer_url), or if the path of the
+    /// [redirect URL](Config::redirect_url) is the default [login
+    /// path](Self::with_login_path), `/login`. (Applications that need
+    /// that path for the redirect URL can configure a different login
+    /// path with [`from_config`](Self::from_config).)
     ///
     /// # Defaults
     ///
     /// The defaults for OpenIdConnectMiddleware are:
     /// - redirect strategy: [`HttpRedirect`](crate::redirect_strategy::HttpRedirect)
+    /// - unauthenticated response: [`UnauthenticatedBehavior::Redirect`]
+    /// - relogin behavior: [`ReloginBehavior::SkipIfAuthenticated`]
     /// - login path: `/login`
+    /// - login method: `GET`
+    /// - redirect status: `302 Found`
+    /// - interstitial login: `false`
     /// - scopes: `["openid"]`
+    /// - offline access: `false`
+    /// - ACR values: none
+    /// - extra authorize parameters: none
+    /// - session claims: all user info claims
+    /// - user id claim: `sub`
+    /// - resource: none
+    /// - response mode: [`ResponseMode::Query`]
+    /// - authentication flow: [`AuthenticationFlow::AuthorizationCode`]
+    /// - pushed authorization requests: `false`
+    /// - callback response: [`CallbackResponse::Redirect`]
+    /// - provider: none
+    /// - session namespace: `tide.oidc`
+    /// - mount path: `/`
+    /// - scope path: `/`
     /// - login landing path: `/`
+    /// - return parameter: `return_to`
+    /// - intercept callback: `true`
+    /// - login flash: none
+    /// - login required message: none
     /// - logout path: `/logout`
+    /// - front-channel logout path: none
+    /// - back-channel logout path: none
     /// - logout destroys session: `true`
     /// - logout landing path: `/`
+    /// - login rejected path: the logout landing path
+    /// - public paths: none
+    /// - require signed session: `true`
+    /// - session store: none (sessions cannot be revoked)
+    /// - token encryption key: none (tokens are stored unencrypted)
+    /// - timing policy: [`TimingPolicy::default()`]
+    /// - JWKS refresh interval: 1 hour
+    /// - HTTP timeout: 30 seconds
+    /// - token exchange retries: 2
+    /// - allowed algorithms: all algorithms advertised by the provider
+    /// - nonce verification: [`NonceMode::Required`]
+    /// - access token hash verification: `false`
+    /// - skip ID token signature verification: `false`
+    /// - ID token decryption key: none
+    /// - client authentication method: `client_secret_basic`
+    /// - client assertion key: none
+    /// - additional audiences: none
+    /// - idle timeout: none
+    /// - session binding: [`SessionBinding::None`]
+    /// - claims refresh interval: none
+    /// - strict authentication: `false`
+    /// - bearer token authentication: `false`
+    /// - bearer audience: the client ID
+    /// - trusted proxy: `false`
+    /// - HTMX support: `false`
+    /// - trace user: `false`
+    /// - clock: [`SystemClock`](crate::clock::SystemClock)
+    /// - require email verified: `false`
+    /// - require requested scopes: `false`
+    /// - claims validator: none
+    /// - claim enricher: none
+    /// - logout URL function: none (the standard `id_token_hint`
+    ///   parameter is added to the logout URL)
+    /// - login hooks: none
+    /// - error renderer: none (errors are plain status responses)
+    /// - metrics hook: none
+    /// - random source: the built-in random generator
     ///
     /// # Examples
     ///
@@ -138,36 +926,489 @@ impl OpenIdConnectMiddleware {
     /// # })
     /// ```
     pub async fn new(config: &Config) -> Self {
+        let middleware = Self::discover(config).await;
+        middleware.assert_distinct_login_path();
+        middleware
+    }
+
+    /// Creates a new instance as [`new`](Self::new) does, but without
+    /// checking the login path, which the caller is about to configure.
+    async fn discover(config: &Config) -> Self {
         // Get the OpenID Connect provider metadata.
         let provider_metadata =
-            CoreProviderMetadata::discover_async(config.issuer_url.clone(), http_client)
+            DiscoveredMetadata::discover_async(config.issuer_url.clone(), http_client)
                 .await
                 .expect("Unable to load OpenID Connect provider metadata.");
 
-        // Create the OpenID Connect client.
-        let client = CoreClient::from_provider_metadata(
+        Self::from_discovered_metadata(
+            provider_metadata,
+            config.client_id.clone(),
+            Some(config.client_secret.clone()),
+            config.redirect_url.clone(),
+            config.idp_logout_url.clone(),
+        )
+    }
+
+    /// Create a new instance using provider metadata that is retrieved
+    /// from an explicit URL, with the same defaults as [`new`](Self::new).
+    ///
+    /// Some Identity Providers do not publish their metadata at the
+    /// standard `.well-known/openid-configuration` location relative to
+    /// their issuer URL (for example, providers with tenant-specific
+    /// metadata paths). The issuer listed in the metadata must have the
+    /// same origin (scheme, host, and port) as the metadata URL.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the metadata URL is invalid, if the OpenID Connect
+    /// provider metadata could not be retrieved or lists an issuer with
+    /// a different origin than the metadata URL, or if the path of the
+    /// redirect URL is the default login path.
+    pub async fn from_metadata_url(
+        metadata_url: &str,
+        client_id: ClientId,
+        client_secret: ClientSecret,
+        redirect_url: RedirectUrl,
+    ) -> Self {
+        let metadata_url = Url::parse(metadata_url).expect("Invalid OpenID Connect metadata URL.");
+        let provider_metadata = discover_from_metadata_url(metadata_url)
+            .await
+            .expect("Unable to load OpenID Connect provider metadata.");
+
+        let middleware = Self::from_discovered_metadata(
+            provider_metadata,
+            client_id,
+            Some(client_secret),
+            redirect_url,
+            None,
+        );
+        middleware.assert_distinct_login_path();
+        middleware
+    }
+
+    /// Create a new instance from provider metadata that has already
+    /// been retrieved (from a cache, for example, or from configuration
+    /// in environments without access to the Identity Provider's
+    /// discovery endpoint), with the same defaults as [`new`](Self::new).
+    ///
+    /// No discovery request is made. The metadata should include the
+    /// provider's [JSON Web Key Set](CoreProviderMetadata::set_jwks),
+    /// since the keys are otherwise only fetched once the [refresh
+    /// interval](Self::with_jwks_refresh_interval) has elapsed.
+    ///
+    /// [`CoreProviderMetadata`] does not include the [pushed
+    /// authorization request](Self::with_par) endpoint, and so the
+    /// middleware never pushes its authorization requests to providers
+    /// that are configured in this way.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the provider metadata is invalid, or if the path of the
+    /// redirect URL is the default login path.
+    pub fn from_provider_metadata(
+        provider_metadata: CoreProviderMetadata,
+        client_id: ClientId,
+        client_secret: ClientSecret,
+        redirect_url: RedirectUrl,
+    ) -> Self {
+        let middleware = Self::from_discovered_metadata(
+            from_core_metadata(provider_metadata)
+                .expect("Invalid OpenID Connect provider metadata."),
+            client_id,
+            Some(client_secret),
+            redirect_url,
+            None,
+        );
+        middleware.assert_distinct_login_path();
+        middleware
+    }
+
+    /// Create a new instance for a *public* client -- one that does not
+    /// have a client secret, such as a native application -- with the
+    /// same defaults as [`new`](Self::new).
+    ///
+    /// Public clients cannot authenticate themselves to the Identity
+    /// Provider when exchanging the authorization code for a token, and
+    /// so the middleware always uses [PKCE] to bind the token request to
+    /// the browser that started the login process.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the OpenID Connect provider metadata could not be
+    /// retrieved or does not match the given issuer URL, or if the path
+    /// of the redirect URL is the default login path.
+    ///
+    /// [PKCE]: https://datatracker.ietf.org/doc/html/rfc7636
+    pub async fn new_public(
+        issuer_url: IssuerUrl,
+        client_id: ClientId,
+        redirect_url: RedirectUrl,
+    ) -> Self {
+        // Get the OpenID Connect provider metadata.
+        let provider_metadata = DiscoveredMetadata::discover_async(issuer_url, http_client)
+            .await
+            .expect("Unable to load OpenID Connect provider metadata.");
+
+        let mut middleware =
+            Self::from_discovered_metadata(provider_metadata, client_id, None, redirect_url, None);
+        middleware.pkce = true;
+        middleware.assert_distinct_login_path();
+        middleware
+    }
+
+    /// Create a new instance by registering a new client with the
+    /// Identity Provider ([OpenID Connect Dynamic Client Registration]),
+    /// with the same defaults as [`new`](Self::new).
+    ///
+    /// The provider's metadata must include a registration endpoint. The
+    /// redirect URL is added to the redirect URIs of the registration
+    /// request (if the request does not already list it). The middleware
+    /// then uses the client id and secret that were returned by the
+    /// provider, along with the returned [client authentication
+    /// method](Self::with_auth_method) (if the middleware supports that
+    /// method). Clients that were registered without a secret are
+    /// [public clients](Self::new_public).
+    ///
+    /// Every call registers a new client, so applications should store
+    /// the registered [`client_id`](Self::client_id) and
+    /// [`client_secret`](Self::client_secret), and use those credentials
+    /// (with [`new`](Self::new)) from then on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the OpenID Connect provider metadata could not be
+    /// retrieved, if the provider does not support dynamic client
+    /// registration, if the registration request fails, or if the path
+    /// of the redirect URL is the default login path.
+    ///
+    /// [OpenID Connect Dynamic Client Registration]: https://openid.net/specs/openid-connect-registration-1_0.html
+    pub async fn register(
+        issuer_url: IssuerUrl,
+        registration_request: CoreClientRegistrationRequest,
+        redirect_url: RedirectUrl,
+    ) -> Self {
+        // Get the OpenID Connect provider metadata.
+        let provider_metadata = DiscoveredMetadata::discover_async(issuer_url, http_client)
+            .await
+            .expect("Unable to load OpenID Connect provider metadata.");
+
+        // Register the client.
+        let registration_endpoint = provider_metadata
+            .registration_endpoint()
+            .expect("OpenID Connect provider does not support dynamic client registration.");
+        let mut redirect_uris = registration_request.redirect_uris().clone();
+        if !redirect_uris.contains(&redirect_url) {
+            redirect_uris.push(redirect_url.clone());
+        }
+        let registration = registration_request
+            .set_redirect_uris(redirect_uris)
+            .register_async(registration_endpoint, http_client)
+            .await
+            .expect("Unable to register the OpenID Connect client.");
+
+        let client_secret = registration.client_secret().cloned();
+        let mut middleware = Self::from_discovered_metadata(
             provider_metadata,
+            registration.client_id().clone(),
+            client_secret.clone(),
+            redirect_url,
+            None,
+        );
+        middleware.pkce = client_secret.is_none();
+        if let Some(
+            auth_method @ (CoreClientAuthMethod::ClientSecretBasic
+            | CoreClientAuthMethod::ClientSecretPost
+            | CoreClientAuthMethod::PrivateKeyJwt),
+        ) = registration.token_endpoint_auth_method()
+        {
+            middleware.auth_method = auth_method.clone();
+        }
+        middleware.assert_distinct_login_path();
+        middleware
+    }
+
+    /// Create a new instance that defers the discovery of the Identity
+    /// Provider's metadata until the first request, with the same
+    /// defaults as [`new`](Self::new).
+    ///
+    /// This allows the server to start even if the Identity Provider is
+    /// briefly unavailable. Requests that arrive before discovery has
+    /// succeeded are rejected with a `503 Service Unavailable` response.
+    /// Failed discovery attempts are retried by later requests, waiting
+    /// one second after the first failure and doubling the wait (up to a
+    /// minute) after every subsequent failure.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the path of the [redirect URL](Config::redirect_url) is
+    /// the default login path.
+    pub fn lazy(config: &Config) -> Self {
+        let mut middleware = Self::with_discovered_provider(
+            None,
+            config.issuer_url.clone(),
             config.client_id.clone(),
             Some(config.client_secret.clone()),
+            config.redirect_url.clone(),
+            config.idp_logout_url.clone(),
+        );
+        middleware.lazy_discovery = Some(LazyDiscovery {
+            backoff: Mutex::new(DiscoveryBackoff::default()),
+        });
+        middleware.assert_distinct_login_path();
+        middleware
+    }
+
+    /// Initializes the middleware (with our defaults) from the Identity
+    /// Provider's metadata.
+    fn from_discovered_metadata(
+        provider_metadata: DiscoveredMetadata,
+        client_id: ClientId,
+        client_secret: Option<ClientSecret>,
+        redirect_url: RedirectUrl,
+        idp_logout_url: Option<String>,
+    ) -> Self {
+        // The clock cannot have been configured yet, and so discovery
+        // necessarily happened according to the default clock.
+        let discovered = DiscoveredProvider::new(
+            provider_metadata,
+            client_id.clone(),
+            client_secret.clone(),
+            redirect_url.clone(),
+            SystemClock.now(),
+        );
+        let issuer_url = discovered.issuer_url.clone();
+        Self::with_discovered_provider(
+            Some(discovered),
+            issuer_url,
+            client_id,
+            client_secret,
+            redirect_url,
+            idp_logout_url,
         )
-        .set_redirect_uri(config.redirect_url.clone());
+    }
 
+    /// Initializes the middleware with our defaults, and with the
+    /// provider state (if discovery has already happened).
+    fn with_discovered_provider(
+        discovered: Option<DiscoveredProvider>,
+        issuer_url: IssuerUrl,
+        client_id: ClientId,
+        client_secret: Option<ClientSecret>,
+        redirect_url: RedirectUrl,
+        idp_logout_url: Option<String>,
+    ) -> Self {
         // Initialize the middleware with our defaults. Note that we do not
         // have to include "openid" in the (default) scopes, because the
         // openidconnect-rs crate always adds that to the scopes list.
         let login_path = "/login".to_string();
         Self {
             login_path: login_path.clone(),
+            login_method: Method::Get,
+            redirect_status: StatusCode::Found,
+            interstitial_login: false,
             scopes: vec![],
-            redirect_url: config.redirect_url.clone(),
+            offline_access: false,
+            acr_values: vec![],
+            extra_authorize_params: vec![],
+            session_claims: None,
+            user_id_claim: None,
+            resources: Vec::new(),
+            response_mode: ResponseMode::Query,
+            authentication_flow: AuthenticationFlow::AuthorizationCode,
+            par: false,
+            callback_response: CallbackResponse::Redirect,
+            redirect_url,
+            provider: None,
+            session_namespace: DEFAULT_SESSION_NAMESPACE.to_string(),
+            mount_path: "/".to_string(),
+            scope_path: "/".to_string(),
             login_landing_path: "/".to_string(),
-            client,
+            return_param: "return_to".to_string(),
+            intercept_callback: true,
+            login_flash: None,
+            login_required_message: None,
             redirect_strategy: Arc::new(HttpRedirect::new(login_path)),
+            unauthenticated_behavior: UnauthenticatedBehavior::Redirect,
+            relogin_behavior: ReloginBehavior::SkipIfAuthenticated,
+            require_email_verified: false,
+            require_requested_scopes: false,
+            claims_validator: None,
+            claim_enricher: None,
+            logout_url_fn: None,
+            on_login: None,
+            on_login_failure: None,
+            error_renderer: None,
+            metrics: None,
+            random_source: None,
             logout_path: "/logout".to_string(),
+            frontchannel_logout_path: None,
+            backchannel_logout_path: None,
             logout_destroys_session: true,
-            idp_logout_url: config.idp_logout_url.clone(),
+            idp_logout_url,
             logout_landing_path: "/".to_string(),
+            login_rejected_path: None,
+            public_paths: vec![],
+            require_signed_session: true,
+            session_store: None,
+            session_index: None,
+            token_encryption_key: None,
+            timing_policy: TimingPolicy::default(),
+            jwks_refresh_interval: Duration::from_secs(60 * 60),
+            http_timeout: DEFAULT_TIMEOUT,
+            token_exchange_retries: 2,
+            idle_timeout: None,
+            session_binding: SessionBinding::None,
+            claims_refresh_interval: None,
+            strict_authentication: false,
+            bearer_token_auth: false,
+            bearer_audience: None,
+            trusted_proxy: false,
+            htmx_support: false,
+            trace_user: false,
+            clock: Arc::new(SystemClock),
+            issuer_url,
+            client_id,
+            client_secret,
+            auth_method: CoreClientAuthMethod::ClientSecretBasic,
+            client_assertion_key: None,
+            pkce: false,
+            discovered: RwLock::new(discovered.map(Arc::new)),
+            lazy_discovery: None,
+            allowed_algorithms: None,
+            nonce_mode: NonceMode::Required,
+            verify_at_hash: false,
+            insecure_skip_signature_verification: false,
+            id_token_decryption_key: None,
+            additional_audiences: vec![],
+        }
+    }
+
+    /// Create a new instance from a complete middleware configuration,
+    /// applying every setting that is present in the configuration and
+    /// using the [defaults](Self::new) for the remaining settings.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the OpenID Connect provider metadata could not be
+    /// retrieved or does not match the configured
+    /// [`issuer_url`](Config::issuer_url), or if the configured login
+    /// path collides with the path of the redirect URL.
+    pub async fn from_config(config: &OpenIdConnectConfig) -> Self {
+        // The paths are only checked once all of them have been
+        // configured.
+        let mut middleware = Self::discover(&config.provider).await;
+
+        if let Some(provider_name) = &config.provider_name {
+            middleware = middleware.with_provider(provider_name);
         }
+        if let Some(session_namespace) = &config.session_namespace {
+            middleware = middleware.with_session_namespace(session_namespace);
+        }
+        if let Some(mount_path) = &config.mount_path {
+            middleware.mount_path = mount_path.to_string();
+        }
+        if let Some(scope_path) = &config.scope_path {
+            middleware.scope_path = scope_path.to_string();
+        }
+        if let Some(scopes) = &config.scopes {
+            middleware = middleware.with_scopes(scopes);
+        }
+        if let Some(offline_access) = config.offline_access {
+            middleware = middleware.with_offline_access(offline_access);
+        }
+        if let Some(acr_values) = &config.acr_values {
+            middleware = middleware.with_acr_values(acr_values);
+        }
+        for resource in &config.resources {
+            middleware = middleware.with_resource(resource);
+        }
+        if let Some(session_claims) = &config.session_claims {
+            middleware = middleware.with_session_claims(session_claims);
+        }
+        if let Some(user_id_claim) = &config.user_id_claim {
+            middleware = middleware.with_user_id_claim(user_id_claim);
+        }
+        if let Some(additional_audiences) = &config.additional_audiences {
+            middleware = middleware.with_additional_audiences(additional_audiences);
+        }
+        if let Some(login_path) = &config.login_path {
+            middleware.login_path = login_path.to_string();
+        }
+        if let Some(login_landing_path) = &config.login_landing_path {
+            middleware = middleware.with_login_landing_path(login_landing_path);
+        }
+        if let Some(return_param) = &config.return_param {
+            middleware = middleware.with_return_param(return_param);
+        }
+        if let Some(intercept_callback) = config.intercept_callback {
+            middleware = middleware.with_intercept_callback(intercept_callback);
+        }
+        if let Some(login_flash) = &config.login_flash {
+            middleware = middleware.with_login_flash(login_flash);
+        }
+        if let Some(login_required_message) = &config.login_required_message {
+            middleware = middleware.with_login_required_message(login_required_message);
+        }
+        if let Some(logout_path) = &config.logout_path {
+            middleware = middleware.with_logout_path(logout_path);
+        }
+        if let Some(frontchannel_logout_path) = &config.frontchannel_logout_path {
+            middleware = middleware.with_frontchannel_logout_path(frontchannel_logout_path);
+        }
+        if let Some(backchannel_logout_path) = &config.backchannel_logout_path {
+            middleware = middleware.with_backchannel_logout_path(backchannel_logout_path);
+        }
+        if let Some(logout_destroys_session) = config.logout_destroys_session {
+            middleware = middleware.with_logout_destroys_session(logout_destroys_session);
+        }
+        if let Some(logout_landing_path) = &config.logout_landing_path {
+            middleware = middleware.with_logout_landing_path(logout_landing_path);
+        }
+        if let Some(login_rejected_path) = &config.login_rejected_path {
+            middleware = middleware.with_login_rejected_path(login_rejected_path);
+        }
+        if let Some(public_paths) = &config.public_paths {
+            middleware = middleware.with_public_paths(public_paths);
+        }
+        if let Some(require_signed_session) = config.require_signed_session {
+            middleware = middleware.with_require_signed_session(require_signed_session);
+        }
+        if let Some(strict_authentication) = config.strict_authentication {
+            middleware = middleware.with_strict_authentication(strict_authentication);
+        }
+        if let Some(bearer_token_auth) = config.bearer_token_auth {
+            middleware = middleware.with_bearer_token_auth(bearer_token_auth);
+        }
+        if let Some(bearer_audience) = &config.bearer_audience {
+            middleware = middleware.with_bearer_audience(bearer_audience);
+        }
+        if let Some(trusted_proxy) = config.trusted_proxy {
+            middleware = middleware.with_trusted_proxy(trusted_proxy);
+        }
+        if let Some(htmx_support) = config.htmx_support {
+            middleware = middleware.with_htmx_support(htmx_support);
+        }
+        if let Some(trace_user) = config.trace_user {
+            middleware = middleware.with_trace_user(trace_user);
+        }
+        if let Some(require_email_verified) = config.require_email_verified {
+            middleware = middleware.with_require_email_verified(require_email_verified);
+        }
+        if let Some(require_requested_scopes) = config.require_requested_scopes {
+            middleware = middleware.with_require_requested_scopes(require_requested_scopes);
+        }
+        if let Some(verify_at_hash) = config.verify_at_hash {
+            middleware = middleware.with_verify_at_hash(verify_at_hash);
+        }
+        if let Some(auth_method) = &config.auth_method {
+            middleware = middleware.with_auth_method(auth_method.clone());
+        }
+        if let Some(par) = config.par {
+            middleware = middleware.with_par(par);
+        }
+
+        middleware.assert_distinct_login_path();
+        middleware
     }
 
     /// Sets the path to the "login" route that will be intercepted by the
@@ -175,38 +1416,499 @@ impl OpenIdConnectMiddleware {
     /// authentication page.
     ///
     /// Defaults to `/login`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the login path is the same as the path of the redirect
+    /// URL, since the middleware would then be unable to tell the start
+    /// of a login from its completion.
     pub fn with_login_path(mut self, login_path: &str) -> Self {
         self.login_path = login_path.to_string();
+        self.assert_distinct_login_path();
         self
     }
 
-    /// Adds one or more scopes to the OpenID Connect request.
+    /// Sets the name of the Identity Provider (`google` or `sso`, for
+    /// example), which allows an application to offer more than one
+    /// provider by installing one middleware instance per provider.
     ///
-    /// Defaults to `openid` (which is the minimum required scope).
-    pub fn with_scopes(mut self, scopes: &[impl AsRef<str>]) -> Self {
-        self.scopes = scopes
-            .iter()
-            .map(|s| Scope::new(s.as_ref().to_owned()))
-            .collect();
+    /// Each instance needs its own [login path](Self::with_login_path)
+    /// (`/login/google`, for example) and redirect URL
+    /// (`/callback/google`). The instances share the authentication
+    /// state in the session -- a session can only be authenticated by
+    /// one provider at a time -- and the provider that authenticated the
+    /// user is available from the [authenticated
+    /// user](crate::AuthenticatedUser::provider).
+    ///
+    /// Defaults to none
+    pub fn with_provider(mut self, provider: &str) -> Self {
+        self.provider = Some(provider.to_string());
         self
     }
 
-    /// Sets the path where the browser will be sent after a successful
-    /// login sequence.
+    /// Sets the prefix of the keys under which the middleware stores its
+    /// state in the session (`tide.oidc.flash`, for example), which
+    /// avoids collisions with session data that is stored by the
+    /// application or by other middleware.
     ///
-    /// Defaults to `/`
-    pub fn with_login_landing_path(mut self, login_landing_path: &str) -> Self {
-        self.login_landing_path = login_landing_path.to_string();
+    /// Middleware instances with different namespaces do not see each
+    /// other's authentication state. Changing the namespace of an
+    /// existing deployment therefore logs out every session.
+    ///
+    /// Defaults to `tide.oidc`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the namespace is empty.
+    pub fn with_session_namespace(mut self, session_namespace: &str) -> Self {
+        assert!(
+            !session_namespace.is_empty(),
+            "Session namespace must not be empty."
+        );
+        self.session_namespace = session_namespace.to_string();
         self
     }
 
-    /// Sets the path to the "logout" route that will be intercepted by
-    /// the middleware in order to clear the sessions's authentication
-    /// state.
+    /// Sets the path at which the application that contains this
+    /// middleware is [nested](tide::Route::nest) inside of another Tide
+    /// server (`/app`, for example). Tide removes that path from the
+    /// requests that it passes to the nested application, and so the
+    /// middleware removes it from the path of the redirect URL as well
+    /// when intercepting the callback.
     ///
-    /// Defaults to `/logout`
-    pub fn with_logout_path(mut self, logout_path: &str) -> Self {
-        self.logout_path = logout_path.to_string();
+    /// The login and logout paths are relative to the nested
+    /// application, and are not affected by the mount path. The landing
+    /// paths, on the other hand, are sent to the browser, and so must
+    /// include the mount path. The session cookie must also be sent
+    /// with requests to the nested application; Tide's session
+    /// middleware sets the path of the cookie to `/` unless configured
+    /// [otherwise](tide::sessions::SessionMiddleware::with_cookie_path).
+    ///
+    /// Defaults to `/` (not nested)
+    pub fn with_mount_path(mut self, mount_path: &str) -> Self {
+        self.mount_path = mount_path.to_string();
+        self.assert_distinct_login_path();
+        self
+    }
+
+    /// Sets the path of the [route](tide::Route) to which this
+    /// middleware has been [attached](tide::Route::with) (`/app`, for
+    /// example), when the middleware only applies to part of the
+    /// application. Unlike nesting, Tide does not remove that path from
+    /// the requests, and so the middleware removes it from the request
+    /// path and from the path of the redirect URL before intercepting
+    /// any of its routes.
+    ///
+    /// The login and logout paths are relative to the route, whereas
+    /// the landing paths must include the scope path (as with the
+    /// [mount path](Self::with_mount_path)). Tide only runs route
+    /// middleware for requests that match one of the route's endpoints,
+    /// so the route needs an endpoint for each of the paths that the
+    /// middleware intercepts; a wildcard endpoint (added with
+    /// `.at("*")` after attaching the middleware) covers all of them.
+    ///
+    /// Defaults to `/` (applies to the entire application)
+    pub fn with_scope_path(mut self, scope_path: &str) -> Self {
+        self.scope_path = scope_path.to_string();
+        self.assert_distinct_login_path();
+        self
+    }
+
+    /// Sets the HTTP method of the "login" route. Only requests with this
+    /// method will be intercepted by the middleware.
+    ///
+    /// Some applications prefer to initiate the login process with a
+    /// `POST` request -- for example, a "Sign in" form that includes its
+    /// own CSRF token -- so that the redirect to the Identity Provider
+    /// cannot be triggered by a simple cross-site link.
+    ///
+    /// Defaults to `GET`
+    pub fn with_login_method(mut self, login_method: Method) -> Self {
+        self.login_method = login_method;
+        self
+    }
+
+    /// Sets the status code of the redirects issued by the middleware
+    /// (to the Identity Provider, and to the login and logout landing
+    /// paths). A [`POST` login method](Self::with_login_method), for
+    /// example, is more correctly answered with a `303 See Other`.
+    ///
+    /// The [redirect
+    /// strategy](Self::with_unauthenticated_redirect_strategy) that
+    /// sends unauthenticated requests to the login path chooses its own
+    /// status code.
+    ///
+    /// Defaults to `302 Found`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the status code is not one of `301`, `302`, `303`, `307`,
+    /// or `308`.
+    pub fn with_redirect_status(mut self, redirect_status: StatusCode) -> Self {
+        assert!(
+            matches!(
+                redirect_status,
+                StatusCode::MovedPermanently
+                    | StatusCode::Found
+                    | StatusCode::SeeOther
+                    | StatusCode::TemporaryRedirect
+                    | StatusCode::PermanentRedirect
+            ),
+            "Redirect status must be a redirection status code, not {}.",
+            redirect_status
+        );
+        self.redirect_status = redirect_status;
+        self
+    }
+
+    /// Sets whether the login route redirects the browser to the
+    /// Identity Provider using an interstitial HTML page -- a `200 OK`
+    /// response which then navigates to the authorize URL using
+    /// JavaScript -- instead of a `302 Found` response.
+    ///
+    /// Some browsers (Safari's Intelligent Tracking Prevention, for
+    /// example) do not reliably store cookies that are set on a response
+    /// that immediately redirects to another site. The interstitial page
+    /// ensures that the session cookie -- which holds the authorization
+    /// state -- has been stored before the browser leaves the site.
+    ///
+    /// Defaults to `false`
+    pub fn with_interstitial_login(mut self, interstitial_login: bool) -> Self {
+        self.interstitial_login = interstitial_login;
+        self
+    }
+
+    /// Adds one or more scopes to the OpenID Connect request.
+    ///
+    /// Defaults to `openid` (which is the minimum required scope).
+    pub fn with_scopes(mut self, scopes: &[impl AsRef<str>]) -> Self {
+        self.scopes = scopes
+            .iter()
+            .map(|s| Scope::new(s.as_ref().to_owned()))
+            .collect();
+        self
+    }
+
+    /// Sets a flag indicating if the login process should request offline
+    /// access, which is how many Identity Providers decide whether or not
+    /// to issue a refresh token (allowing the middleware to [refresh
+    /// expired access tokens](crate::OpenIdConnectRequestExt::access_token)).
+    ///
+    /// Offline access adds the `offline_access` scope to the [configured
+    /// scopes](Self::with_scopes), and also adds `prompt=consent` to the
+    /// authorization request, since providers will only issue a refresh
+    /// token once the user has consented to offline access.
+    ///
+    /// Defaults to `false`
+    pub fn with_offline_access(mut self, offline_access: bool) -> Self {
+        self.offline_access = offline_access;
+        self
+    }
+
+    /// Requests one or more Authentication Context Class References
+    /// (the `acr_values` parameter) from the Identity Provider, which
+    /// allows the application to ask for a specific authentication
+    /// context (multi-factor authentication, for example).
+    ///
+    /// Defaults to no ACR values.
+    pub fn with_acr_values(mut self, acr_values: &[impl AsRef<str>]) -> Self {
+        self.acr_values = acr_values
+            .iter()
+            .map(|s| AuthenticationContextClass::new(s.as_ref().to_owned()))
+            .collect();
+        self
+    }
+
+    /// Requests an access token for a specific API (the `resource`
+    /// parameter from [RFC 8707]), rather than for the Identity
+    /// Provider's default audience. Call this more than once to request
+    /// a token for several APIs; each resource is added as a separate
+    /// `resource` parameter to the authorization and token requests.
+    ///
+    /// If the Identity Provider issues JWT access tokens, the `aud`
+    /// claim of the returned access token must include (at least one
+    /// of) the requested resources, otherwise the login is rejected. Opaque (non-JWT)
+    /// access tokens are accepted as-is. Note that this is a sanity
+    /// check on the token that the API will receive, and *not* a
+    /// verification of the token's signature -- that is the job of the
+    /// API itself.
+    ///
+    /// Defaults to none
+    ///
+    /// [RFC 8707]: https://datatracker.ietf.org/doc/html/rfc8707
+    pub fn with_resource(mut self, resource: &str) -> Self {
+        self.resources.push(resource.to_string());
+        self
+    }
+
+    /// Sets additional (usually provider-specific) query parameters to
+    /// add to the authorization URL, such as Auth0's `organization` or
+    /// `invitation` parameters. The names and values are URL-encoded by
+    /// the middleware.
+    ///
+    /// Defaults to none
+    ///
+    /// # Panics
+    ///
+    /// Panics if a parameter name is empty, or is one of the parameters
+    /// that the middleware manages itself (`response_type`, `client_id`,
+    /// `redirect_uri`, `scope`, `state`, `nonce`, `code_challenge`,
+    /// `code_challenge_method`, or `response_mode`).
+    pub fn with_extra_authorize_params(mut self, params: Vec<(String, String)>) -> Self {
+        for (name, _) in &params {
+            assert!(
+                !name.is_empty(),
+                "Authorization request parameter names must not be empty."
+            );
+            assert!(
+                !RESERVED_AUTHORIZE_PARAMS.contains(&name.as_str()),
+                "The \"{}\" authorization request parameter is managed by the middleware.",
+                name
+            );
+        }
+        self.extra_authorize_params = params;
+        self
+    }
+
+    /// Sets the user info claims (such as `email` or `name`) that are
+    /// stored in the session after the login completes; all other claims
+    /// are dropped. The `sub` claim is always stored. Localized claims
+    /// (such as `name#de`) are stored if their base claim is listed.
+    ///
+    /// Limiting the stored claims keeps the session small, which matters
+    /// when using a cookie-backed session store.
+    ///
+    /// Defaults to all of the claims returned by the user info endpoint
+    pub fn with_session_claims(mut self, claims: &[impl AsRef<str>]) -> Self {
+        self.session_claims = Some(
+            claims
+                .iter()
+                .map(|claim| claim.as_ref().to_string())
+                .collect(),
+        );
+        self
+    }
+
+    /// Sets the claim (such as `email` or `preferred_username`) that
+    /// provides the [user id](crate::OpenIdConnectRequestExt::user_id) of
+    /// the authenticated user. The claim is taken from the ID token or,
+    /// if the ID token does not include it, from the user info; users
+    /// without the claim fall back to the `sub` claim. Logins are
+    /// rejected if the claim is present but is not a string.
+    ///
+    /// Defaults to `sub`
+    pub fn with_user_id_claim(mut self, user_id_claim: &str) -> Self {
+        self.user_id_claim = Some(user_id_claim.to_string());
+        self
+    }
+
+    /// Sets how the Identity Provider returns the authorization response
+    /// to the redirect URL. The middleware accepts both `GET` and `POST`
+    /// requests to the redirect URL regardless of this setting; this only
+    /// determines which response mode is requested from the provider.
+    ///
+    /// Defaults to [`ResponseMode::Query`]
+    pub fn with_response_mode(mut self, response_mode: ResponseMode) -> Self {
+        self.response_mode = response_mode;
+        self
+    }
+
+    /// Sets the authentication flow (the `response_type` of the
+    /// authorization request). The [hybrid
+    /// flow](https://openid.net/specs/openid-connect-core-1_0.html#HybridFlowAuth)
+    /// (`code id_token`, for example) returns an ID token along with the
+    /// authorization code, in which case the callback verifies that ID
+    /// token -- including its `c_hash` claim, which binds it to the
+    /// authorization code -- before exchanging the code, and rejects the
+    /// login if it identifies a different user than the ID token from
+    /// the token endpoint.
+    ///
+    /// Providers return hybrid flow responses in the URL fragment (which
+    /// browsers do not send to the server) unless another response mode
+    /// is requested, and so the hybrid flow should be combined with
+    /// [`ResponseMode::FormPost`].
+    ///
+    /// Defaults to [`AuthenticationFlow::AuthorizationCode`]
+    ///
+    /// # Panics
+    ///
+    /// Panics if the flow does not return an authorization code (such as
+    /// the implicit flow), since the middleware always exchanges the code
+    /// for the access token.
+    pub fn with_authentication_flow(
+        mut self,
+        authentication_flow: AuthenticationFlow<CoreResponseType>,
+    ) -> Self {
+        let returns_code = match &authentication_flow {
+            AuthenticationFlow::AuthorizationCode => true,
+            AuthenticationFlow::Hybrid(response_types) => {
+                response_types.contains(&CoreResponseType::Code)
+            }
+            _ => false,
+        };
+        assert!(
+            returns_code,
+            "The authentication flow must return an authorization code."
+        );
+        self.authentication_flow = authentication_flow;
+        self
+    }
+
+    /// Sets whether or not the login route [pushes the authorization
+    /// request](https://www.rfc-editor.org/rfc/rfc9126) to the Identity
+    /// Provider before redirecting the browser, which some providers (and
+    /// security profiles such as FAPI) require.
+    ///
+    /// The middleware POSTs the authorization request parameters to the
+    /// provider's `pushed_authorization_request_endpoint` -- using the
+    /// same [client authentication](Self::with_auth_method) as for token
+    /// requests -- then redirects the browser to the authorization
+    /// endpoint with only the client id and the `request_uri` that was
+    /// returned by the provider. This keeps the parameters out of the
+    /// browser, and lets the provider authenticate the client before the
+    /// user signs in. Providers that do not advertise the endpoint in
+    /// their metadata receive the usual redirect.
+    ///
+    /// Defaults to `false`
+    pub fn with_par(mut self, par: bool) -> Self {
+        self.par = par;
+        self
+    }
+
+    /// Sets how the callback route responds once it has processed the
+    /// authorization response: with a redirect, or with a JSON
+    /// description of the login for single-page applications.
+    ///
+    /// Defaults to [`CallbackResponse::Redirect`]
+    pub fn with_callback_response(mut self, callback_response: CallbackResponse) -> Self {
+        self.callback_response = callback_response;
+        self
+    }
+
+    /// Sets the path where the browser will be sent after a successful
+    /// login sequence.
+    ///
+    /// The path is emitted verbatim (in the `Location` header, or in the
+    /// [JSON callback response](CallbackResponse::Json)), and so may
+    /// include a query string and a fragment -- for example,
+    /// `/#/dashboard` for single-page applications that use hash routing.
+    ///
+    /// Defaults to `/`
+    pub fn with_login_landing_path(mut self, login_landing_path: &str) -> Self {
+        self.login_landing_path = login_landing_path.to_string();
+        self
+    }
+
+    /// Sets the name of the login path's query parameter that carries
+    /// the path to which the browser will be sent after a successful
+    /// login (instead of the [login landing
+    /// path](Self::with_login_landing_path)). Linking to
+    /// `/login?return_to=/reports`, for example, returns the user to the
+    /// reports page once they have logged in (or straight away, if they
+    /// are already logged in).
+    ///
+    /// The value must be a path on this site (starting with a single
+    /// `/`); login requests with any other value -- such as the URL of
+    /// another site -- are rejected, so that the parameter cannot be used
+    /// as an open redirect.
+    ///
+    /// Defaults to `return_to`
+    pub fn with_return_param(mut self, return_param: &str) -> Self {
+        self.return_param = return_param.to_string();
+        self
+    }
+
+    /// Sets whether or not the middleware handles requests to the path
+    /// of the [redirect URL](Config::redirect_url) itself. Applications
+    /// that disable this option must complete the login from their own
+    /// route handler for that path, with
+    /// [`complete_login`](Self::complete_login).
+    ///
+    /// Defaults to `true`
+    pub fn with_intercept_callback(mut self, intercept_callback: bool) -> Self {
+        self.intercept_callback = intercept_callback;
+        self
+    }
+
+    /// Sets a one-time "flash" message that will be made available to
+    /// the first request after a successful login, usually in order to
+    /// display a "Welcome back" message. The message is removed from
+    /// the session as soon as it has been provided to a request; see
+    /// [`flash()`](crate::OpenIdConnectRequestExt::flash).
+    ///
+    /// Defaults to no message
+    pub fn with_login_flash(mut self, login_flash: &str) -> Self {
+        self.login_flash = Some(login_flash.to_string());
+        self
+    }
+
+    /// Sets a one-time message (such as "Please sign in to continue.")
+    /// that is queued up whenever an unauthenticated request is sent to
+    /// the login process by a route that [requires
+    /// authentication](crate::OpenIdConnectRouteExt::authenticated), or
+    /// because the session has gone idle. The message is made available
+    /// to the next request, usually the application's sign in page (see
+    /// [`with_unauthenticated_redirect_strategy`](Self::with_unauthenticated_redirect_strategy)),
+    /// and is then removed from the session; see
+    /// [`login_flash()`](crate::OpenIdConnectRequestExt::login_flash).
+    /// Completing the login also removes the message.
+    ///
+    /// Defaults to no message
+    pub fn with_login_required_message(mut self, login_required_message: &str) -> Self {
+        self.login_required_message = Some(login_required_message.to_string());
+        self
+    }
+
+    /// Sets the path to the "logout" route that will be intercepted by
+    /// the middleware in order to clear the sessions's authentication
+    /// state.
+    ///
+    /// Defaults to `/logout`
+    pub fn with_logout_path(mut self, logout_path: &str) -> Self {
+        self.logout_path = logout_path.to_string();
+        self
+    }
+
+    /// Sets the path to the [front-channel logout] route, which the
+    /// Identity Provider loads (usually in an iframe) when the user logs
+    /// out of another application that shares the same single sign-on
+    /// session. The route clears the authentication state of the
+    /// browser's session (or destroys the session, depending on
+    /// [`with_logout_destroys_session`](Self::with_logout_destroys_session))
+    /// if the `iss` and `sid` parameters sent by the Identity Provider
+    /// match the session.
+    ///
+    /// Note that browsers only send the session cookie with requests from
+    /// a cross-site iframe if the cookie's `SameSite` policy is `None`;
+    /// with the `Lax` policy that the middleware requires for the login
+    /// process, front-channel logout requests will not find the session.
+    ///
+    /// Defaults to none (front-channel logout is disabled)
+    ///
+    /// [front-channel logout]: https://openid.net/specs/openid-connect-frontchannel-1_0.html
+    pub fn with_frontchannel_logout_path(mut self, frontchannel_logout_path: &str) -> Self {
+        self.frontchannel_logout_path = Some(frontchannel_logout_path.to_string());
+        self
+    }
+
+    /// Sets the path to the [back-channel logout] route, to which the
+    /// Identity Provider `POST`s a signed logout token when the user
+    /// logs out of the provider's single sign-on session. The token's
+    /// signature and claims are verified, after which every session that
+    /// belongs to the logged-out provider session (`sid`) -- or, if the
+    /// token does not identify the provider session, to the logged-out
+    /// user (`sub`) -- is destroyed.
+    ///
+    /// The sessions are found through the index that the middleware
+    /// keeps in the [session store](Self::with_session_store), which
+    /// must therefore be configured; logout requests are rejected
+    /// otherwise.
+    ///
+    /// Defaults to none (back-channel logout is disabled)
+    ///
+    /// [back-channel logout]: https://openid.net/specs/openid-connect-backchannel-1_0.html
+    pub fn with_backchannel_logout_path(mut self, backchannel_logout_path: &str) -> Self {
+        self.backchannel_logout_path = Some(backchannel_logout_path.to_string());
         self
     }
 
@@ -226,142 +1928,3175 @@ impl OpenIdConnectMiddleware {
     /// a route that cleans up personally-identifying information after
     /// the logout completes).
     ///
+    /// Destroying the session also removes the session cookie. Tide's
+    /// session middleware always removes the cookie using the `/` path,
+    /// and browsers only delete cookies whose path matches, so
+    /// applications that configure a [different cookie
+    /// path](tide::sessions::SessionMiddleware::with_cookie_path) should
+    /// disable this option, which clears the authentication state but
+    /// leaves the session (and its cookie) in place.
+    ///
     /// Defaults to `true`
     pub fn with_logout_destroys_session(mut self, logout_destroys_session: bool) -> Self {
         self.logout_destroys_session = logout_destroys_session;
         self
     }
 
-    /// Sets the path where the browser will be sent after the logout
-    /// sequence.
-    ///
-    /// Defaults to `/`
-    pub fn with_logout_landing_path(mut self, logout_landing_path: &str) -> Self {
-        self.logout_landing_path = logout_landing_path.to_string();
-        self
+    /// Sets the path where the browser will be sent after the logout
+    /// sequence. As with the [login landing
+    /// path](Self::with_login_landing_path), the path is emitted
+    /// verbatim, including any query string and fragment.
+    ///
+    /// Defaults to `/`
+    pub fn with_logout_landing_path(mut self, logout_landing_path: &str) -> Self {
+        self.logout_landing_path = logout_landing_path.to_string();
+        self
+    }
+
+    /// Sets the path where the browser will be sent if a login is
+    /// rejected by the [email verification
+    /// requirement](Self::with_require_email_verified) or the [claims
+    /// validator](Self::with_claims_validator). The reason for the
+    /// rejection is available from the
+    /// [flash message](crate::OpenIdConnectRequestExt::flash) at that
+    /// path, which must be reachable without authentication.
+    ///
+    /// Defaults to the [logout landing path](Self::with_logout_landing_path)
+    pub fn with_login_rejected_path(mut self, login_rejected_path: &str) -> Self {
+        self.login_rejected_path = Some(login_rejected_path.to_string());
+        self
+    }
+
+    /// Sets the paths that are always public, such as health check or
+    /// metrics endpoints. A request matches a public path if its path is
+    /// the same as the public path, or if it is nested below the public
+    /// path (`/metrics` matches both `/metrics` and `/metrics/http`, but
+    /// not `/metrics-old`).
+    ///
+    /// Requests to public paths are passed straight on to the
+    /// application, without contacting the Identity Provider or looking
+    /// at the session, and are always treated as unauthenticated. Paths
+    /// that are protected with
+    /// [`authenticated()`](crate::OpenIdConnectRouteExt::authenticated)
+    /// will therefore reject those requests, so public paths should not
+    /// be protected.
+    ///
+    /// Defaults to none
+    pub fn with_public_paths(mut self, public_paths: &[impl AsRef<str>]) -> Self {
+        self.public_paths = public_paths
+            .iter()
+            .map(|path| normalize_path(path.as_ref()))
+            .collect();
+        self
+    }
+
+    /// Sets a flag indicating if the session cookie must contain only
+    /// the (signed) session id, with the session data -- and thus the
+    /// access token -- kept in a server-side session store.
+    ///
+    /// Session stores such as [`CookieStore`] serialize the entire
+    /// session into the cookie. Tide signs that cookie, so it cannot be
+    /// tampered with, but the contents are still readable by the
+    /// browser (and anything else with access to the cookie jar).
+    ///
+    /// The middleware only knows the session store if the application
+    /// passes it to [`with_session_store`](Self::with_session_store), in
+    /// which case the flag is enforced there. Applications that do not
+    /// need to should check the store of Tide's
+    /// [`SessionMiddleware`](tide::sessions::SessionMiddleware) with
+    /// [`validate_session_store`](Self::validate_session_store) instead.
+    /// Only [`CookieStore`] itself is recognized, not stores that wrap
+    /// it.
+    ///
+    /// Defaults to `true`
+    ///
+    /// # Panics
+    ///
+    /// Panics if the flag is set while the [session
+    /// store](Self::with_session_store) is a [`CookieStore`].
+    pub fn with_require_signed_session(mut self, require_signed_session: bool) -> Self {
+        self.require_signed_session = require_signed_session;
+        self.assert_signed_session_store();
+        self
+    }
+
+    /// Gives the middleware access to the session store, which must be
+    /// the same store that is passed to Tide's
+    /// [`SessionMiddleware`](tide::sessions::SessionMiddleware). The
+    /// middleware then indexes each session by its user when the login
+    /// completes, which allows it to [revoke a user's
+    /// sessions](Self::revoke_user_sessions) by destroying them in the
+    /// store.
+    ///
+    /// The index is kept in the store as well (in records that do not
+    /// belong to any session cookie), and so it is shared by every
+    /// server instance that uses the same store. Logins and logouts
+    /// write to those records in addition to the session itself, and
+    /// logins save the session to the store ahead of the session
+    /// middleware, so that a failing store fails the callback request
+    /// with [`OpenIdConnectError::SessionUnavailable`].
+    ///
+    /// A [`CookieStore`] keeps the sessions in the browsers, out of the
+    /// reach of the middleware. Such a store is rejected unless [signed
+    /// sessions are no longer required](Self::with_require_signed_session),
+    /// and even then sessions cannot be revoked.
+    ///
+    /// Defaults to none (sessions cannot be revoked)
+    ///
+    /// # Panics
+    ///
+    /// Panics if the store is a [`CookieStore`] and signed sessions are
+    /// required.
+    pub fn with_session_store<Store>(mut self, store: Store) -> Self
+    where
+        Store: SessionStore,
+    {
+        let store_type = TypeId::of::<Store>();
+        self.session_store = Some((store_type, std::any::type_name::<Store>()));
+        self.session_index = if store_type == TypeId::of::<CookieStore>() {
+            None
+        } else {
+            Some(SessionIndex::new(store))
+        };
+        self.assert_signed_session_store();
+        self
+    }
+
+    /// Sets the key used to encrypt the access token and refresh token
+    /// before they are stored in the session, which protects the tokens
+    /// at rest in the session store (and, with a [`CookieStore`], from
+    /// anything that can read the session cookie). The tokens are
+    /// encrypted with AES-256-GCM, and are decrypted transparently when
+    /// the session is read.
+    ///
+    /// The key must be kept secret, and should be generated randomly
+    /// (and separately from the session middleware's signing secret).
+    /// Sessions whose tokens cannot be decrypted -- because the key has
+    /// changed, for example -- are treated as unauthenticated.
+    ///
+    /// Defaults to none (tokens are stored unencrypted)
+    pub fn with_token_encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.token_encryption_key = Some(TokenEncryptionKey::new(key));
+        self
+    }
+
+    /// Confirms that the given session store is compatible with the
+    /// middleware's [session store
+    /// requirement](Self::with_require_signed_session); call this
+    /// with the same store that is passed to Tide's
+    /// [`SessionMiddleware`](tide::sessions::SessionMiddleware).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError::UnsignedSessionStore`] if the store keeps
+    /// plain session data in the session cookie and the middleware has
+    /// not been configured to
+    /// [allow that](Self::with_require_signed_session).
+    pub fn validate_session_store<Store>(&self, _store: &Store) -> Result<(), ConfigError>
+    where
+        Store: SessionStore + 'static,
+    {
+        self.check_signed_session_store(TypeId::of::<Store>(), std::any::type_name::<Store>())
+    }
+
+    /// Returns `true` if the session with the given cookie value is still
+    /// authenticated by this middleware, for code that acts on behalf of
+    /// a user outside of a request (such as a background job). `store`
+    /// must be the same store that is passed to Tide's
+    /// [`SessionMiddleware`](tide::sessions::SessionMiddleware), and
+    /// `cookie_value` is the (unsigned) value under which that store
+    /// keeps the session.
+    ///
+    /// A session is no longer active once it has been destroyed
+    /// (including by a [revocation](Self::revoke_user_sessions) or a
+    /// [back-channel logout](Self::with_backchannel_logout_path)) or has
+    /// expired, has been logged out, or is no longer valid in the same
+    /// way as during a request: it has exceeded the [idle
+    /// timeout](Self::with_idle_timeout), its access token has expired
+    /// under [strict authentication](Self::with_strict_authentication)
+    /// (even if the next request could refresh it), or it was not [bound
+    /// to its client](Self::with_session_binding) under the current
+    /// binding. Errors from the store are treated as an inactive
+    /// session.
+    pub async fn is_subject_session_active<Store>(&self, store: &Store, cookie_value: &str) -> bool
+    where
+        Store: SessionStore,
+    {
+        match store.load_session(cookie_value.to_string()).await {
+            Ok(Some(session)) => self.check_session(&session, None).is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Logs the user with the given [user
+    /// id](crate::AuthenticatedUser::user_id) out of every session that
+    /// they have logged in to, by destroying those sessions in the
+    /// [session store](Self::with_session_store), which allows
+    /// applications to offer a "log out everywhere" feature. The
+    /// browsers keep their session cookies, but the cookies no longer
+    /// refer to a session, and so the next request with each of them is
+    /// unauthenticated.
+    ///
+    /// A request that is in progress for one of the sessions (including
+    /// the current request, if it belongs to the user) still holds a
+    /// copy of its session, which Tide's session middleware writes back
+    /// to the store once the response has been generated. Handlers that
+    /// revoke the sessions of the current user should therefore also
+    /// [destroy](tide::sessions::Session::destroy) the current session.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OpenIdConnectError::SessionUnavailable`] if no session
+    /// store has been configured, or if the store returned an error.
+    pub async fn revoke_user_sessions(&self, user_id: &str) -> Result<(), OpenIdConnectError> {
+        let session_index = self.session_index.as_ref().ok_or_else(|| {
+            OpenIdConnectError::SessionUnavailable(match self.session_store {
+                Some((_, store_type)) => format!("sessions in `{}` cannot be revoked", store_type),
+                None => "no session store has been configured".to_string(),
+            })
+        })?;
+        let revoked = session_index
+            .destroy_sessions(
+                &self.session_index_scope(),
+                &IndexKey::User(user_id.to_string()),
+            )
+            .await
+            .map_err(OpenIdConnectError::SessionUnavailable)?;
+        crate::log::event!(debug, "Revoked user sessions.", { sessions: revoked });
+        Ok(())
+    }
+
+    /// Returns the URL of the Identity Provider that the middleware
+    /// authenticates against.
+    pub fn issuer_url(&self) -> &IssuerUrl {
+        &self.issuer_url
+    }
+
+    /// Returns the client id with which the middleware identifies itself
+    /// to the Identity Provider.
+    pub fn client_id(&self) -> &ClientId {
+        &self.client_id
+    }
+
+    /// Returns the client secret with which the middleware authenticates
+    /// itself to the Identity Provider, or `None` for [public
+    /// clients](Self::new_public).
+    pub fn client_secret(&self) -> Option<&ClientSecret> {
+        self.client_secret.as_ref()
+    }
+
+    /// Confirms that the Identity Provider is reachable by re-fetching
+    /// its JSON Web Key Set (after first discovering the provider, if
+    /// the middleware was created with [`lazy`](Self::lazy) and
+    /// discovery has not yet succeeded), which allows the application
+    /// to report the provider's health from a readiness route.
+    ///
+    /// The re-fetched keys replace the cached keys, and so a successful
+    /// health check also refreshes the keys that are used to verify ID
+    /// tokens.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OpenIdConnectError::ProviderTimeout`] if the provider
+    /// did not respond within the [HTTP timeout](Self::with_http_timeout),
+    /// or [`OpenIdConnectError::ProviderUnavailable`] if the provider's
+    /// metadata or key set could not be retrieved.
+    pub async fn health_check(&self) -> Result<(), OpenIdConnectError> {
+        let discovered = self
+            .discovered_provider()
+            .await
+            .map_err(|error| OpenIdConnectError::ProviderUnavailable(error.to_string()))?;
+
+        let started = Instant::now();
+        let refreshed = discovered
+            .jwks
+            .refresh(self.http_timeout, self.clock.now())
+            .await;
+        self.record_metric(MetricEvent::JwksRefresh {
+            duration: started.elapsed(),
+        });
+        refreshed.map_err(|error| match error {
+            DiscoveryError::Request(error) if error.is_timeout() => {
+                OpenIdConnectError::ProviderTimeout
+            }
+            error => OpenIdConnectError::ProviderUnavailable(error.to_string()),
+        })
+    }
+
+    /// Completes the login from the application's own route handler for
+    /// the path of the [redirect URL](Config::redirect_url), for
+    /// applications that [do not let the middleware intercept the
+    /// callback](Self::with_intercept_callback) because they want full
+    /// control over the response.
+    ///
+    /// This performs the same steps as the middleware's own callback
+    /// handling -- CSRF state and nonce verification, the code exchange,
+    /// and populating the session -- and returns the user that logged
+    /// in. The [login hooks](Self::on_login) are called as usual.
+    /// The middleware must still be installed (for the login path and
+    /// for the authentication of subsequent requests), and so the
+    /// application needs to share it between the server and the handler,
+    /// for example by keeping it in an `Arc` that a small forwarding
+    /// middleware installs.
+    ///
+    /// # Errors
+    ///
+    /// Returns the [`OpenIdConnectError`] that caused the login to fail;
+    /// replayed callback requests fail with
+    /// [`OpenIdConnectError::ReplayedCallback`].
+    pub async fn complete_login<State>(
+        &self,
+        req: &mut Request<State>,
+    ) -> Result<AuthenticatedUser, OpenIdConnectError>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let discovered = self
+            .discovered_provider()
+            .await
+            .map_err(|error| OpenIdConnectError::ProviderUnavailable(error.to_string()))?;
+
+        let started = Instant::now();
+        let login = match self.verify_callback(&discovered, req).await {
+            Ok((claims, session_state, _)) => {
+                let MiddlewareSessionState::PostAuth {
+                    subject,
+                    user_id,
+                    issuer,
+                    provider,
+                    user_info,
+                    ..
+                } = &session_state;
+                let user = AuthenticatedUser::new(
+                    subject.to_string(),
+                    user_id.clone(),
+                    issuer
+                        .as_ref()
+                        .unwrap_or(&discovered.issuer_url)
+                        .to_string(),
+                    provider.clone(),
+                    user_info,
+                );
+                self.store_login(req, session_state)
+                    .await
+                    .map(|()| (claims, user))
+            }
+            Err(error) => Err(error),
+        };
+        self.record_metric(MetricEvent::Callback {
+            duration: started.elapsed(),
+            success: login.is_ok(),
+        });
+
+        match login {
+            Ok((claims, user)) => {
+                if let Some(on_login) = &self.on_login {
+                    on_login(&claims);
+                }
+                Ok(user)
+            }
+            Err(OpenIdConnectError::ReplayedCallback) => Err(OpenIdConnectError::ReplayedCallback),
+            Err(error) => {
+                if let Some(on_login_failure) = &self.on_login_failure {
+                    on_login_failure(&error);
+                }
+                if let OpenIdConnectError::ClaimsRejected(_) = error {
+                    req.session_mut().remove(&self.session_namespace);
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Sets the time limits (and clock skew leeway) that are applied to
+    /// the login flow.
+    ///
+    /// Defaults to [`TimingPolicy::default()`]
+    pub fn with_timing_policy(mut self, timing_policy: TimingPolicy) -> Self {
+        self.timing_policy = timing_policy;
+        self
+    }
+
+    /// Restricts the algorithms that are accepted in ID token signatures
+    /// to the given list, which protects against algorithm confusion
+    /// attacks. Only algorithms that are also advertised by the Identity
+    /// Provider (in its `id_token_signing_alg_values_supported` metadata)
+    /// are accepted, and unsigned (`none`) tokens are always rejected.
+    ///
+    /// Defaults to all of the algorithms advertised by the provider
+    pub fn with_allowed_algorithms(mut self, algorithms: &[CoreJwsSigningAlgorithm]) -> Self {
+        self.allowed_algorithms = Some(algorithms.to_vec());
+        self
+    }
+
+    /// Sets whether or not ID tokens must contain the `nonce` claim. See
+    /// [`NonceMode::Optional`] for the security trade-off of accepting
+    /// ID tokens without a nonce.
+    ///
+    /// Defaults to [`NonceMode::Required`]
+    pub fn with_nonce_verification(mut self, nonce_mode: NonceMode) -> Self {
+        self.nonce_mode = nonce_mode;
+        self
+    }
+
+    /// Sets whether or not the access token hash (`at_hash`) claim of
+    /// the ID token is verified against the access token, which binds
+    /// the access token to the (signed) ID token. Logins are rejected if
+    /// the hash does not match the access token.
+    ///
+    /// The `at_hash` claim is optional in the authorization code flow,
+    /// and not all providers include it; ID tokens without the claim are
+    /// accepted.
+    ///
+    /// Defaults to `false`
+    pub fn with_verify_at_hash(mut self, verify_at_hash: bool) -> Self {
+        self.verify_at_hash = verify_at_hash;
+        self
+    }
+
+    /// **Dangerous:** sets whether or not the signature of the ID token
+    /// that the token endpoint returns during the login is left
+    /// unverified. The token's issuer, audience, expiration, and nonce
+    /// are still verified.
+    ///
+    /// This is only safe if the connection to the token endpoint is
+    /// itself authenticated (with mutual TLS on a tightly-controlled
+    /// network, for example), so that the ID token cannot have come from
+    /// anywhere but the Identity Provider. Otherwise, anyone who can
+    /// tamper with the token response can log in as any user. Tokens
+    /// that arrive through the browser or from API clients -- [bearer
+    /// tokens](Self::with_bearer_token_auth) and the front-channel ID
+    /// token of the [hybrid flow](Self::with_authentication_flow) -- are
+    /// always verified.
+    ///
+    /// A warning is logged whenever this is enabled. Unlike most
+    /// settings, this cannot be enabled through the
+    /// [`OpenIdConnectConfig`].
+    ///
+    /// Defaults to `false`
+    pub fn with_insecure_skip_signature_verification(
+        mut self,
+        insecure_skip_signature_verification: bool,
+    ) -> Self {
+        if insecure_skip_signature_verification {
+            crate::log::event!(
+                warn,
+                "INSECURE: OpenID Connect ID token signature verification is disabled; only do this if the token endpoint is reached over an authenticated connection."
+            );
+        }
+        self.insecure_skip_signature_verification = insecure_skip_signature_verification;
+        self
+    }
+
+    /// Sets the private key used to decrypt encrypted (JWE) ID tokens,
+    /// for Identity Providers that have been configured to encrypt the
+    /// ID tokens that they issue to this client. Encrypted ID tokens
+    /// are decrypted before their signature and claims are verified;
+    /// unencrypted ID tokens continue to be accepted.
+    ///
+    /// Defaults to none (encrypted ID tokens are rejected)
+    pub fn with_id_token_decryption_key(mut self, key: IdTokenDecryptionKey) -> Self {
+        self.id_token_decryption_key = Some(key);
+        self
+    }
+
+    /// Sets the method that this client uses to authenticate itself to
+    /// the Identity Provider's token endpoint, which must match the
+    /// method that the provider expects from this client:
+    ///
+    /// - [`ClientSecretBasic`](CoreClientAuthMethod::ClientSecretBasic)
+    ///   sends the client secret in the `Authorization` header.
+    /// - [`ClientSecretPost`](CoreClientAuthMethod::ClientSecretPost)
+    ///   sends the client secret in the request body.
+    /// - [`PrivateKeyJwt`](CoreClientAuthMethod::PrivateKeyJwt) sends a
+    ///   client assertion signed with the [client assertion
+    ///   key](Self::with_client_assertion_key) instead of the client
+    ///   secret.
+    ///
+    /// Public clients (created with [`new_public`](Self::new_public))
+    /// do not have a client secret, and so only send their client id.
+    ///
+    /// Defaults to [`ClientSecretBasic`](CoreClientAuthMethod::ClientSecretBasic)
+    ///
+    /// # Panics
+    ///
+    /// Panics if the authentication method is not one of the methods
+    /// listed above.
+    pub fn with_auth_method(mut self, auth_method: CoreClientAuthMethod) -> Self {
+        assert!(
+            matches!(
+                auth_method,
+                CoreClientAuthMethod::ClientSecretBasic
+                    | CoreClientAuthMethod::ClientSecretPost
+                    | CoreClientAuthMethod::PrivateKeyJwt
+            ),
+            "Client authentication method `{}` is not supported.",
+            serde_json::to_value(&auth_method)
+                .ok()
+                .and_then(|value| value.as_str().map(str::to_owned))
+                .unwrap_or_default()
+        );
+        self.auth_method = auth_method;
+        self
+    }
+
+    /// Sets the private key used to sign the client assertions that are
+    /// sent to the token endpoint when the [client authentication
+    /// method](Self::with_auth_method) is
+    /// [`PrivateKeyJwt`](CoreClientAuthMethod::PrivateKeyJwt); token
+    /// requests fail if that method is used without a key.
+    ///
+    /// Defaults to none
+    pub fn with_client_assertion_key(mut self, key: ClientAssertionKey) -> Self {
+        self.client_assertion_key = Some(key);
+        self
+    }
+
+    /// Sets the maximum age of the cached JSON Web Key Set (JWKS) that
+    /// is used to verify ID token signatures. The key set is re-fetched
+    /// from the Identity Provider's `jwks_uri` -- during the next login
+    /// -- once the cached copy is older than this interval, which allows
+    /// long-running servers to pick up rotated signing keys.
+    ///
+    /// Independently of this interval, the key set is re-fetched (at most
+    /// once a minute) when an ID token is signed with a key that is not
+    /// in the cached copy.
+    ///
+    /// Defaults to 1 hour
+    pub fn with_jwks_refresh_interval(mut self, jwks_refresh_interval: Duration) -> Self {
+        self.jwks_refresh_interval = jwks_refresh_interval;
+        self
+    }
+
+    /// Sets the maximum amount of time to wait for the Identity Provider
+    /// to respond to the requests made while handling logins: the token
+    /// exchange (and refresh), the user info request, and the JWKS
+    /// refresh. A callback request whose token exchange or user info
+    /// request times out fails with a `502 Bad Gateway` response.
+    ///
+    /// The discovery requests made by [`new`](Self::new) always use the
+    /// default timeout, since they happen before this setting can be
+    /// applied.
+    ///
+    /// Defaults to 30 seconds
+    pub fn with_http_timeout(mut self, http_timeout: Duration) -> Self {
+        self.http_timeout = http_timeout;
+        self
+    }
+
+    /// Sets the number of times that a failed exchange of the
+    /// authorization code is retried, which allows logins to ride out
+    /// brief provider outages (during a deploy, for example). Only
+    /// server errors (`5xx` responses) and connection failures are
+    /// retried; client errors (`4xx` responses) and
+    /// [timeouts](Self::with_http_timeout) fail the login immediately.
+    /// The middleware waits 100 milliseconds before the first retry, and
+    /// doubles the wait (up to one second) before every subsequent
+    /// retry. The number of retries is limited to 5, since the browser's
+    /// callback request is held open while retrying.
+    ///
+    /// Defaults to 2
+    pub fn with_token_exchange_retries(mut self, token_exchange_retries: u32) -> Self {
+        self.token_exchange_retries = token_exchange_retries.min(MAX_TOKEN_EXCHANGE_RETRIES);
+        self
+    }
+
+    /// Sets the maximum amount of time that may pass between two
+    /// requests in an authenticated session. Once a session has been
+    /// idle for longer than this, its authentication state is cleared
+    /// and the browser is redirected to the login path, regardless of
+    /// whether or not the access token has expired.
+    ///
+    /// Defaults to none (sessions never go idle)
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Binds authenticated sessions to a coarse fingerprint of the
+    /// client, which makes a stolen session cookie less useful. Requests
+    /// whose fingerprint differs from the one recorded at login have
+    /// their authentication state cleared and are redirected to the
+    /// login path. Sessions that were authenticated before the binding
+    /// was enabled (or changed) must log in again as well.
+    ///
+    /// Defaults to [`SessionBinding::None`]
+    pub fn with_session_binding(mut self, session_binding: SessionBinding) -> Self {
+        self.session_binding = session_binding;
+        self
+    }
+
+    /// Sets the maximum age of the user info claims that are stored in
+    /// the session. Once the claims are older than this (measured from
+    /// the login, or from the previous refresh), the middleware
+    /// re-fetches the user info from the Identity Provider before the
+    /// request is processed, so that long-lived sessions pick up changes
+    /// to claims such as the user's roles or groups.
+    ///
+    /// If the user info cannot be retrieved, then the session keeps the
+    /// old claims, and the refresh is retried on the next request.
+    ///
+    /// Defaults to none (the claims are only retrieved during the login)
+    pub fn with_claims_refresh_interval(mut self, claims_refresh_interval: Duration) -> Self {
+        self.claims_refresh_interval = Some(claims_refresh_interval);
+        self
+    }
+
+    /// Sets whether or not requests whose access token has expired are
+    /// treated as unauthenticated.
+    ///
+    /// Expired access tokens are refreshed automatically if the Identity
+    /// Provider issued a refresh token. Without strict authentication, a
+    /// session whose access token has expired (and could not be
+    /// refreshed) remains authenticated until the session itself
+    /// expires; with strict authentication, the request is treated as
+    /// unauthenticated, and
+    /// [`is_authenticated`](crate::OpenIdConnectRequestExt::is_authenticated)
+    /// returns `false`.
+    ///
+    /// Defaults to `false`
+    pub fn with_strict_authentication(mut self, strict_authentication: bool) -> Self {
+        self.strict_authentication = strict_authentication;
+        self
+    }
+
+    /// Sets whether or not requests can authenticate with an
+    /// `Authorization: Bearer <jwt>` header instead of a session, which
+    /// allows services that cannot go through the interactive login
+    /// process to call the application with a pre-issued token.
+    ///
+    /// The token must be a [JWT access
+    /// token](https://www.rfc-editor.org/rfc/rfc9068) (with a `typ` of
+    /// `at+jwt`) that is signed with one of the Identity Provider's keys,
+    /// and that was issued by the provider for the [bearer
+    /// audience](Self::with_bearer_audience). ID tokens are rejected,
+    /// even if they were issued for this client. Requests with a valid
+    /// token are authenticated without touching the session, using the
+    /// token's claims as the user info; requests with an invalid token
+    /// are rejected with a `401 Unauthorized` response, instead of being
+    /// sent through the login process. Requests without a bearer token
+    /// are authenticated by their session, as usual.
+    ///
+    /// Defaults to `false`
+    pub fn with_bearer_token_auth(mut self, bearer_token_auth: bool) -> Self {
+        self.bearer_token_auth = bearer_token_auth;
+        self
+    }
+
+    /// Sets the audience (`aud` claim) that [bearer
+    /// tokens](Self::with_bearer_token_auth) must have been issued for,
+    /// which is usually the identifier of the application's API at the
+    /// Identity Provider.
+    ///
+    /// Defaults to the client ID
+    pub fn with_bearer_audience(mut self, bearer_audience: &str) -> Self {
+        self.bearer_audience = Some(bearer_audience.to_string());
+        self
+    }
+
+    /// Sets whether or not the middleware trusts the `X-Forwarded-Proto`
+    /// header, which a TLS-terminating reverse proxy uses to report the
+    /// scheme of the client's connection, when deciding if a request
+    /// arrived over https. Only enable this behind a proxy that
+    /// overwrites the header on every request.
+    ///
+    /// The session cookie is created by Tide's session middleware (which
+    /// runs before this middleware), and is only marked `Secure` if the
+    /// request URL already uses https by then. Trusting the proxy
+    /// therefore also requires installing
+    /// [`ForwardedProtoMiddleware`](crate::ForwardedProtoMiddleware)
+    /// before the session middleware: requests that the proxy received
+    /// over https, but whose URL still uses http, are rejected with a
+    /// `500 Internal Server Error` response instead of being given an
+    /// insecure session cookie.
+    ///
+    /// Defaults to `false`
+    pub fn with_trusted_proxy(mut self, trusted_proxy: bool) -> Self {
+        self.trusted_proxy = trusted_proxy;
+        self
+    }
+
+    /// Sets whether or not unauthenticated [HTMX](https://htmx.org/)
+    /// requests (requests with an `HX-Request` header) are sent to the
+    /// login path with an `HX-Redirect` header and a `200 OK` status
+    /// instead of a `302 Found` redirect. HTMX follows redirects within
+    /// the XHR request, which would swap the Identity Provider's login
+    /// page into the current page instead of navigating to it.
+    ///
+    /// This only applies where the middleware would otherwise redirect
+    /// the request to the login path.
+    ///
+    /// Defaults to `false`
+    pub fn with_htmx_support(mut self, htmx_support: bool) -> Self {
+        self.htmx_support = htmx_support;
+        self
+    }
+
+    /// Sets whether or not the [user id](crate::AuthenticatedUser::user_id)
+    /// of authenticated requests is recorded in the `user_id` field of
+    /// the current `tracing` span, which ties the request's traces to the
+    /// user. Nothing is recorded for unauthenticated requests.
+    ///
+    /// The span must declare the field (as in
+    /// `tracing::info_span!("request", user_id = tracing::field::Empty)`),
+    /// since `tracing` ignores values for undeclared fields. This setting
+    /// has no effect unless the `tracing` feature is enabled.
+    ///
+    /// Defaults to `false`
+    pub fn with_trace_user(mut self, trace_user: bool) -> Self {
+        self.trace_user = trace_user;
+        self
+    }
+
+    /// Sets the source of the current time used by the middleware.
+    ///
+    /// Defaults to [`SystemClock`](crate::clock::SystemClock)
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets the audiences that are trusted in addition to our client id.
+    ///
+    /// ID tokens must always include our client id in their audience
+    /// (`aud` claim), but some Identity Providers also include other
+    /// audiences, such as the API for which the access token was issued.
+    /// ID tokens with any audience other than our client id and these
+    /// additional audiences are rejected.
+    ///
+    /// Defaults to none
+    pub fn with_additional_audiences(mut self, additional_audiences: &[impl AsRef<str>]) -> Self {
+        self.additional_audiences = additional_audiences
+            .iter()
+            .map(|s| s.as_ref().to_owned())
+            .collect();
+        self
+    }
+
+    /// Sets a flag indicating if logins are only accepted from users
+    /// whose `email_verified` claim is `true`. The claim is taken from
+    /// the user info (or, if the user info does not include it, from the
+    /// ID token), and users without the claim are rejected.
+    ///
+    /// Rejected logins are handled in the same way as logins that are
+    /// rejected by the [claims validator](Self::with_claims_validator).
+    ///
+    /// Defaults to `false`
+    pub fn with_require_email_verified(mut self, require_email_verified: bool) -> Self {
+        self.require_email_verified = require_email_verified;
+        self
+    }
+
+    /// Sets a flag indicating if logins are only accepted when the
+    /// Identity Provider granted all of the [requested
+    /// scopes](Self::with_scopes) (including `offline_access`, if
+    /// [offline access](Self::with_offline_access) is enabled). By
+    /// default, the login succeeds with whatever scopes were granted,
+    /// which are then available from
+    /// [`scopes`](crate::OpenIdConnectRequestExt::scopes).
+    ///
+    /// Rejected logins are handled in the same way as logins that are
+    /// rejected by the [claims validator](Self::with_claims_validator).
+    ///
+    /// Defaults to `false`
+    pub fn with_require_requested_scopes(mut self, require_requested_scopes: bool) -> Self {
+        self.require_requested_scopes = require_requested_scopes;
+        self
+    }
+
+    /// Sets a function that applies application-specific rules to the
+    /// (already verified) ID token claims of every login -- for example,
+    /// requiring that the user belongs to the expected tenant.
+    ///
+    /// If the validator returns an error, then the login is rejected:
+    /// the request is not authenticated, the error message is queued up
+    /// as the [flash message](crate::OpenIdConnectRequestExt::flash),
+    /// and the browser is redirected to the [login rejected
+    /// path](Self::with_login_rejected_path) (which, unlike the login
+    /// landing path, must be reachable without authentication).
+    ///
+    /// Defaults to none
+    pub fn with_claims_validator<F>(mut self, claims_validator: F) -> Self
+    where
+        F: Fn(&CoreIdTokenClaims) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.claims_validator = Some(Arc::new(claims_validator));
+        self
+    }
+
+    /// Sets a function that derives additional claims from the (already
+    /// verified) ID token claims of every login -- for example, the
+    /// user's roles in the application, looked up from the application's
+    /// own data.
+    ///
+    /// The additional claims are stored in the session along with the
+    /// user's other claims (taking precedence over claims with the same
+    /// name from the ID token or user info), and are available to
+    /// handlers through [`claims`](crate::OpenIdConnectRequestExt::claims)
+    /// and [`is_authorized_for`](crate::OpenIdConnectRequestExt::is_authorized_for).
+    /// The function is also applied to the claims of [bearer
+    /// tokens](Self::with_bearer_token_auth), on every request.
+    ///
+    /// The function is called as part of processing the callback request,
+    /// and so should return quickly.
+    ///
+    /// Defaults to none
+    pub fn with_claim_enricher<F>(mut self, claim_enricher: F) -> Self
+    where
+        F: Fn(&CoreIdTokenClaims) -> HashMap<String, serde_json::Value> + Send + Sync + 'static,
+    {
+        self.claim_enricher = Some(Arc::new(claim_enricher));
+        self
+    }
+
+    /// Sets a function that rewrites the identity provider's [logout
+    /// URL](Config::idp_logout_url) before the browser is
+    /// redirected to it, which allows applications to adapt to providers
+    /// whose logout endpoint expects non-standard parameters (Auth0's
+    /// `returnTo` and `client_id`, for example).
+    ///
+    /// The function receives the logout URL with the standard
+    /// parameters (the `id_token_hint`, if the session has an ID token)
+    /// already added, and returns the URL to redirect to.
+    ///
+    /// Defaults to none
+    pub fn with_logout_url_fn<F>(mut self, logout_url_fn: F) -> Self
+    where
+        F: Fn(Url) -> Url + Send + Sync + 'static,
+    {
+        self.logout_url_fn = Some(Arc::new(logout_url_fn));
+        self
+    }
+
+    /// Sets a function that is called with the ID token claims after
+    /// every successful login, for example in order to write an audit
+    /// log entry or to increment a metric.
+    ///
+    /// The hook is called as part of processing the callback request,
+    /// and so should return quickly; spawn a task for anything that may
+    /// block.
+    ///
+    /// Defaults to none
+    pub fn on_login<F>(mut self, on_login: F) -> Self
+    where
+        F: Fn(&CoreIdTokenClaims) + Send + Sync + 'static,
+    {
+        self.on_login = Some(Arc::new(on_login));
+        self
+    }
+
+    /// Sets a function that is called with the reason for the failure
+    /// after every failed login (including logins rejected by the
+    /// [claims validator](Self::with_claims_validator)).
+    ///
+    /// The hook is called as part of processing the callback request,
+    /// and so should return quickly; spawn a task for anything that may
+    /// block.
+    ///
+    /// Defaults to none
+    pub fn on_login_failure<F>(mut self, on_login_failure: F) -> Self
+    where
+        F: Fn(&OpenIdConnectError) + Send + Sync + 'static,
+    {
+        self.on_login_failure = Some(Arc::new(on_login_failure));
+        self
+    }
+
+    /// Sets a function that renders the response for a failed login
+    /// (such as a CSRF mismatch in the callback, or an Identity Provider
+    /// that cannot be reached), which allows the application to show a
+    /// branded error page or to return a JSON error body.
+    ///
+    /// The error is still attached to the rendered response, and so can
+    /// be retrieved with [`Response::downcast_error`] by outer
+    /// middleware. Logins that are rejected by the [claims
+    /// validator](Self::with_claims_validator) are not errors, and are
+    /// redirected to the login rejected path instead.
+    ///
+    /// Defaults to none, in which case the middleware returns a plain
+    /// status response
+    pub fn with_error_renderer<F>(mut self, error_renderer: F) -> Self
+    where
+        F: Fn(&OpenIdConnectError) -> Response + Send + Sync + 'static,
+    {
+        self.error_renderer = Some(Arc::new(error_renderer));
+        self
+    }
+
+    /// Sets a function that receives timing measurements of the login
+    /// process (such as the duration of the token exchange), which
+    /// allows the application to report those measurements to the
+    /// metrics library of its choice.
+    ///
+    /// The hook is called inline with request processing, and so should
+    /// return quickly.
+    ///
+    /// Defaults to none
+    pub fn with_metrics<F>(mut self, metrics: F) -> Self
+    where
+        F: Fn(MetricEvent) + Send + Sync + 'static,
+    {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Sets the function that generates the random values used as the
+    /// `state` and `nonce` of each login, which allows applications with
+    /// specific compliance requirements to supply their own entropy
+    /// source (a FIPS-validated random number generator, for example).
+    ///
+    /// The function is called once for the state and once for the nonce
+    /// of every login, and must return a new, unguessable, URL-safe value
+    /// on every call; base64url-encoding 32 bytes of randomness matches
+    /// the built-in generator.
+    ///
+    /// Defaults to the built-in random generator
+    pub fn with_random_source<F>(mut self, random_source: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.random_source = Some(Arc::new(random_source));
+        self
+    }
+
+    /// Sets the trait used to generate redirect responses to
+    /// unauthenticated requests.
+    ///
+    /// Defaults to [`HttpRedirect`](crate::redirect_strategy::HttpRedirect)
+    pub fn with_unauthenticated_redirect_strategy<R>(mut self, redirect_strategy: R) -> Self
+    where
+        R: RedirectStrategy + 'static,
+    {
+        self.redirect_strategy = Arc::new(redirect_strategy);
+        self
+    }
+
+    /// Sets how the middleware responds to requests for the login path
+    /// from a session that is already authenticated (by this
+    /// middleware's [provider](Self::with_provider)).
+    ///
+    /// Defaults to [`ReloginBehavior::SkipIfAuthenticated`]
+    pub fn with_relogin_behavior(mut self, relogin_behavior: ReloginBehavior) -> Self {
+        self.relogin_behavior = relogin_behavior;
+        self
+    }
+
+    /// Sets how the middleware responds to unauthenticated requests for
+    /// routes that require
+    /// [authentication](crate::OpenIdConnectRouteExt::authenticated).
+    ///
+    /// Defaults to [`UnauthenticatedBehavior::Redirect`]
+    pub fn with_unauthenticated_response(
+        mut self,
+        unauthenticated_behavior: UnauthenticatedBehavior,
+    ) -> Self {
+        self.unauthenticated_behavior = unauthenticated_behavior;
+        self
+    }
+
+    /// Returns the strategy used to respond to unauthenticated requests,
+    /// according to the configured [`UnauthenticatedBehavior`].
+    fn unauthenticated_strategy<State>(&self, req: &Request<State>) -> Arc<dyn RedirectStrategy>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        match self.unauthenticated_behavior {
+            UnauthenticatedBehavior::Negotiate if is_api_request(req) => {
+                Arc::new(UnauthorizedResponse)
+            }
+            UnauthenticatedBehavior::Redirect | UnauthenticatedBehavior::Negotiate
+                if self.htmx_support && req.header(HX_REQUEST).is_some() =>
+            {
+                Arc::new(HtmxRedirect(self.login_path.clone()))
+            }
+            UnauthenticatedBehavior::Redirect | UnauthenticatedBehavior::Negotiate => {
+                self.redirect_strategy.clone()
+            }
+            UnauthenticatedBehavior::Unauthorized => Arc::new(UnauthorizedResponse),
+        }
+    }
+
+    /// Returns the user info claims that should be stored in the session,
+    /// keeping only the [session claims](Self::with_session_claims) (if
+    /// any were configured).
+    fn project_session_claims(
+        &self,
+        claims: &StandardClaims<CoreGenderClaim>,
+    ) -> StandardClaims<CoreGenderClaim> {
+        let session_claims = match &self.session_claims {
+            Some(session_claims) => session_claims,
+            None => return claims.clone(),
+        };
+
+        let projected = match serde_json::to_value(claims) {
+            Ok(serde_json::Value::Object(mut fields)) => {
+                fields.retain(|name, _| {
+                    let base_name = name.split('#').next().unwrap_or_default();
+                    base_name == "sub" || session_claims.iter().any(|claim| claim == base_name)
+                });
+                serde_json::from_value(serde_json::Value::Object(fields)).ok()
+            }
+            _ => None,
+        };
+        projected.unwrap_or_else(|| StandardClaims::new(claims.subject().clone()))
+    }
+
+    /// Handles a front-channel logout request from the Identity Provider
+    /// by clearing the session's authentication state, but only if the
+    /// session was authenticated by that provider and (if the provider
+    /// identified its session) belongs to the provider's session.
+    async fn frontchannel_logout<State>(
+        &self,
+        discovered: &DiscoveredProvider,
+        mut req: Request<State>,
+    ) -> tide::Result
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        #[derive(Deserialize)]
+        struct FrontchannelLogoutQuery {
+            iss: Option<IssuerUrl>,
+            sid: Option<String>,
+        }
+        let query: FrontchannelLogoutQuery = req.query()?;
+
+        // The session id is only unique within the issuer, so the two
+        // must be provided together.
+        match (&query.iss, &query.sid) {
+            (Some(iss), _) if *iss != discovered.issuer_url => {
+                return Err(tide::http::Error::from_str(
+                    StatusCode::BadRequest,
+                    "Front-channel logout issuer does not match the provider.",
+                ));
+            }
+            (None, Some(_)) => {
+                return Err(tide::http::Error::from_str(
+                    StatusCode::BadRequest,
+                    "Front-channel logout session id requires an issuer.",
+                ));
+            }
+            _ => {}
+        }
+
+        let matches_session = match req.session().get(&self.session_namespace) {
+            Some(MiddlewareSessionState::PostAuth {
+                provider,
+                session_id,
+                ..
+            }) => {
+                provider == self.provider
+                    && match &query.sid {
+                        Some(sid) => session_id.as_ref() == Some(sid),
+                        None => true,
+                    }
+            }
+            None => false,
+        };
+        if matches_session {
+            crate::log::event!(
+                debug,
+                "Clearing session after OpenID Connect front-channel logout."
+            );
+            self.unindex_session(&mut req).await;
+            if self.logout_destroys_session {
+                req.session_mut().destroy();
+            } else {
+                req.session_mut().remove(&self.session_namespace);
+            }
+        }
+
+        // The response is rendered in an iframe by the provider, and must
+        // not be cached.
+        let mut res = Response::new(StatusCode::Ok);
+        res.insert_header(tide::http::headers::CACHE_CONTROL, "no-cache, no-store");
+        Ok(res)
+    }
+
+    /// Handles a back-channel logout request from the Identity Provider
+    /// by verifying the logout token and destroying the matching
+    /// sessions in the session store.
+    async fn backchannel_logout<State>(
+        &self,
+        discovered: &DiscoveredProvider,
+        mut req: Request<State>,
+    ) -> tide::Result
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        #[derive(Deserialize)]
+        struct BackchannelLogoutForm {
+            logout_token: String,
+        }
+
+        self.refresh_stale_jwks(discovered).await;
+        let allowed_algs = self.allowed_signing_algs(discovered);
+        let keys = discovered.jwks.keys();
+        let verified = match req.body_form::<BackchannelLogoutForm>().await {
+            Ok(form) => LogoutTokenVerifier {
+                issuer: &discovered.issuer_url,
+                client_id: &self.client_id,
+                keys: &keys,
+                allowed_algs: &allowed_algs,
+                now: self.clock.now(),
+                leeway: self.timing_policy.leeway(),
+            }
+            .verify(&form.logout_token),
+            Err(error) => Err(error.to_string()),
+        };
+
+        // The provider expects a JSON error response if the logout
+        // could not be processed.
+        let logged_out = match verified {
+            Ok(logout) => {
+                crate::log::event!(
+                    debug,
+                    "Received OpenID Connect back-channel logout.",
+                    { sid: format!("{:?}", logout.session_id), sub: format!("{:?}", logout.subject) }
+                );
+                self.destroy_logged_out_sessions(logout).await
+            }
+            Err(error) => {
+                crate::log::event!(
+                    warn,
+                    "Rejected invalid OpenID Connect back-channel logout.",
+                    { error: error }
+                );
+                Err(())
+            }
+        };
+        let mut res = match logged_out {
+            Ok(()) => Response::new(StatusCode::Ok),
+            Err(()) => Response::builder(StatusCode::BadRequest)
+                .body(serde_json::json!({ "error": "invalid_request" }))
+                .build(),
+        };
+        res.insert_header(tide::http::headers::CACHE_CONTROL, "no-store");
+        Ok(res)
+    }
+
+    /// Destroys the sessions that belong to the provider session (or,
+    /// failing that, the user) of a back-channel logout. Failures are
+    /// logged, and reported to the provider as a failed logout.
+    async fn destroy_logged_out_sessions(&self, logout: BackchannelLogout) -> Result<(), ()> {
+        let session_index = match &self.session_index {
+            Some(session_index) => session_index,
+            None => {
+                crate::log::event!(
+                    error,
+                    "Back-channel logout requires a server-side session store; see OpenIdConnectMiddleware::with_session_store."
+                );
+                return Err(());
+            }
+        };
+        let key = match (logout.session_id, logout.subject) {
+            (Some(session_id), _) => IndexKey::ProviderSession(session_id),
+            (None, Some(subject)) => IndexKey::Subject(subject),
+            // The logout token has already been verified to contain one
+            // or the other.
+            (None, None) => return Err(()),
+        };
+        match session_index
+            .destroy_sessions(&self.session_index_scope(), &key)
+            .await
+        {
+            Ok(destroyed) => {
+                crate::log::event!(
+                    debug,
+                    "Destroyed sessions after OpenID Connect back-channel logout.",
+                    { sessions: destroyed }
+                );
+                Ok(())
+            }
+            Err(error) => {
+                crate::log::event!(
+                    error,
+                    "Failed to destroy sessions after OpenID Connect back-channel logout.",
+                    { error: error }
+                );
+                Err(())
+            }
+        }
+    }
+
+    /// Returns the session's authentication state, with the tokens
+    /// decrypted if [token encryption](Self::with_token_encryption_key)
+    /// has been enabled. Authentication state whose tokens cannot be
+    /// decrypted is removed from the session.
+    fn session_state<State>(&self, req: &mut Request<State>) -> Option<MiddlewareSessionState>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let session_state: MiddlewareSessionState = req.session().get(&self.session_namespace)?;
+        let key = match &self.token_encryption_key {
+            Some(key) => key,
+            None => return Some(session_state),
+        };
+
+        let session_state = session_state.map_tokens(|field, token| key.decrypt(field, token));
+        if session_state.is_none() {
+            crate::log::event!(
+                warn,
+                "Unable to decrypt the OpenID Connect tokens in the session; clearing the authentication state."
+            );
+            req.session_mut().remove(&self.session_namespace);
+            req.session_mut()
+                .remove(&self.session_key(LAST_SEEN_SESSION_KEY));
+        }
+        session_state
+    }
+
+    /// Stores the authentication state in the session, first encrypting
+    /// the tokens if [token encryption](Self::with_token_encryption_key)
+    /// has been enabled.
+    fn store_session_state<State>(
+        &self,
+        req: &mut Request<State>,
+        session_state: MiddlewareSessionState,
+    ) -> tide::Result<()>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let session_state = match &self.token_encryption_key {
+            Some(key) => session_state
+                .map_tokens(|field, token| key.encrypt(field, token))
+                .ok_or_else(|| {
+                    tide::http::Error::from_str(
+                        StatusCode::InternalServerError,
+                        "Unable to encrypt the session tokens.",
+                    )
+                })?,
+            None => session_state,
+        };
+        req.session_mut()
+            .insert(&self.session_namespace, session_state)
+            .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))
+    }
+
+    /// Returns the additional claims that the [claim
+    /// enricher](Self::with_claim_enricher) derives from the ID token
+    /// claims (if there is an enricher).
+    fn enrich_claims(&self, claims: &CoreIdTokenClaims) -> HashMap<String, serde_json::Value> {
+        self.claim_enricher
+            .as_ref()
+            .map(|claim_enricher| claim_enricher(claims))
+            .unwrap_or_default()
+    }
+
+    /// Returns the scheme of the client's connection: the scheme of the
+    /// request URL or, behind a [trusted proxy](Self::with_trusted_proxy),
+    /// the scheme reported by the proxy.
+    fn request_scheme<'a, State>(&self, req: &'a Request<State>) -> &'a str {
+        match forwarded_proto(req) {
+            Some(proto) if self.trusted_proxy => proto,
+            _ => req.url().scheme(),
+        }
+    }
+
+    /// Returns the response for a failed login, rendered by the [error
+    /// renderer](Self::with_error_renderer) if there is one.
+    fn error_response(&self, error: OpenIdConnectError) -> tide::Result {
+        let status = error.status();
+        match &self.error_renderer {
+            Some(error_renderer) => {
+                let mut res = error_renderer(&error);
+                res.set_error(tide::http::Error::new(status, error));
+                Ok(res)
+            }
+            None => Err(tide::http::Error::new(status, error)),
+        }
+    }
+
+    /// Returns the callback route's response, which sends the browser to
+    /// `location` in the configured [manner](Self::with_callback_response).
+    fn callback_response(&self, authenticated: bool, location: &str) -> Response {
+        match self.callback_response {
+            CallbackResponse::Json => Response::builder(StatusCode::Ok)
+                .header(tide::http::headers::CACHE_CONTROL, "no-store")
+                .body(serde_json::json!({
+                    "authenticated": authenticated,
+                    "redirect": location,
+                }))
+                .build(),
+            CallbackResponse::Redirect => self.redirect(location),
+        }
+    }
+
+    /// Records the authenticated user's id on the current tracing span,
+    /// if [enabled](Self::with_trace_user).
+    fn trace_user(&self, user: &AuthenticatedUser) {
+        #[cfg(feature = "tracing")]
+        if self.trace_user {
+            tracing::Span::current().record("user_id", user.user_id.as_str());
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = user;
+    }
+
+    /// Returns the (validated) path that the login request asks to return
+    /// to after login, if it has a [return parameter](Self::with_return_param).
+    fn return_to<State>(&self, req: &Request<State>) -> tide::Result<Option<String>> {
+        req.url()
+            .query_pairs()
+            .find(|(name, _)| *name == self.return_param)
+            .map(|(_, return_to)| validate_return_to(return_to.into_owned()))
+            .transpose()
+    }
+
+    /// Returns a redirect to `location` with the configured [redirect
+    /// status](Self::with_redirect_status).
+    fn redirect(&self, location: impl AsRef<str>) -> Response {
+        let mut res = Response::new(self.redirect_status);
+        res.insert_header(tide::http::headers::LOCATION, location.as_ref());
+        res
+    }
+
+    /// Returns the provider state, first discovering the Identity
+    /// Provider's metadata if the middleware was created with
+    /// [`lazy`](Self::lazy) and discovery has not yet succeeded.
+    async fn discovered_provider(&self) -> tide::Result<Arc<DiscoveredProvider>> {
+        if let Some(discovered) = self
+            .discovered
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            return Ok(discovered.clone());
+        }
+
+        let unavailable = || {
+            tide::http::Error::from_str(
+                StatusCode::ServiceUnavailable,
+                "OpenID Connect provider is unavailable.",
+            )
+        };
+        let lazy_discovery = self.lazy_discovery.as_ref().ok_or_else(unavailable)?;
+
+        // Wait for the backoff period to elapse before trying again.
+        let now = self.clock.now();
+        if matches!(
+            lazy_discovery
+                .backoff
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retry_at,
+            Some(retry_at) if now < retry_at
+        ) {
+            return Err(unavailable());
+        }
+
+        let provider_metadata =
+            DiscoveredMetadata::discover_async(self.issuer_url.clone(), |request| {
+                http_client_with_timeout(request, self.http_timeout)
+            })
+            .await;
+        match provider_metadata {
+            Ok(provider_metadata) => {
+                let discovered = Arc::new(DiscoveredProvider::new(
+                    provider_metadata,
+                    self.client_id.clone(),
+                    self.client_secret.clone(),
+                    self.redirect_url.clone(),
+                    self.clock.now(),
+                ));
+                *self
+                    .discovered
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(discovered.clone());
+                Ok(discovered)
+            }
+            Err(error) => {
+                crate::log::event!(
+                    warn,
+                    "Unable to load OpenID Connect provider metadata; retrying after a delay.",
+                    { error: error.to_string() }
+                );
+                let mut backoff = lazy_discovery
+                    .backoff
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                let delay =
+                    Duration::from_secs(1 << backoff.failures.min(6)).min(MAX_DISCOVERY_BACKOFF);
+                backoff.failures += 1;
+                backoff.retry_at = Some(now + delay);
+                Err(unavailable())
+            }
+        }
+    }
+
+    /// Returns an ID token verifier that uses the cached JWKS, first
+    /// re-fetching the key set if the cached copy has gone stale.
+    ///
+    /// The verifier requires the `iss` claim to exactly match the
+    /// provider's issuer URL (which discovery has already checked
+    /// against the configured issuer URL), so that tokens signed with
+    /// the provider's keys for another issuer -- another tenant of a
+    /// multi-tenant provider, for example -- are rejected.
+    async fn id_token_verifier<'a>(
+        &self,
+        discovered: &'a DiscoveredProvider,
+    ) -> CoreIdTokenVerifier<'a> {
+        self.refresh_stale_jwks(discovered).await;
+
+        // Verify the token's expiration time against a clock that has
+        // been turned back by the leeway, which accepts tokens from
+        // providers whose clocks are slightly ahead of ours.
+        let leeway = chrono::Duration::from_std(self.timing_policy.leeway())
+            .unwrap_or_else(|_| chrono::Duration::zero());
+        let clock = self.clock.clone();
+        let additional_audiences = self.additional_audiences.clone();
+
+        // Public clients do not have a client secret, and so cannot
+        // verify tokens signed with the (HMAC) secret.
+        let verifier = match &self.client_secret {
+            Some(client_secret) => CoreIdTokenVerifier::new_confidential_client(
+                self.client_id.clone(),
+                client_secret.clone(),
+                discovered.issuer_url.clone(),
+                discovered.jwks.keys(),
+            ),
+            None => CoreIdTokenVerifier::new_public_client(
+                self.client_id.clone(),
+                discovered.issuer_url.clone(),
+                discovered.jwks.keys(),
+            ),
+        };
+
+        verifier
+            .set_allowed_algs(self.allowed_signing_algs(discovered))
+            .set_other_audience_verifier_fn(move |aud| {
+                additional_audiences.iter().any(|a| a == aud.as_str())
+            })
+            .set_time_fn(move || {
+                let now = chrono::DateTime::<chrono::Utc>::from(clock.now());
+                now.checked_sub_signed(leeway)
+                    .unwrap_or(chrono::DateTime::<chrono::Utc>::MIN_UTC)
+            })
+    }
+
+    /// Verifies the ID token that the hybrid flow returns along with the
+    /// authorization code, including the `c_hash` claim that binds the
+    /// token to the code.
+    async fn verify_front_channel_id_token(
+        &self,
+        discovered: &DiscoveredProvider,
+        id_token: &str,
+        code: &AuthorizationCode,
+        nonce: &Nonce,
+    ) -> Result<CoreIdTokenClaims, OpenIdConnectError> {
+        let id_token_verifier = self.id_token_verifier(discovered).await;
+        let id_token = id_token
+            .parse::<CoreIdToken>()
+            .map_err(|error| OpenIdConnectError::ClaimVerification(error.to_string()))?;
+        let claims = id_token
+            .claims(&id_token_verifier, nonce)
+            .map_err(id_token_error)?;
+
+        let expected_hash = claims.code_hash().ok_or_else(|| {
+            OpenIdConnectError::ClaimVerification(
+                "Front-channel ID token does not contain a code hash".to_string(),
+            )
+        })?;
+        let actual_hash = id_token
+            .signing_alg()
+            .map_err(|error| error.to_string())
+            .and_then(|alg| {
+                AuthorizationCodeHash::from_code(code, &alg).map_err(|error| error.to_string())
+            })
+            .map_err(OpenIdConnectError::ClaimVerification)?;
+        if actual_hash != *expected_hash {
+            return Err(OpenIdConnectError::ClaimVerification(
+                "Authorization code hash does not match the authorization code".to_string(),
+            ));
+        }
+        Ok(claims.clone())
+    }
+
+    /// Returns the algorithms that the provider uses to sign tokens,
+    /// limited to the [allowed algorithms](Self::with_allowed_algorithms).
+    fn allowed_signing_algs(
+        &self,
+        discovered: &DiscoveredProvider,
+    ) -> Vec<CoreJwsSigningAlgorithm> {
+        discovered
+            .id_token_signing_algs
+            .iter()
+            .filter(|alg| match &self.allowed_algorithms {
+                Some(allowed_algorithms) => allowed_algorithms.contains(alg),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Re-fetches the provider's key set if the cached copy has gone
+    /// stale.
+    async fn refresh_stale_jwks(&self, discovered: &DiscoveredProvider) {
+        if discovered
+            .jwks
+            .is_stale(self.jwks_refresh_interval, self.clock.now())
+        {
+            self.refresh_jwks(discovered).await;
+        }
+    }
+
+    /// Re-fetches the provider's key set after a token was signed with a
+    /// key that is not in the cached copy, which happens when the
+    /// provider rotates its keys between refreshes. Returns `true` if the
+    /// key set was re-fetched, which it is not if the cached copy is
+    /// younger than [`JWKS_REFETCH_MIN_INTERVAL`] (so that tokens with
+    /// unknown keys cannot make the middleware hammer the provider).
+    async fn refetch_jwks_for_unknown_key(&self, discovered: &DiscoveredProvider) -> bool {
+        if !discovered
+            .jwks
+            .is_stale(JWKS_REFETCH_MIN_INTERVAL, self.clock.now())
+        {
+            return false;
+        }
+        crate::log::event!(
+            info,
+            "ID token is signed with an unknown key; re-fetching the OpenID Connect JWKS."
+        );
+        self.refresh_jwks(discovered).await
+    }
+
+    /// Re-fetches the provider's key set, returning `true` if the fetch
+    /// succeeded.
+    async fn refresh_jwks(&self, discovered: &DiscoveredProvider) -> bool {
+        let started = Instant::now();
+        let refreshed = discovered
+            .jwks
+            .refresh(self.http_timeout, self.clock.now())
+            .await;
+        self.record_metric(MetricEvent::JwksRefresh {
+            duration: started.elapsed(),
+        });
+        match refreshed {
+            Ok(()) => true,
+            Err(error) => {
+                crate::log::event!(
+                    warn,
+                    "Unable to refresh the OpenID Connect JWKS; continuing with the cached keys.",
+                    { error: error.to_string() }
+                );
+                false
+            }
+        }
+    }
+
+    /// Exchanges the session's refresh token for a new access token if
+    /// the current access token has expired (and was issued by this
+    /// middleware's provider).
+    ///
+    /// Providers that rotate refresh tokens return a new refresh token
+    /// with every refresh, which replaces the stored token. A refresh
+    /// that fails with `invalid_grant` may indicate that the refresh
+    /// token was reused (by an attacker that stole it, for example), and
+    /// so the authentication state is cleared in order to force a new
+    /// login. Other failures leave the session as-is.
+    async fn refresh_expired_token<State>(
+        &self,
+        discovered: &DiscoveredProvider,
+        req: &mut Request<State>,
+    ) -> tide::Result<()>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let mut session_state = match self.session_state(req) {
+            Some(session_state) => session_state,
+            None => return Ok(()),
+        };
+        let MiddlewareSessionState::PostAuth {
+            access_token,
+            scopes,
+            expires_at,
+            refresh_token,
+            provider,
+            id_token,
+            ..
+        } = &mut session_state;
+        let now = self.clock.now();
+        let refresh_token = match (&*expires_at, refresh_token) {
+            (Some(expires_at), Some(refresh_token))
+                if *expires_at <= now && *provider == self.provider =>
+            {
+                refresh_token
+            }
+            _ => return Ok(()),
+        };
+
+        let client_assertion = match self.client_assertion(discovered) {
+            Ok(client_assertion) => client_assertion,
+            Err(error) => {
+                crate::log::event!(
+                    warn,
+                    "Unable to refresh the OpenID Connect access token.",
+                    { error: error }
+                );
+                return Ok(());
+            }
+        };
+        let token_client = self.token_client(discovered);
+        let mut refresh_request = token_client.exchange_refresh_token(refresh_token);
+        if let Some(client_assertion) = client_assertion {
+            refresh_request = refresh_request
+                .add_extra_param("client_assertion_type", CLIENT_ASSERTION_TYPE)
+                .add_extra_param("client_assertion", client_assertion);
+        }
+        for resource in &self.resources {
+            refresh_request = refresh_request.add_extra_param("resource", resource.clone());
+        }
+        let started = Instant::now();
+        let token_response = refresh_request
+            .request_async(|request| http_client_with_timeout(request, self.http_timeout))
+            .await;
+        self.record_metric(MetricEvent::TokenRefresh {
+            duration: started.elapsed(),
+        });
+
+        match token_response {
+            Ok(token_response) => {
+                *access_token = token_response.access_token().clone();
+                *expires_at = token_response
+                    .expires_in()
+                    .map(|expires_in| now + expires_in);
+                if let Some(granted_scopes) = token_response.scopes() {
+                    *scopes = granted_scopes.clone();
+                }
+                if let Some(rotated_refresh_token) = token_response.refresh_token() {
+                    *refresh_token = rotated_refresh_token.clone();
+                }
+                if let Some(refreshed_id_token) = token_response.extra_fields().id_token() {
+                    *id_token = Some(refreshed_id_token.to_string());
+                }
+                self.store_session_state(req, session_state)?;
+            }
+            Err(RequestTokenError::ServerResponse(response))
+                if *response.error() == CoreErrorResponseType::InvalidGrant =>
+            {
+                crate::log::event!(
+                    warn,
+                    "OpenID Connect refresh token was rejected (and may have been reused); clearing the authentication state."
+                );
+                req.session_mut().remove(&self.session_namespace);
+                req.session_mut()
+                    .remove(&self.session_key(LAST_SEEN_SESSION_KEY));
+            }
+            Err(error) => {
+                crate::log::event!(
+                    warn,
+                    "Unable to refresh the OpenID Connect access token.",
+                    { error: error.to_string() }
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the client used for token requests, which authenticates
+    /// itself with the configured client authentication method.
+    fn token_client<'a>(&self, discovered: &'a DiscoveredProvider) -> Cow<'a, CoreClient> {
+        match self.auth_method {
+            CoreClientAuthMethod::ClientSecretPost => Cow::Owned(
+                discovered
+                    .client
+                    .clone()
+                    .set_auth_type(AuthType::RequestBody),
+            ),
+            CoreClientAuthMethod::PrivateKeyJwt => Cow::Borrowed(&discovered.assertion_client),
+            _ => Cow::Borrowed(&discovered.client),
+        }
+    }
+
+    /// Returns a new client assertion for a token request, if the client
+    /// authenticates itself with the `private_key_jwt` method.
+    fn client_assertion(&self, discovered: &DiscoveredProvider) -> Result<Option<String>, String> {
+        if self.auth_method != CoreClientAuthMethod::PrivateKeyJwt {
+            return Ok(None);
+        }
+        let key = self.client_assertion_key.as_ref().ok_or_else(|| {
+            "private_key_jwt client authentication requires a client assertion key".to_string()
+        })?;
+        let token_url = discovered
+            .token_url
+            .as_ref()
+            .ok_or_else(|| "provider does not have a token endpoint".to_string())?;
+        key.sign(&self.client_id, token_url, self.clock.now())
+            .map(Some)
+            .map_err(|error| error.to_string())
+    }
+
+    /// Pushes the authorization request to the provider, returning the
+    /// authorization URL that refers to the pushed request.
+    async fn push_authorization_request(
+        &self,
+        discovered: &DiscoveredProvider,
+        par_url: &Url,
+        authorize_url: &Url,
+    ) -> Result<Url, OpenIdConnectError> {
+        let client_auth = match (&self.auth_method, &self.client_secret) {
+            (CoreClientAuthMethod::PrivateKeyJwt, _) => self
+                .client_assertion(discovered)
+                .map_err(OpenIdConnectError::ProviderUnavailable)?
+                .map_or(ParClientAuth::None, ParClientAuth::Assertion),
+            (CoreClientAuthMethod::ClientSecretPost, Some(client_secret)) => {
+                ParClientAuth::Post(client_secret)
+            }
+            (_, Some(client_secret)) => ParClientAuth::Basic(client_secret),
+            (_, None) => ParClientAuth::None,
+        };
+
+        push_authorization_request(
+            par_url,
+            authorize_url,
+            &self.client_id,
+            client_auth,
+            self.http_timeout,
+        )
+        .await
+        .map_err(|error| {
+            crate::log::event!(
+                warn,
+                "Unable to push the OpenID Connect authorization request.",
+                { error: error }
+            );
+            OpenIdConnectError::ProviderUnavailable(format!(
+                "Pushed authorization request failed: {}",
+                error
+            ))
+        })
+    }
+
+    /// Re-fetches the user info if the claims in the session are older
+    /// than the [claims refresh
+    /// interval](Self::with_claims_refresh_interval). Failures leave
+    /// the session as-is.
+    async fn refresh_stale_claims<State>(
+        &self,
+        discovered: &DiscoveredProvider,
+        req: &mut Request<State>,
+    ) -> tide::Result<()>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let claims_refresh_interval = match self.claims_refresh_interval {
+            Some(claims_refresh_interval) => claims_refresh_interval,
+            None => return Ok(()),
+        };
+        let mut session_state = match self.session_state(req) {
+            Some(session_state) => session_state,
+            None => return Ok(()),
+        };
+        let MiddlewareSessionState::PostAuth {
+            subject,
+            access_token,
+            user_info,
+            authenticated_at,
+            provider,
+            logged_in_at,
+            claims_refreshed_at,
+            ..
+        } = &mut session_state;
+        let now = self.clock.now();
+        let claims_fetched_at = claims_refreshed_at
+            .or(*logged_in_at)
+            .unwrap_or(*authenticated_at);
+        if *provider != self.provider
+            || now.duration_since(claims_fetched_at).unwrap_or_default() < claims_refresh_interval
+        {
+            return Ok(());
+        }
+
+        let started = Instant::now();
+        let refreshed_user_info: Result<CoreUserInfoClaims, String> = match discovered
+            .client
+            .user_info(access_token.clone(), Some(subject.clone()))
+        {
+            Ok(user_info_request) => user_info_request
+                .request_async(|request| http_client_with_timeout(request, self.http_timeout))
+                .await
+                .map_err(|error| error.to_string()),
+            Err(error) => Err(error.to_string()),
+        };
+        self.record_metric(MetricEvent::UserInfo {
+            duration: started.elapsed(),
+        });
+
+        match refreshed_user_info {
+            Ok(refreshed_user_info) => {
+                **user_info = self.project_session_claims(refreshed_user_info.standard_claims());
+                *claims_refreshed_at = Some(now);
+                self.store_session_state(req, session_state)?;
+            }
+            Err(error) => {
+                crate::log::event!(
+                    warn,
+                    "Unable to refresh the OpenID Connect user info; keeping the previous claims.",
+                    { error: error }
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the bearer token in the request's `Authorization` header,
+    /// if [bearer token authentication](Self::with_bearer_token_auth) is
+    /// enabled and the request has such a header.
+    fn bearer_token<State>(&self, req: &Request<State>) -> Option<String> {
+        if !self.bearer_token_auth {
+            return None;
+        }
+        let authorization = req.header(tide::http::headers::AUTHORIZATION)?.last();
+        let (scheme, token) = authorization.as_str().split_once(' ')?;
+        if scheme.eq_ignore_ascii_case("Bearer") && !token.trim().is_empty() {
+            Some(token.trim().to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Authenticates a request with the given bearer token, instead of
+    /// with the session, then passes the request on to the next
+    /// middleware. Requests with an invalid token are rejected.
+    async fn handle_bearer_request<State>(
+        &self,
+        discovered: &DiscoveredProvider,
+        mut req: Request<State>,
+        bearer_token: String,
+        next: Next<'_, State>,
+    ) -> tide::Result
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let (auth_state, granted_scopes) =
+            match self.verify_bearer_token(discovered, &bearer_token).await {
+                Ok(verified) => verified,
+                Err(error) => {
+                    crate::log::event!(
+                        debug,
+                        "Rejecting request with an invalid bearer token.",
+                        { error: error.to_string() }
+                    );
+                    let mut res = Response::new(error.status());
+                    res.insert_header(
+                        tide::http::headers::WWW_AUTHENTICATE,
+                        "Bearer error=\"invalid_token\"",
+                    );
+                    res.set_error(tide::http::Error::new(error.status(), error));
+                    return Ok(res);
+                }
+            };
+
+        if let OpenIdConnectRequestExtData::Authenticated { user, .. } = &auth_state {
+            self.trace_user(user);
+        }
+        req.set_ext(auth_state);
+        req.set_ext(granted_scopes.clone());
+        let mut res = next.run(req).await;
+        res.insert_ext(granted_scopes);
+        Ok(res)
+    }
+
+    /// Verifies the signature and claims of a bearer token, returning
+    /// the authentication state and the scopes granted by the token.
+    async fn verify_bearer_token(
+        &self,
+        discovered: &DiscoveredProvider,
+        bearer_token: &str,
+    ) -> Result<(OpenIdConnectRequestExtData, GrantedScopes), OpenIdConnectError> {
+        // Bearer tokens must be JWT access tokens that were issued for
+        // the bearer audience; ID tokens (which are issued for the
+        // client) are not accepted in their place.
+        self.refresh_stale_jwks(discovered).await;
+        let claims = AccessTokenVerifier {
+            issuer: &discovered.issuer_url,
+            audience: self
+                .bearer_audience
+                .as_deref()
+                .unwrap_or_else(|| self.client_id.as_str()),
+            keys: &discovered.jwks.keys(),
+            allowed_algs: &self.allowed_signing_algs(discovered),
+            now: self.clock.now(),
+            leeway: self.timing_policy.leeway(),
+        }
+        .verify(bearer_token)
+        .map_err(OpenIdConnectError::ClaimVerification)?;
+        if let Some(claims_validator) = &self.claims_validator {
+            claims_validator(&claims).map_err(OpenIdConnectError::ClaimsRejected)?;
+        }
+
+        // The token's claims take the place of the user info, and its
+        // `scope` claim (if any) lists the granted scopes.
+        let token_claims = decode_jwt_claims(bearer_token);
+        let user_info = token_claims
+            .clone()
+            .and_then(|token_claims| serde_json::from_value(token_claims).ok())
+            .unwrap_or_else(|| StandardClaims::new(claims.subject().clone()));
+        let user_id = match &self.user_id_claim {
+            Some(user_id_claim) => {
+                user_id_from_claims(user_id_claim, token_claims.as_ref(), &user_info)?
+            }
+            None => None,
+        };
+        let scopes: Vec<String> = token_claims
+            .as_ref()
+            .and_then(|token_claims| token_claims.get("scope"))
+            .and_then(|scope| scope.as_str())
+            .map(|scope| scope.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default();
+
+        let now = self.clock.now();
+        let expires_at = SystemTime::from(claims.expiration());
+        let user = AuthenticatedUser::new(
+            claims.subject().to_string(),
+            user_id,
+            claims.issuer().to_string(),
+            self.provider.clone(),
+            &user_info,
+        );
+        Ok((
+            OpenIdConnectRequestExtData::Authenticated {
+                user: Box::new(user),
+                access_token: bearer_token.to_string(),
+                scopes: scopes.clone(),
+                user_info: Box::new(self.project_session_claims(&user_info)),
+                claims: Box::new(Claims::new(
+                    scopes.clone(),
+                    user_claims(token_claims, None, self.enrich_claims(&claims)),
+                )),
+                expires_at: Some(expires_at),
+                expires_in: Some(expires_at.duration_since(now).unwrap_or_default()),
+                authenticated_at: claims
+                    .auth_time()
+                    .map_or_else(|| SystemTime::from(claims.issue_time()), SystemTime::from),
+            },
+            GrantedScopes(scopes),
+        ))
+    }
+
+    /// Returns the configured scopes, plus the `offline_access` scope
+    /// (if enabled and not already configured). Does not include the
+    /// `openid` scope, which is always requested.
+    fn requested_scopes(&self) -> Vec<Scope> {
+        let mut scopes = self.scopes.clone();
+        if self.offline_access && !scopes.iter().any(|s| s.as_str() == "offline_access") {
+            scopes.push(Scope::new("offline_access".to_string()));
+        }
+        scopes
+    }
+
+    /// Returns [`ConfigError::UnsignedSessionStore`] if signed sessions
+    /// are required, but the session store of the given type keeps the
+    /// session data in the session cookie.
+    fn check_signed_session_store(
+        &self,
+        store_type: TypeId,
+        store_name: &'static str,
+    ) -> Result<(), ConfigError> {
+        if self.require_signed_session && store_type == TypeId::of::<CookieStore>() {
+            return Err(ConfigError::UnsignedSessionStore { store: store_name });
+        }
+
+        Ok(())
+    }
+
+    /// Panics if the [session store](Self::with_session_store) does not
+    /// meet the [signed session
+    /// requirement](Self::with_require_signed_session).
+    fn assert_signed_session_store(&self) {
+        if let Some((store_type, store_name)) = self.session_store {
+            if let Err(error) = self.check_signed_session_store(store_type, store_name) {
+                panic!("{}", error);
+            }
+        }
+    }
+
+    /// Panics if the login path collides with the path of the redirect
+    /// URL.
+    fn assert_distinct_login_path(&self) {
+        assert!(
+            normalize_path(&self.login_path) != self.callback_path(),
+            "Login path `{}` collides with the path of the redirect URL `{}`; change one of the two paths.",
+            self.login_path,
+            self.redirect_url.as_str()
+        );
+    }
+
+    /// Returns the path of the redirect URL as seen by the middleware,
+    /// which excludes the mount path and scope path (if any).
+    fn callback_path(&self) -> String {
+        let path = normalize_path(self.redirect_url.url().path());
+        strip_path_prefix(strip_path_prefix(path, &self.mount_path), &self.scope_path)
+    }
+
+    /// Returns `true` if the (normalized) path is, or is nested below,
+    /// one of the public paths.
+    fn is_public_path(&self, path: &str) -> bool {
+        self.public_paths.iter().any(|public_path| {
+            public_path == "/"
+                || match path.strip_prefix(public_path.as_str()) {
+                    Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                    None => false,
+                }
+        })
+    }
+
+    /// Reports a timing measurement to the metrics hook (if any).
+    fn record_metric(&self, event: MetricEvent) {
+        if let Some(metrics) = &self.metrics {
+            metrics(event);
+        }
+    }
+
+    /// Returns `true` if the session has been authenticated by this
+    /// middleware's provider (and, with strict authentication, the
+    /// access token has not expired).
+    fn is_authenticated<State>(&self, req: &Request<State>) -> bool
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        match req.session().get(&self.session_namespace) {
+            Some(MiddlewareSessionState::PostAuth {
+                provider,
+                expires_at,
+                ..
+            }) => provider == self.provider && !self.is_token_expired(expires_at),
+            None => false,
+        }
+    }
+
+    /// Returns `true` if [strict
+    /// authentication](Self::with_strict_authentication) is enabled and
+    /// the access token (which expires at the given time) has expired.
+    fn is_token_expired(&self, expires_at: Option<SystemTime>) -> bool {
+        self.strict_authentication
+            && matches!(expires_at, Some(expires_at) if expires_at <= self.clock.now())
     }
 
-    /// Sets the trait used to generate redirect responses to
-    /// unauthenticated requests.
-    ///
-    /// Defaults to [`HttpRedirect`](crate::redirect_strategy::HttpRedirect)
-    pub fn with_unauthenticated_redirect_strategy<R>(mut self, redirect_strategy: R) -> Self
+    /// Checks that the session is still authenticated by this
+    /// middleware: that it is [bound](Self::with_session_binding) to the
+    /// client with the given fingerprint, has not exceeded the [idle
+    /// timeout](Self::with_idle_timeout), and -- with [strict
+    /// authentication](Self::with_strict_authentication) -- that its
+    /// access token has not expired. Sessions that are checked outside
+    /// of a request have no client, and so their binding is only checked
+    /// to have been recorded under the current binding.
+    fn check_session(
+        &self,
+        session: &Session,
+        client_fingerprint: Option<&str>,
+    ) -> Result<(), InvalidSession> {
+        let expires_at = match session.get(&self.session_namespace) {
+            Some(MiddlewareSessionState::PostAuth {
+                provider,
+                expires_at,
+                ..
+            }) if provider == self.provider => expires_at,
+            _ => return Err(InvalidSession::Unauthenticated),
+        };
+
+        if let Some(binding) = self.session_binding_name() {
+            let recorded = session.get::<String>(&self.session_key(FINGERPRINT_SESSION_KEY));
+            let bound = match (recorded, client_fingerprint) {
+                (Some(recorded), Some(fingerprint)) => recorded == fingerprint,
+                (Some(recorded), None) => recorded.starts_with(&format!("{}:", binding)),
+                (None, _) => false,
+            };
+            if !bound {
+                return Err(InvalidSession::Rebound);
+            }
+        }
+
+        if let (Some(idle_timeout), Some(last_seen)) = (
+            self.idle_timeout,
+            session.get::<SystemTime>(&self.session_key(LAST_SEEN_SESSION_KEY)),
+        ) {
+            if self
+                .clock
+                .now()
+                .duration_since(last_seen)
+                .unwrap_or_default()
+                > idle_timeout
+            {
+                return Err(InvalidSession::Idle);
+            }
+        }
+
+        if self.is_token_expired(expires_at) {
+            return Err(InvalidSession::Expired);
+        }
+        Ok(())
+    }
+
+    /// Returns the scope of this middleware's records in the [session
+    /// index](Self::with_session_store), which keeps the sessions of
+    /// middleware instances for different providers apart.
+    fn session_index_scope(&self) -> String {
+        format!(
+            "{}\0{}",
+            self.session_namespace,
+            self.provider.as_deref().unwrap_or_default()
+        )
+    }
+
+    /// Updates the [session index](Self::with_session_store) so that the
+    /// session is indexed under exactly the given keys (which are none
+    /// once the session has been logged out).
+    async fn update_session_index<State>(
+        &self,
+        req: &mut Request<State>,
+        keys: Vec<IndexKey>,
+    ) -> Result<(), OpenIdConnectError>
     where
-        R: RedirectStrategy + 'static,
+        State: Clone + Send + Sync + 'static,
     {
-        self.redirect_strategy = Arc::new(redirect_strategy);
-        self
+        let session_index = match &self.session_index {
+            Some(session_index) => session_index,
+            None => return Ok(()),
+        };
+        let indexed_key = self.session_key(INDEXED_SESSION_KEY);
+        let indexed: Vec<IndexKey> = req.session().get(&indexed_key).unwrap_or_default();
+        if indexed == keys {
+            return Ok(());
+        }
+
+        let scope = self.session_index_scope();
+        let session_id = req.session().id().to_string();
+        for key in indexed.iter().filter(|key| !keys.contains(key)) {
+            session_index
+                .remove(&scope, key, &session_id)
+                .await
+                .map_err(OpenIdConnectError::SessionUnavailable)?;
+        }
+        for key in keys.iter().filter(|key| !indexed.contains(key)) {
+            session_index
+                .insert(&scope, key, &session_id)
+                .await
+                .map_err(OpenIdConnectError::SessionUnavailable)?;
+        }
+        if keys.is_empty() {
+            req.session_mut().remove(&indexed_key);
+        } else {
+            req.session_mut()
+                .insert(&indexed_key, keys)
+                .map_err(|error| OpenIdConnectError::SessionUnavailable(error.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Removes the session from the [session
+    /// index](Self::with_session_store) as part of a logout. Failures are
+    /// logged rather than failing the logout; the session itself is
+    /// logged out regardless.
+    async fn unindex_session<State>(&self, req: &mut Request<State>)
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        if let Err(error) = self.update_session_index(req, Vec::new()).await {
+            crate::log::event!(
+                warn,
+                "Failed to remove the session from the session index.",
+                { error: error.to_string() }
+            );
+        }
+    }
+
+    /// Returns the session key with the given name, within the
+    /// middleware's [session namespace](Self::with_session_namespace).
+    fn session_key(&self, name: &str) -> String {
+        format!("{}.{}", self.session_namespace, name)
+    }
+
+    /// Returns the session key of the pending logins, which is specific
+    /// to the provider (if any) so that multiple middleware instances do
+    /// not collide.
+    fn pending_logins_session_key(&self) -> String {
+        match &self.provider {
+            Some(provider) => format!(
+                "{}.{}",
+                self.session_key(PENDING_LOGINS_SESSION_KEY),
+                provider
+            ),
+            None => self.session_key(PENDING_LOGINS_SESSION_KEY),
+        }
+    }
+
+    /// Returns the logins that have been started, but not yet completed,
+    /// by this session.
+    fn pending_logins<State>(&self, req: &Request<State>) -> HashMap<String, PendingLogin>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        req.session()
+            .get(&self.pending_logins_session_key())
+            .unwrap_or_default()
+    }
+
+    /// Records the request as the most recent activity of an
+    /// authenticated session (if an idle timeout has been configured).
+    fn record_activity<State>(&self, req: &mut Request<State>) -> tide::Result<()> {
+        if self.idle_timeout.is_none()
+            || !matches!(
+                req.session().get(&self.session_namespace),
+                Some(MiddlewareSessionState::PostAuth { .. })
+            )
+        {
+            return Ok(());
+        }
+
+        req.session_mut()
+            .insert(&self.session_key(LAST_SEEN_SESSION_KEY), self.clock.now())
+            .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))?;
+        Ok(())
+    }
+
+    /// Returns the name of the configured [`SessionBinding`], which
+    /// prefixes the client fingerprints, or `None` if sessions are not
+    /// bound.
+    fn session_binding_name(&self) -> Option<&'static str> {
+        match self.session_binding {
+            SessionBinding::None => None,
+            SessionBinding::UserAgent => Some("user-agent"),
+            SessionBinding::IpPrefix => Some("ip-prefix"),
+        }
+    }
+
+    /// Returns the fingerprint of the client according to the configured
+    /// [`SessionBinding`], or `None` if sessions are not bound.
+    fn client_fingerprint<State>(&self, req: &Request<State>) -> Option<String> {
+        let binding = self.session_binding_name()?;
+        let source = match self.session_binding {
+            SessionBinding::None => return None,
+            SessionBinding::UserAgent => req
+                .header(tide::http::headers::USER_AGENT)
+                .map(|user_agent| user_agent.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            SessionBinding::IpPrefix => {
+                let remote = if self.trusted_proxy {
+                    req.remote()
+                } else {
+                    req.peer_addr()
+                };
+                remote
+                    .and_then(|remote| {
+                        remote
+                            .parse::<SocketAddr>()
+                            .map(|addr| addr.ip())
+                            .or_else(|_| remote.trim_matches(&['[', ']'][..]).parse::<IpAddr>())
+                            .ok()
+                    })
+                    .map(|ip| match ip {
+                        IpAddr::V4(ip) => format!("{:?}", &ip.octets()[..3]),
+                        IpAddr::V6(ip) => format!("{:?}", &ip.segments()[..3]),
+                    })
+                    .unwrap_or_default()
+            }
+        };
+        Some(format!(
+            "{}:{}",
+            binding,
+            base64::encode_config(
+                Sha256::digest(format!("{}:{}", binding, source).as_bytes()),
+                base64::URL_SAFE_NO_PAD,
+            )
+        ))
     }
 
-    async fn generate_redirect<State>(&self, mut req: Request<State>) -> tide::Result
+    async fn generate_redirect<State>(
+        &self,
+        discovered: &DiscoveredProvider,
+        mut req: Request<State>,
+    ) -> tide::Result
     where
         State: Clone + Send + Sync + 'static,
     {
-        let mut request = self.client.authorize_url(
-            AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
-            CsrfToken::new_random,
-            Nonce::new_random,
+        // Get the (optional) login hint, which allows the application to
+        // pre-fill the username on the Identity Provider's sign in page
+        // by linking to, for example, `/login?login_hint=user@example.com`.
+        #[derive(Deserialize)]
+        struct LoginQuery {
+            login_hint: Option<String>,
+        }
+        let login_query: LoginQuery = req.query()?;
+        let return_to = self.return_to(&req)?;
+
+        let (state, nonce) = match &self.random_source {
+            Some(random_source) => (CsrfToken::new(random_source()), Nonce::new(random_source())),
+            None => (CsrfToken::new_random(), Nonce::new_random()),
+        };
+        let mut request = discovered.client.authorize_url(
+            self.authentication_flow.clone(),
+            move || state,
+            move || nonce,
         );
-        for s in &self.scopes {
-            request = request.add_scope(s.clone());
+        for s in self.requested_scopes() {
+            request = request.add_scope(s);
+        }
+        if self.offline_access {
+            request = request.add_prompt(CoreAuthPrompt::Consent);
+        }
+        for acr_value in &self.acr_values {
+            request = request.add_auth_context_value(acr_value.clone());
+        }
+        for resource in &self.resources {
+            request = request.add_extra_param("resource", resource.clone());
+        }
+        if self.response_mode == ResponseMode::FormPost {
+            let scheme = self.request_scheme(&req);
+            if scheme != "https" {
+                crate::log::event!(
+                    warn,
+                    "OpenID Connect form_post callbacks need a SameSite=None session cookie, which browsers reject without the Secure attribute; Tide only sets that attribute on responses to https requests.",
+                    { scheme: scheme }
+                );
+            }
+            request = request.add_extra_param("response_mode", "form_post");
+        } else if matches!(self.authentication_flow, AuthenticationFlow::Hybrid(_)) {
+            crate::log::event!(
+                warn,
+                "OpenID Connect hybrid flow responses are returned in the URL fragment, which is not sent to the server; use the form_post response mode with the hybrid flow."
+            );
+        }
+        for (name, value) in &self.extra_authorize_params {
+            request = request.add_extra_param(name.clone(), value.clone());
+        }
+        if let Some(login_hint) = login_query.login_hint {
+            request = request.set_login_hint(validate_login_hint(login_hint)?);
         }
+        let pkce_verifier = if self.pkce {
+            let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+            request = request.set_pkce_challenge(pkce_challenge);
+            Some(pkce_verifier)
+        } else {
+            None
+        };
         let (authorize_url, csrf_token, nonce) = request.url();
+        let authorize_url = match &discovered.par_url {
+            Some(par_url) if self.par => match self
+                .push_authorization_request(discovered, par_url, &authorize_url)
+                .await
+            {
+                Ok(authorize_url) => authorize_url,
+                Err(error) => return self.error_response(error),
+            },
+            _ => authorize_url,
+        };
+        crate::log::event!(
+            debug,
+            "Redirecting to the OpenID Connect authorization endpoint.",
+            { url: crate::log::redact_url(&authorize_url) }
+        );
 
-        // Initialize the middleware's session state so that we can
+        // Add this login to the session's pending logins so that we can
         // validate the login after the user completes the authentication
-        // flow.
+        // flow. Logins that can no longer be completed are dropped, as
+        // are the oldest logins if there are too many of them.
+        let now = self.clock.now();
+        let lifetime = self.timing_policy.authorize_state_lifetime();
+        let mut pending_logins = self.pending_logins(&req);
+        pending_logins
+            .retain(|_, login| now.duration_since(login.issued_at).unwrap_or_default() <= lifetime);
+        pending_logins.insert(
+            csrf_token.secret().clone(),
+            PendingLogin {
+                nonce,
+                issued_at: now,
+                pkce_verifier,
+                consumed: false,
+                return_to,
+            },
+        );
+        while pending_logins.len() > MAX_PENDING_LOGINS {
+            if let Some(oldest) = pending_logins
+                .iter()
+                .min_by_key(|(_, login)| login.issued_at)
+                .map(|(state, _)| state.clone())
+            {
+                pending_logins.remove(&oldest);
+            }
+        }
         req.session_mut()
-            .insert(
-                SESSION_KEY,
-                MiddlewareSessionState::PreAuth(csrf_token, nonce),
-            )
+            .insert(&self.pending_logins_session_key(), pending_logins)
             .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))?;
 
-        Ok(Redirect::new(&authorize_url).into())
+        if self.interstitial_login {
+            Ok(interstitial_redirect(authorize_url.as_str()))
+        } else {
+            Ok(self.redirect(&authorize_url))
+        }
+    }
+
+    async fn handle_callback<State>(
+        &self,
+        discovered: &DiscoveredProvider,
+        mut req: Request<State>,
+    ) -> tide::Result
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let started = Instant::now();
+        let login = match self.verify_callback(discovered, &mut req).await {
+            Ok((claims, session_state, return_to)) => self
+                .store_login(&mut req, session_state)
+                .await
+                .map(|()| (claims, return_to)),
+            Err(error) => Err(error),
+        };
+        self.record_metric(MetricEvent::Callback {
+            duration: started.elapsed(),
+            success: login.is_ok(),
+        });
+        let (claims, return_to) = match login {
+            Ok(login) => login,
+            Err(OpenIdConnectError::ReplayedCallback) => {
+                // The login already completed (or failed); send the
+                // browser on to wherever it would be now, rather than
+                // showing an error for what is usually a press of the
+                // back button.
+                crate::log::event!(debug, "Ignoring replayed OpenID Connect callback.");
+                return Ok(
+                    match (self.is_authenticated(&req), self.callback_response) {
+                        (true, _) => self.callback_response(true, &self.login_landing_path),
+                        (false, CallbackResponse::Json) => {
+                            self.callback_response(false, &self.login_path)
+                        }
+                        (false, CallbackResponse::Redirect) => self.redirect_strategy.redirect(),
+                    },
+                );
+            }
+            Err(error) => {
+                if let Some(on_login_failure) = &self.on_login_failure {
+                    on_login_failure(&error);
+                }
+
+                // Logins that were rejected by the application are sent
+                // to the login rejected path, along with the reason for
+                // the rejection. Everything else is an error.
+                return match error {
+                    OpenIdConnectError::ClaimsRejected(message) => {
+                        crate::log::event!(
+                            warn,
+                            "OpenID Connect login rejected by the claims validator.",
+                            { reason: message }
+                        );
+                        req.session_mut().remove(&self.session_namespace);
+                        req.session_mut()
+                            .insert(&self.session_key(FLASH_SESSION_KEY), message)
+                            .map_err(|error| {
+                                let error =
+                                    OpenIdConnectError::SessionUnavailable(error.to_string());
+                                tide::http::Error::new(error.status(), error)
+                            })?;
+                        Ok(self.callback_response(
+                            false,
+                            self.login_rejected_path
+                                .as_ref()
+                                .unwrap_or(&self.logout_landing_path),
+                        ))
+                    }
+                    error => self.error_response(error),
+                };
+            }
+        };
+
+        if let Some(on_login) = &self.on_login {
+            on_login(&claims);
+        }
+
+        // The user has logged in; redirect them to the page that they
+        // asked to return to, or else to the main site.
+        Ok(self.callback_response(true, return_to.as_ref().unwrap_or(&self.login_landing_path)))
+    }
+
+    /// Stores the authenticated session state (which contains the user
+    /// id) in order to mark this session as authenticated, along with
+    /// the one-time values for the request after the login.
+    async fn store_login<State>(
+        &self,
+        req: &mut Request<State>,
+        session_state: MiddlewareSessionState,
+    ) -> Result<(), OpenIdConnectError>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let session_unavailable =
+            |error: serde_json::Error| OpenIdConnectError::SessionUnavailable(error.to_string());
+        let MiddlewareSessionState::PostAuth {
+            subject,
+            user_id,
+            session_id,
+            ..
+        } = &session_state;
+        let mut index_keys = vec![
+            IndexKey::User(user_id.clone().unwrap_or_else(|| subject.to_string())),
+            IndexKey::Subject(subject.to_string()),
+        ];
+        index_keys.extend(session_id.clone().map(IndexKey::ProviderSession));
+        self.store_session_state(req, session_state)
+            .map_err(|error| OpenIdConnectError::SessionUnavailable(error.to_string()))?;
+        if self.idle_timeout.is_some() {
+            req.session_mut()
+                .insert(&self.session_key(LAST_SEEN_SESSION_KEY), self.clock.now())
+                .map_err(session_unavailable)?;
+        }
+        match self.client_fingerprint(req) {
+            Some(fingerprint) => req
+                .session_mut()
+                .insert(&self.session_key(FINGERPRINT_SESSION_KEY), fingerprint)
+                .map_err(session_unavailable)?,
+            None => req
+                .session_mut()
+                .remove(&self.session_key(FINGERPRINT_SESSION_KEY)),
+        }
+        self.update_session_index(req, index_keys).await?;
+
+        // Let the next request know that the login just completed,
+        // and queue up the login flash message (if any) for that
+        // request as well. The login required message is no longer
+        // relevant.
+        req.session_mut()
+            .remove(&self.session_key(LOGIN_REQUIRED_SESSION_KEY));
+        req.session_mut()
+            .insert(&self.session_key(JUST_LOGGED_IN_SESSION_KEY), true)
+            .map_err(session_unavailable)?;
+        if let Some(login_flash) = &self.login_flash {
+            req.session_mut()
+                .insert(&self.session_key(FLASH_SESSION_KEY), login_flash)
+                .map_err(session_unavailable)?;
+        }
+
+        // Tide's session middleware only saves the session once the
+        // response has been produced, and so a failing store would
+        // otherwise go unnoticed here; check it with the login saved.
+        if let Some(session_index) = &self.session_index {
+            if let Err(error) = session_index.save_session(req.session()).await {
+                crate::log::event!(
+                    error,
+                    "Failed to save the session after an OpenID Connect login.",
+                    { error: error.clone() }
+                );
+                return Err(OpenIdConnectError::SessionUnavailable(error));
+            }
+        }
+        Ok(())
     }
 
-    async fn handle_callback<State>(&self, mut req: Request<State>) -> tide::Result
+    /// Completes the login process by validating the callback request,
+    /// exchanging the authorization code for a token, and verifying the
+    /// resulting ID token. Returns the ID token claims and the
+    /// authenticated session state.
+    async fn verify_callback<State>(
+        &self,
+        discovered: &DiscoveredProvider,
+        req: &mut Request<State>,
+    ) -> Result<(CoreIdTokenClaims, MiddlewareSessionState, Option<String>), OpenIdConnectError>
     where
         State: Clone + Send + Sync + 'static,
     {
-        // Get the middleware state from the session. If this fails then
-        // A) the browser got to the callback URL without actually going
-        // through the auth process, or B) more likely, the session
+        // The rest of the login process depends on the session.
+        if req.ext::<Session>().is_none() {
+            crate::log::event!(
+                error,
+                "Session is unavailable; make sure that SessionMiddleware is installed before OpenIdConnectMiddleware."
+            );
+            return Err(OpenIdConnectError::SessionUnavailable(
+                "SessionMiddleware is not installed".to_string(),
+            ));
+        }
+
+        // Get the pending logins from the session. If there are none
+        // then A) the browser got to the callback URL without actually
+        // going through the auth process, or B) more likely, the session
         // middleware is configured with Strict cookies instead of Lax
         // cookies. We cannot tell at this level which error occurred,
         // so we just reject the request and log the error.
-        if let Some(MiddlewareSessionState::PreAuth(csrf_token, nonce)) =
-            req.session().get(SESSION_KEY)
+        crate::log::event!(
+            debug,
+            "Processing OpenID Connect callback.",
+            { url: crate::log::redact_url(req.url()) }
+        );
+        let mut pending_logins = self.pending_logins(req);
+        if pending_logins.is_empty() {
+            crate::log::event!(
+                warn,
+                "Missing OpenID Connect state in session; make sure SessionMiddleware is configured with SameSite::Lax (but do *not* mutate server-side state on GET requests if you make that change!)."
+            );
+            return Err(OpenIdConnectError::MissingState);
+        }
+
+        // Extract the OpenID callback information -- from the query
+        // string, or from the form body if the provider used the
+        // `form_post` response mode.
+        #[derive(Deserialize)]
+        struct OpenIdCallback {
+            code: Option<AuthorizationCode>,
+            state: String,
+            error: Option<String>,
+            error_description: Option<String>,
+            id_token: Option<String>,
+        }
+        let callback_data: OpenIdCallback = if req.method() == Method::Post {
+            req.body_form().await
+        } else {
+            req.query()
+        }
+        .map_err(|error| OpenIdConnectError::InvalidCallback(error.to_string()))?;
+
+        // Find (and consume) the pending login that matches the CSRF
+        // state; each login can only be completed once, so the login is
+        // replaced with a consumed marker that identifies replays of the
+        // callback URL (from the back button, for example).
+        let pending_login = pending_logins.remove(&callback_data.state);
+        let replayed = matches!(&pending_login, Some(login) if login.consumed);
+        if let Some(login) = &pending_login {
+            pending_logins.insert(
+                callback_data.state.clone(),
+                PendingLogin {
+                    nonce: login.nonce.clone(),
+                    issued_at: login.issued_at,
+                    pkce_verifier: None,
+                    consumed: true,
+                    return_to: None,
+                },
+            );
+        }
+        if req
+            .session_mut()
+            .insert(&self.pending_logins_session_key(), pending_logins)
+            .is_err()
         {
-            // Extract the OpenID callback information and verify the CSRF
-            // state.
-            #[derive(Deserialize)]
-            struct OpenIdCallback {
-                code: AuthorizationCode,
-                state: String,
-            }
-            let callback_data: OpenIdCallback = req.query()?;
-            if &callback_data.state != csrf_token.secret() {
-                return Err(tide::http::Error::from_str(
-                    StatusCode::Unauthorized,
-                    "Invalid CSRF state.",
-                ));
+            req.session_mut().remove(&self.pending_logins_session_key());
+        }
+        if replayed {
+            return Err(OpenIdConnectError::ReplayedCallback);
+        }
+        let PendingLogin {
+            nonce,
+            issued_at,
+            pkce_verifier,
+            return_to,
+            ..
+        } = pending_login.ok_or(OpenIdConnectError::CsrfMismatch)?;
+
+        // Reject the callback if the browser took too long to complete
+        // the sign in process (or if the browser is replaying an old
+        // callback URL).
+        let age = self
+            .clock
+            .now()
+            .duration_since(issued_at)
+            .unwrap_or_default();
+        if age > self.timing_policy.authorize_state_lifetime() {
+            return Err(OpenIdConnectError::ExpiredState);
+        }
+
+        // The provider may have returned an error (for example, if the
+        // user declined to sign in) instead of an authorization code.
+        if let Some(error) = callback_data.error {
+            return Err(OpenIdConnectError::ProviderError {
+                error,
+                description: callback_data.error_description,
+            });
+        }
+        let code = callback_data.code.ok_or_else(|| {
+            OpenIdConnectError::InvalidCallback("missing field `code`".to_string())
+        })?;
+
+        // The hybrid flow also returns an ID token through the browser,
+        // which must be bound to the code before the code is exchanged.
+        let front_channel_claims = match &callback_data.id_token {
+            Some(id_token) => Some(
+                self.verify_front_channel_id_token(discovered, id_token, &code, &nonce)
+                    .await?,
+            ),
+            None => None,
+        };
+
+        // Exchange the code for a token.
+        let client_assertion = self
+            .client_assertion(discovered)
+            .map_err(OpenIdConnectError::TokenExchange)?;
+        let token_client = self.token_client(discovered);
+        let mut token_request = token_client.exchange_code(code);
+        if let Some(client_assertion) = client_assertion {
+            token_request = token_request
+                .add_extra_param("client_assertion_type", CLIENT_ASSERTION_TYPE)
+                .add_extra_param("client_assertion", client_assertion);
+        }
+        for resource in &self.resources {
+            token_request = token_request.add_extra_param("resource", resource.clone());
+        }
+        if let Some(pkce_verifier) = pkce_verifier {
+            token_request = token_request.set_pkce_verifier(pkce_verifier);
+        }
+        let started = Instant::now();
+        let token_response = token_request
+            .request_async(|request| async move {
+                let mut retries = 0;
+                let mut response = loop {
+                    let response =
+                        http_client_with_timeout(request.clone(), self.http_timeout).await;
+                    let retryable = match &response {
+                        Ok(response) => response.status_code.is_server_error(),
+                        Err(error) => error.is_network(),
+                    };
+                    if !retryable || retries >= self.token_exchange_retries {
+                        break response?;
+                    }
+
+                    retries += 1;
+                    crate::log::event!(
+                        warn,
+                        "OpenID Connect token exchange failed; retrying.",
+                        { retry: retries }
+                    );
+                    Timer::after(token_exchange_retry_delay(retries)).await;
+                };
+                if let Some(key) = &self.id_token_decryption_key {
+                    if response.status_code.is_success() {
+                        response.body = key
+                            .decrypt_token_response(response.body)
+                            .map_err(HttpClientError::IdTokenDecryption)?;
+                    }
+                }
+                Ok(response)
+            })
+            .await;
+        self.record_metric(MetricEvent::TokenExchange {
+            duration: started.elapsed(),
+        });
+        let token_response = token_response.map_err(|error| match error {
+            RequestTokenError::Request(error) if error.is_timeout() => {
+                OpenIdConnectError::ProviderTimeout
+            }
+            RequestTokenError::Request(HttpClientError::IdTokenDecryption(error)) => {
+                OpenIdConnectError::ClaimVerification(error.to_string())
             }
+            error => OpenIdConnectError::TokenExchange(error.to_string()),
+        })?;
 
-            // Exchange the code for a token.
-            let token_response = self
-                .client
-                .exchange_code(callback_data.code)
-                .request_async(http_client)
-                .await
-                .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))?;
+        // Make sure that the access token was issued for (one of) the
+        // APIs that we requested (if any).
+        if !self.resources.is_empty() {
+            validate_access_token_audience(token_response.access_token(), &self.resources)?;
+        }
 
-            // Get the claims and verify the nonce.
-            let claims = token_response
-                .extra_fields()
-                .id_token()
-                .ok_or_else(|| {
-                    tide::http::Error::from_str(
-                        StatusCode::InternalServerError,
-                        "OpenID Connect server did not return an ID token.",
-                    )
-                })?
-                .claims(&self.client.id_token_verifier(), &nonce)
-                .map_err(|error| tide::http::Error::new(StatusCode::Unauthorized, error))?;
-
-            // Get user info
-            let user_info_request = self
-                .client
-                .user_info(token_response.access_token().clone(), None)?;
-            let user_info: CoreUserInfoClaims =
-                user_info_request.request_async(http_client).await?;
-
-            // Add the user id to the session state in order to mark this
-            // session as authenticated.
-            req.session_mut()
-                .insert(
-                    SESSION_KEY,
-                    MiddlewareSessionState::PostAuth(
-                        claims.subject().clone(),
-                        token_response.access_token().clone(),
-                        token_response.scopes().unwrap_or(&self.scopes).clone(),
-                        user_info.standard_claims().clone(),
-                    ),
-                )
-                .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))?;
-
-            // The user has logged in; redirect them to the main site.
-            Ok(Redirect::new(&self.login_landing_path).into())
-        } else {
-            tide::log::warn!(
-                    "Missing OpenID Connect state in session; make sure SessionMiddleware is configured with SameSite::Lax (but do *not* mutate server-side state on GET requests if you make that change!)."
+        // Get the claims and verify the nonce.
+        let mut id_token_verifier = self.id_token_verifier(discovered).await;
+        if self.insecure_skip_signature_verification {
+            id_token_verifier = id_token_verifier.insecure_disable_signature_check();
+        }
+        let id_token = match token_response.extra_fields().id_token() {
+            Some(id_token) => id_token,
+            None => {
+                crate::log::event!(
+                    warn,
+                    "OpenID Connect server did not return an ID token; make sure that the provider supports OpenID Connect and that the client is configured for the \"openid\" scope."
                 );
-            Err(tide::http::Error::from_str(
-                StatusCode::InternalServerError,
-                "Missing authorization state.",
-            ))
+                return Err(OpenIdConnectError::MissingIdToken);
+            }
+        };
+        let nonce_verifier = |claims_nonce: Option<&Nonce>| match claims_nonce {
+            None if self.nonce_mode == NonceMode::Optional => Ok(()),
+            claims_nonce => (&nonce).verify(claims_nonce),
+        };
+        // A token that is signed with an unknown key may have been signed
+        // with a key that the provider rotated in since we last fetched
+        // its key set, in which case a fresh copy of the key set will
+        // contain the key.
+        let claims = match id_token.claims(&id_token_verifier, nonce_verifier) {
+            Err(ClaimsVerificationError::SignatureVerification(
+                SignatureVerificationError::NoMatchingKey,
+            )) if self.refetch_jwks_for_unknown_key(discovered).await => {
+                let id_token_verifier = self.id_token_verifier(discovered).await;
+                id_token.claims(&id_token_verifier, nonce_verifier)
+            }
+            claims => claims,
+        }
+        .map_err(id_token_error)?;
+        if self.verify_at_hash {
+            if let Some(expected_hash) = claims.access_token_hash() {
+                let actual_hash = id_token
+                    .signing_alg()
+                    .map_err(|error| error.to_string())
+                    .and_then(|alg| {
+                        AccessTokenHash::from_token(token_response.access_token(), &alg)
+                            .map_err(|error| error.to_string())
+                    })
+                    .map_err(OpenIdConnectError::ClaimVerification)?;
+                if actual_hash != *expected_hash {
+                    return Err(OpenIdConnectError::ClaimVerification(
+                        "Access token hash does not match the access token".to_string(),
+                    ));
+                }
+            }
+        }
+        if let Some(front_channel_claims) = &front_channel_claims {
+            if front_channel_claims.issuer() != claims.issuer()
+                || front_channel_claims.subject() != claims.subject()
+            {
+                return Err(OpenIdConnectError::ClaimVerification(
+                    "Front-channel ID token identifies a different user".to_string(),
+                ));
+            }
+        }
+        let id_token_claims = decode_jwt_claims(&id_token.to_string());
+        let session_id = id_token_claims.as_ref().and_then(|claims| {
+            claims
+                .get("sid")
+                .and_then(|sid| sid.as_str())
+                .map(str::to_string)
+        });
+
+        crate::log::event!(
+            debug,
+            "Verified OpenID Connect ID token.",
+            { issuer: claims.issuer().as_str(), subject: claims.subject().as_str() }
+        );
+
+        // Apply the application's own rules to the claims.
+        if let Some(claims_validator) = &self.claims_validator {
+            claims_validator(claims).map_err(OpenIdConnectError::ClaimsRejected)?;
+        }
+
+        // Get user info
+        let user_info_request = discovered
+            .client
+            .user_info(token_response.access_token().clone(), None)
+            .map_err(|error| OpenIdConnectError::UserInfo(error.to_string()))?;
+        let started = Instant::now();
+        let user_info: Result<CoreUserInfoClaims, _> = user_info_request
+            .request_async(|request| http_client_with_timeout(request, self.http_timeout))
+            .await;
+        self.record_metric(MetricEvent::UserInfo {
+            duration: started.elapsed(),
+        });
+        let user_info = user_info.map_err(|error| match error {
+            UserInfoError::Request(error) if error.is_timeout() => {
+                OpenIdConnectError::ProviderTimeout
+            }
+            error => OpenIdConnectError::UserInfo(error.to_string()),
+        })?;
+        if self.require_email_verified
+            && user_info
+                .email_verified()
+                .or_else(|| claims.email_verified())
+                != Some(true)
+        {
+            return Err(OpenIdConnectError::ClaimsRejected(
+                "Email address has not been verified.".to_string(),
+            ));
+        }
+        let user_id = match &self.user_id_claim {
+            Some(user_id_claim) => user_id_from_claims(
+                user_id_claim,
+                id_token_claims.as_ref(),
+                user_info.standard_claims(),
+            )?,
+            None => None,
+        };
+
+        // Calculate the absolute expiration time of the access token,
+        // and the time at which the user authenticated (which is
+        // either provided by the Identity Provider, or is right now).
+        let now = self.clock.now();
+        let expires_at = token_response
+            .expires_in()
+            .map(|expires_in| now + expires_in);
+        let authenticated_at = claims.auth_time().map_or(now, SystemTime::from);
+
+        // The provider may grant fewer (or different) scopes than we
+        // requested, in which case the token response lists the granted
+        // scopes. If the response does not list the scopes, then the
+        // provider granted exactly the scopes that we requested (which
+        // always includes "openid").
+        let scopes = match token_response.scopes() {
+            Some(scopes) => scopes.clone(),
+            None => std::iter::once(Scope::new("openid".to_string()))
+                .chain(self.requested_scopes())
+                .collect(),
+        };
+        if self.require_requested_scopes {
+            let missing_scopes: Vec<String> = self
+                .requested_scopes()
+                .into_iter()
+                .filter(|requested| !scopes.contains(requested))
+                .map(|scope| scope.to_string())
+                .collect();
+            if !missing_scopes.is_empty() {
+                return Err(OpenIdConnectError::ClaimsRejected(format!(
+                    "Requested scopes were not granted: {}",
+                    missing_scopes.join(" ")
+                )));
+            }
         }
+
+        Ok((
+            claims.clone(),
+            MiddlewareSessionState::PostAuth {
+                subject: claims.subject().clone(),
+                access_token: token_response.access_token().clone(),
+                scopes,
+                user_info: Box::new(self.project_session_claims(user_info.standard_claims())),
+                expires_at,
+                authenticated_at,
+                refresh_token: token_response.refresh_token().cloned(),
+                provider: self.provider.clone(),
+                issuer: Some(claims.issuer().clone()),
+                session_id,
+                id_token: Some(id_token.to_string()),
+                user_id,
+                logged_in_at: Some(now),
+                claims_refreshed_at: None,
+                enriched_claims: self.enrich_claims(claims),
+            },
+            return_to,
+        ))
+    }
+}
+
+/// Returns the time to wait before the given (1-based) retry of a failed
+/// token exchange.
+fn token_exchange_retry_delay(retry: u32) -> Duration {
+    2u32.checked_pow(retry.saturating_sub(1))
+        .and_then(|factor| TOKEN_EXCHANGE_RETRY_DELAY.checked_mul(factor))
+        .map_or(MAX_TOKEN_EXCHANGE_RETRY_DELAY, |delay| {
+            delay.min(MAX_TOKEN_EXCHANGE_RETRY_DELAY)
+        })
+}
+
+/// Returns `true` if the request's `Accept` header asks for JSON but
+/// not for HTML, which identifies requests from API clients (as opposed
+/// to browser navigations, which always accept HTML).
+fn is_api_request<State>(req: &Request<State>) -> bool {
+    let accept = match req.header(tide::http::headers::ACCEPT) {
+        Some(accept) => accept
+            .iter()
+            .map(|value| value.as_str().to_ascii_lowercase())
+            .collect::<Vec<_>>()
+            .join(","),
+        None => return false,
+    };
+    let media_types: Vec<&str> = accept
+        .split(',')
+        .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+        .collect();
+    let accepts_json = media_types
+        .iter()
+        .any(|media_type| *media_type == "application/json" || media_type.ends_with("+json"));
+    let accepts_html = media_types
+        .iter()
+        .any(|media_type| *media_type == "text/html" || *media_type == "application/xhtml+xml");
+    accepts_json && !accepts_html
+}
+
+/// Adds the ID token (if any) to the identity provider's logout URL as
+/// the `id_token_hint` query parameter, then applies the logout URL
+/// function (if any). Logout URLs that cannot be parsed are returned
+/// unchanged.
+fn idp_logout_redirect_url(
+    idp_logout_url: &str,
+    id_token: Option<String>,
+    logout_url_fn: Option<&LogoutUrlFn>,
+) -> String {
+    let mut url = match Url::parse(idp_logout_url) {
+        Ok(url) if id_token.is_some() || logout_url_fn.is_some() => url,
+        _ => return idp_logout_url.to_string(),
+    };
+    if let Some(id_token) = id_token {
+        url.query_pairs_mut()
+            .append_pair("id_token_hint", &id_token);
+    }
+    match logout_url_fn {
+        Some(logout_url_fn) => logout_url_fn(url).to_string(),
+        None => url.to_string(),
+    }
+}
+
+/// Removes the given prefix (such as the mount path) from a normalized
+/// path, returning the path unchanged if it is not below the prefix.
+fn strip_path_prefix(path: String, prefix: &str) -> String {
+    let prefix = normalize_path(prefix);
+    if prefix == "/" {
+        return path;
+    }
+
+    match path.strip_prefix(&prefix) {
+        Some("") => "/".to_string(),
+        Some(rest) if rest.starts_with('/') => rest.to_string(),
+        _ => path,
+    }
+}
+
+/// Normalizes a URL path so that equivalent paths compare as equal:
+/// percent-encoded characters are decoded, and trailing slashes are
+/// removed (except from the root path). Paths remain case-sensitive.
+fn normalize_path(path: &str) -> String {
+    let decoded = percent_decode_str(path).decode_utf8_lossy();
+    match decoded.trim_end_matches('/') {
+        "" => "/".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// Validates a login hint provided by the browser. The hint is
+/// URL-encoded when it is added to the authorize URL, but we still
+/// reject hints that could not possibly be a username, in order to keep
+/// arbitrary (and potentially huge) content out of the redirect.
+fn validate_login_hint(login_hint: String) -> tide::Result<LoginHint> {
+    if login_hint.is_empty()
+        || login_hint.len() > LOGIN_HINT_MAX_LEN
+        || login_hint.chars().any(char::is_control)
+    {
+        return Err(tide::http::Error::from_str(
+            StatusCode::BadRequest,
+            "Invalid login hint.",
+        ));
+    }
+
+    Ok(LoginHint::new(login_hint))
+}
+
+/// Validates the path to return to after login, which must be a path on
+/// this site: protocol-relative URLs (`//evil.example`) and backslashes
+/// (which browsers treat as slashes) would allow an open redirect.
+fn validate_return_to(return_to: String) -> tide::Result<String> {
+    if !return_to.starts_with('/')
+        || return_to.starts_with("//")
+        || return_to.contains('\\')
+        || return_to.len() > RETURN_TO_MAX_LEN
+        || return_to.chars().any(char::is_control)
+    {
+        return Err(tide::http::Error::from_str(
+            StatusCode::BadRequest,
+            "Invalid return path.",
+        ));
+    }
+
+    Ok(return_to)
+}
+
+/// Creates an HTML page that sends the browser to the given URL once
+/// the page (and its cookies) have been loaded.
+fn interstitial_redirect(url: &str) -> Response {
+    // The URL is embedded both in a JavaScript string, where we need to
+    // prevent it from closing the `<script>` element, and in an HTML
+    // attribute.
+    let js_url = serde_json::Value::from(url)
+        .to_string()
+        .replace("</", "<\\/");
+    let html_url = url
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+
+    Response::builder(StatusCode::Ok)
+        .header("Cache-Control", "no-store")
+        .content_type(mime::HTML)
+        .body(format!(
+            "<!DOCTYPE html><html><head><title>Signing in</title></head><body><script>window.location.replace({});</script><noscript><a href=\"{}\">Continue to sign in</a></noscript></body></html>",
+            js_url, html_url
+        ))
+        .build()
+}
+
+/// Converts an ID token verification failure into the corresponding
+/// login error.
+fn id_token_error(error: ClaimsVerificationError) -> OpenIdConnectError {
+    match error {
+        ClaimsVerificationError::InvalidNonce(_) => OpenIdConnectError::NonceMismatch,
+        ClaimsVerificationError::SignatureVerification(
+            SignatureVerificationError::NoMatchingKey,
+        ) => OpenIdConnectError::NoMatchingKey,
+        error => OpenIdConnectError::ClaimVerification(error.to_string()),
+    }
+}
+
+/// Returns the user's claims: the claims of the (already verified) ID
+/// token or bearer token, if any, updated with the user info claims from
+/// the session (if any) and then with the enriched claims.
+fn user_claims(
+    token_claims: Option<serde_json::Value>,
+    user_info: Option<&StandardClaims<CoreGenderClaim>>,
+    enriched_claims: HashMap<String, serde_json::Value>,
+) -> serde_json::Value {
+    let mut claims = match token_claims {
+        Some(serde_json::Value::Object(claims)) => claims,
+        _ => serde_json::Map::new(),
+    };
+    if let Some(Ok(serde_json::Value::Object(user_info))) = user_info.map(serde_json::to_value) {
+        claims.extend(user_info);
+    }
+    claims.extend(enriched_claims);
+    serde_json::Value::Object(claims)
+}
+
+/// Returns the value of the user id claim from the (already verified)
+/// ID token claims or, failing that, from the user info claims. Returns
+/// `None` if neither includes the claim.
+fn user_id_from_claims(
+    user_id_claim: &str,
+    id_token_claims: Option<&serde_json::Value>,
+    user_info: &StandardClaims<CoreGenderClaim>,
+) -> Result<Option<String>, OpenIdConnectError> {
+    let user_info = serde_json::to_value(user_info).unwrap_or_default();
+    let value = id_token_claims
+        .and_then(|claims| claims.get(user_id_claim))
+        .or_else(|| user_info.get(user_id_claim));
+    match value {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(user_id)) => Ok(Some(user_id.clone())),
+        Some(_) => Err(OpenIdConnectError::ClaimVerification(format!(
+            "The user id claim `{}` is not a string.",
+            user_id_claim
+        ))),
+    }
+}
+
+/// Decodes the payload of a JWT *without* verifying its signature.
+/// Returns `None` if the token is not a JWT.
+fn decode_jwt_claims(token: &str) -> Option<serde_json::Value> {
+    match token.split('.').collect::<Vec<_>>()[..] {
+        [_header, payload, _signature] => base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok()),
+        _ => None,
+    }
+}
+
+/// Validates that a JWT access token was issued for (one of) the given
+/// audiences. Opaque access tokens cannot be inspected, and so are always
+/// accepted.
+fn validate_access_token_audience(
+    access_token: &AccessToken,
+    audiences: &[String],
+) -> Result<(), OpenIdConnectError> {
+    // Decode the (unverified) payload of the token, if it is a JWT.
+    let claims = match decode_jwt_claims(access_token.secret()) {
+        Some(claims) => claims,
+        None => return Ok(()),
+    };
+
+    // The `aud` claim can either be a single audience, or an array of
+    // audiences.
+    let matches = match claims.get("aud") {
+        Some(serde_json::Value::String(aud)) => audiences.contains(aud),
+        Some(serde_json::Value::Array(auds)) => auds
+            .iter()
+            .any(|aud| matches!(aud.as_str(), Some(aud) if audiences.iter().any(|a| a == aud))),
+        _ => false,
+    };
+    if !matches {
+        return Err(OpenIdConnectError::AccessTokenAudience);
     }
+
+    Ok(())
 }
 
 #[tide::utils::async_trait]
@@ -377,19 +5112,80 @@ where
         // browser to the login URL. And if they are authenticated, then
         // just proceed to the handler (after populating the request extension
         // fields).
-        if req.method() == Method::Get && req.url().path() == self.login_path {
-            self.generate_redirect(req).await
-        } else if req.method() == Method::Get && req.url().path() == self.redirect_url.url().path()
+        // Behind a trusted proxy, the scheme of the request URL must
+        // already have been rewritten (before the session middleware
+        // decided whether the session cookie is Secure).
+        if self.trusted_proxy
+            && forwarded_proto(&req) == Some("https")
+            && req.url().scheme() != "https"
+        {
+            crate::log::event!(
+                error,
+                "Request arrived at the trusted proxy over https, but its URL uses http; install ForwardedProtoMiddleware before the session middleware."
+            );
+            // Keep the session middleware from issuing a session cookie
+            // that is not marked as Secure.
+            req.session_mut().destroy();
+            return Err(tide::http::Error::from_str(
+                StatusCode::InternalServerError,
+                "Request scheme was not rewritten for the trusted proxy.",
+            ));
+        }
+
+        let path = strip_path_prefix(normalize_path(req.url().path()), &self.scope_path);
+        if self.is_public_path(&path) {
+            // Public paths bypass the auth process entirely.
+            req.set_ext(OpenIdConnectRequestExtData::Unauthenticated {
+                redirect_strategy: self.unauthenticated_strategy(&req),
+                login_required_message: self.login_required_message.clone(),
+                login_required_session_key: self.session_key(LOGIN_REQUIRED_SESSION_KEY),
+            });
+            return Ok(next.run(req).await);
+        }
+
+        let discovered = self.discovered_provider().await?;
+        let is_login_path = path == normalize_path(&self.login_path);
+        let is_callback_path = path == self.callback_path();
+
+        if req.method() == self.login_method
+            && is_login_path
+            && self.relogin_behavior == ReloginBehavior::SkipIfAuthenticated
+            && self.is_authenticated(&req)
         {
-            self.handle_callback(req).await
-        } else if req.method() == Method::Get && req.url().path() == self.logout_path {
+            // The user is already logged in, so there is no need to go
+            // through the login process again.
+            let return_to = self.return_to(&req)?;
+            Ok(self.redirect(return_to.as_ref().unwrap_or(&self.login_landing_path)))
+        } else if req.method() == self.login_method && is_login_path {
+            let started = Instant::now();
+            let res = self.generate_redirect(&discovered, req).await;
+            self.record_metric(MetricEvent::AuthorizeRedirect {
+                duration: started.elapsed(),
+            });
+            res
+        } else if (req.method() == Method::Get || req.method() == Method::Post)
+            && is_callback_path
+            && self.intercept_callback
+        {
+            self.handle_callback(&discovered, req).await
+        } else if req.method() == Method::Get && path == normalize_path(&self.logout_path) {
+            // Grab the ID token (if any) before the session is cleared,
+            // so that it can be passed to the identity provider.
+            let id_token = match req.session().get(&self.session_namespace) {
+                Some(MiddlewareSessionState::PostAuth {
+                    provider, id_token, ..
+                }) if provider == self.provider => id_token,
+                _ => None,
+            };
+
             // Destroy the session as part of the logout, or clear only
             // the app state, depending on how the middleware has been
             // configured.
+            self.unindex_session(&mut req).await;
             if self.logout_destroys_session {
                 req.session_mut().destroy();
             } else {
-                req.session_mut().remove(SESSION_KEY);
+                req.session_mut().remove(&self.session_namespace);
             }
 
             // Redirect the user now that their authentication state has
@@ -398,34 +5194,167 @@ where
             // path if the app is not configured to log the user out of
             // the identity provider.
             if let Some(idp_logout_url) = &self.idp_logout_url {
-                Ok(Redirect::new(idp_logout_url).into())
+                Ok(self.redirect(idp_logout_redirect_url(
+                    idp_logout_url,
+                    id_token,
+                    self.logout_url_fn.as_deref(),
+                )))
             } else {
-                Ok(Redirect::new(&self.logout_landing_path).into())
+                Ok(self.redirect(&self.logout_landing_path))
+            }
+        } else if req.method() == Method::Get
+            && matches!(
+                &self.frontchannel_logout_path,
+                Some(frontchannel_logout_path) if path == normalize_path(frontchannel_logout_path)
+            )
+        {
+            self.frontchannel_logout(&discovered, req).await
+        } else if req.method() == Method::Post
+            && matches!(
+                &self.backchannel_logout_path,
+                Some(backchannel_logout_path) if path == normalize_path(backchannel_logout_path)
+            )
+        {
+            self.backchannel_logout(&discovered, req).await
+        } else if let Some(bearer_token) = self.bearer_token(&req) {
+            self.handle_bearer_request(&discovered, req, bearer_token, next)
+                .await
+        } else if let Err(invalid @ (InvalidSession::Rebound | InvalidSession::Idle)) =
+            self.check_session(req.session(), self.client_fingerprint(&req).as_deref())
+        {
+            // The session is being used by a different client or has
+            // been idle for too long; clear the authentication state and
+            // send the browser back through the login process. (Expired
+            // access tokens may still be refreshed below.)
+            if invalid == InvalidSession::Rebound {
+                crate::log::event!(
+                    info,
+                    "Client fingerprint does not match the authenticated session; clearing the authentication state."
+                );
+            }
+            req.session_mut().remove(&self.session_namespace);
+            req.session_mut()
+                .remove(&self.session_key(LAST_SEEN_SESSION_KEY));
+            req.session_mut()
+                .remove(&self.session_key(FINGERPRINT_SESSION_KEY));
+            if let Some(login_required_message) = &self.login_required_message {
+                req.session_mut()
+                    .insert(
+                        &self.session_key(LOGIN_REQUIRED_SESSION_KEY),
+                        login_required_message,
+                    )
+                    .map_err(|error| {
+                        tide::http::Error::new(StatusCode::InternalServerError, error)
+                    })?;
             }
+            Ok(self.unauthenticated_strategy(&req).redirect())
         } else {
+            self.record_activity(&mut req)?;
+            self.refresh_expired_token(&discovered, &mut req).await?;
+            self.refresh_stale_claims(&discovered, &mut req).await?;
+
             // Get the middleware's session state (which will *not* be
             // present if the browser has not yet gone through the auth
             // process), then augment the request with the authentication
-            // status.
-            match req.session().get(SESSION_KEY) {
-                Some(MiddlewareSessionState::PostAuth(
+            // status. Strict authentication also requires that the
+            // access token has not expired (which, at this point, means
+            // that it could not be refreshed).
+            let now = self.clock.now();
+            let granted_scopes = match self.session_state(&mut req) {
+                Some(MiddlewareSessionState::PostAuth {
                     subject,
                     access_token,
                     scopes,
                     user_info,
-                )) => req.set_ext(OpenIdConnectRequestExtData::Authenticated {
-                    user_id: subject.to_string(),
-                    access_token: access_token.secret().to_string(),
-                    scopes: scopes.iter().map(|s| s.to_string()).collect(),
-                    user_info: user_info.clone(),
-                }),
-                _ => req.set_ext(OpenIdConnectRequestExtData::Unauthenticated {
-                    redirect_strategy: self.redirect_strategy.clone(),
-                }),
+                    expires_at,
+                    authenticated_at,
+                    provider,
+                    issuer,
+                    id_token,
+                    user_id,
+                    enriched_claims,
+                    ..
+                }) if !self.is_token_expired(expires_at) => {
+                    let scopes: Vec<String> = scopes.iter().map(|s| s.to_string()).collect();
+                    let user = AuthenticatedUser::new(
+                        subject.to_string(),
+                        user_id,
+                        issuer
+                            .as_ref()
+                            .unwrap_or(&discovered.issuer_url)
+                            .to_string(),
+                        provider,
+                        &user_info,
+                    );
+                    self.trace_user(&user);
+                    req.set_ext(OpenIdConnectRequestExtData::Authenticated {
+                        user: Box::new(user),
+                        access_token: access_token.secret().to_string(),
+                        scopes: scopes.clone(),
+                        claims: Box::new(Claims::new(
+                            scopes.clone(),
+                            user_claims(
+                                id_token.as_deref().and_then(decode_jwt_claims),
+                                Some(&user_info),
+                                enriched_claims,
+                            ),
+                        )),
+                        user_info,
+                        expires_at,
+                        expires_in: expires_at
+                            .map(|expires_at| expires_at.duration_since(now).unwrap_or_default()),
+                        authenticated_at,
+                    });
+                    Some(GrantedScopes(scopes))
+                }
+                _ => {
+                    req.set_ext(OpenIdConnectRequestExtData::Unauthenticated {
+                        redirect_strategy: self.unauthenticated_strategy(&req),
+                        login_required_message: self.login_required_message.clone(),
+                        login_required_session_key: self.session_key(LOGIN_REQUIRED_SESSION_KEY),
+                    });
+                    None
+                }
             };
+            if let Some(granted_scopes) = &granted_scopes {
+                req.set_ext(granted_scopes.clone());
+            }
+
+            // Consume the one-time flash messages and login marker, if
+            // any are waiting in the session.
+            if let Some(flash) = req
+                .session()
+                .get::<String>(&self.session_key(FLASH_SESSION_KEY))
+            {
+                req.session_mut()
+                    .remove(&self.session_key(FLASH_SESSION_KEY));
+                req.set_ext(FlashMessage(flash));
+            }
+            if let Some(message) = req
+                .session()
+                .get::<String>(&self.session_key(LOGIN_REQUIRED_SESSION_KEY))
+            {
+                req.session_mut()
+                    .remove(&self.session_key(LOGIN_REQUIRED_SESSION_KEY));
+                req.set_ext(LoginRequiredMessage(message));
+            }
+            if req
+                .session()
+                .get::<bool>(&self.session_key(JUST_LOGGED_IN_SESSION_KEY))
+                .is_some()
+            {
+                req.session_mut()
+                    .remove(&self.session_key(JUST_LOGGED_IN_SESSION_KEY));
+                req.set_ext(JustLoggedIn);
+            }
 
-            // Call the downstream middleware.
-            Ok(next.run(req).await)
+            // Call the downstream middleware, then make the granted scopes
+            // available to the upstream middleware as well.
+            let mut res = next.run(req).await;
+            if let Some(granted_scopes) = granted_scopes {
+                res.insert_ext(granted_scopes);
+            }
+            Ok(res)
         }
     }
 }
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use openidconnect::url::{form_urlencoded, Url};
+use openidconnect::{ClientId, ClientSecret, HttpRequest};
+use serde::Deserialize;
+
+use crate::client_assertion::CLIENT_ASSERTION_TYPE;
+use crate::isahc::http_client_with_timeout;
+
+/// How the client authenticates itself to the pushed authorization
+/// request endpoint, which is the same way in which it authenticates
+/// itself to the token endpoint.
+pub(crate) enum ParClientAuth<'a> {
+    /// Public clients only identify themselves (with the `client_id`
+    /// parameter of the authorization request).
+    None,
+    /// HTTP Basic authentication (`client_secret_basic`).
+    Basic(&'a ClientSecret),
+    /// Client secret in the request body (`client_secret_post`).
+    Post(&'a ClientSecret),
+    /// Signed client assertion (`private_key_jwt`).
+    Assertion(String),
+}
+
+/// Pushes the parameters of the given authorization URL to the
+/// [pushed authorization request](https://www.rfc-editor.org/rfc/rfc9126)
+/// endpoint, returning the authorization URL that refers to the pushed
+/// parameters (by their `request_uri`) instead.
+pub(crate) async fn push_authorization_request(
+    par_url: &Url,
+    authorize_url: &Url,
+    client_id: &ClientId,
+    client_auth: ParClientAuth<'_>,
+    timeout: Duration,
+) -> Result<Url, String> {
+    #[derive(Deserialize)]
+    struct ParResponse {
+        request_uri: String,
+    }
+
+    let mut params: Vec<(String, String)> = authorize_url.query_pairs().into_owned().collect();
+    let mut headers = http::HeaderMap::new();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/x-www-form-urlencoded"),
+    );
+    headers.insert(
+        http::header::ACCEPT,
+        http::HeaderValue::from_static("application/json"),
+    );
+    match client_auth {
+        ParClientAuth::None => {}
+        ParClientAuth::Basic(client_secret) => {
+            // The credentials are form-encoded before they are combined
+            // (RFC 6749, Section 2.3.1).
+            let encode = |value: &str| -> String {
+                form_urlencoded::byte_serialize(value.as_bytes()).collect()
+            };
+            let credentials = format!(
+                "{}:{}",
+                encode(client_id.as_str()),
+                encode(client_secret.secret())
+            );
+            let authorization = format!("Basic {}", base64::encode(credentials));
+            headers.insert(
+                http::header::AUTHORIZATION,
+                http::HeaderValue::from_str(&authorization).map_err(|error| error.to_string())?,
+            );
+        }
+        ParClientAuth::Post(client_secret) => {
+            params.push(("client_secret".to_string(), client_secret.secret().clone()));
+        }
+        ParClientAuth::Assertion(client_assertion) => {
+            params.push((
+                "client_assertion_type".to_string(),
+                CLIENT_ASSERTION_TYPE.to_string(),
+            ));
+            params.push(("client_assertion".to_string(), client_assertion));
+        }
+    }
+    let body = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+
+    let response = http_client_with_timeout(
+        HttpRequest {
+            url: par_url.clone(),
+            method: http::Method::POST,
+            headers,
+            body: body.into_bytes(),
+        },
+        timeout,
+    )
+    .await
+    .map_err(|error| error.to_string())?;
+    if !response.status_code.is_success() {
+        return Err(format!("HTTP status code {}", response.status_code));
+    }
+    let par_response: ParResponse = serde_json::from_slice(&response.body)
+        .map_err(|error| format!("invalid response: {}", error))?;
+
+    let mut authorize_url = authorize_url.clone();
+    authorize_url
+        .query_pairs_mut()
+        .clear()
+        .append_pair("client_id", client_id.as_str())
+        .append_pair("request_uri", &par_response.request_uri);
+    Ok(authorize_url)
+}
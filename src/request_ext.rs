@@ -1,7 +1,9 @@
 use openidconnect::core::CoreGenderClaim;
 use openidconnect::StandardClaims;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
+use crate::authorization::Claims;
 use crate::redirect_strategy::RedirectStrategy;
 use tide::Request;
 
@@ -9,23 +11,128 @@ use tide::Request;
 pub trait OpenIdConnectRequestExt {
     /// Returns `true` if the request is authenticated, `false`
     /// otherwise.
+    ///
+    /// With [strict
+    /// authentication](crate::OpenIdConnectMiddleware::with_strict_authentication),
+    /// requests whose access token has expired (and could not be
+    /// refreshed) are not authenticated.
     fn is_authenticated(&self) -> bool;
 
     /// Gets the Identity Provider-specific access token for the
     /// authenticated user, or `None` if the session has not been
     /// authenticated.
+    ///
+    /// Expired access tokens are refreshed before the request is
+    /// processed if the Identity Provider issued a refresh token.
     fn access_token(&self) -> Option<String>;
 
     /// Gets the list of scopes authorized by/granted to the user, or
     /// `None` if the session has not been authenticated.
+    ///
+    /// These are the scopes that the Identity Provider actually granted,
+    /// which may be fewer than (or different from) the scopes that were
+    /// [requested](crate::OpenIdConnectMiddleware::with_scopes).
     fn scopes(&self) -> Option<Vec<String>>;
 
-    /// Gets the Identity Provider-specific user id of the authenticated
-    /// user, or `None` if the session has not been authenticated.
+    /// Gets the user id of the authenticated user, or `None` if the
+    /// session has not been authenticated.
+    ///
+    /// This is the [`user_id`](AuthenticatedUser::user_id) of the
+    /// [authenticated user](Self::user).
     fn user_id(&self) -> Option<String>;
 
+    /// Gets the authenticated user, or `None` if the session has not
+    /// been authenticated.
+    fn user(&self) -> Option<&AuthenticatedUser>;
+
     /// Gets the StandardClaims provided by the user_info endpoint
     fn user_info(&self) -> Option<StandardClaims<CoreGenderClaim>>;
+
+    /// Gets the time at which the access token expires, or `None` if the
+    /// session has not been authenticated or if the Identity Provider
+    /// did not specify the lifetime of the access token.
+    fn expires_at(&self) -> Option<SystemTime>;
+
+    /// Gets the remaining lifetime of the access token, as of the time
+    /// at which the request was received, or `None` if the session has
+    /// not been authenticated or if the Identity Provider did not
+    /// specify the lifetime of the access token. Expired access tokens
+    /// have a remaining lifetime of zero.
+    ///
+    /// Handlers can use this to decide whether the access token will
+    /// last for the duration of a long-running operation.
+    fn access_token_expires_in(&self) -> Option<Duration>;
+
+    /// Gets the time at which the user authenticated with the Identity
+    /// Provider, or `None` if the session has not been authenticated.
+    ///
+    /// This is the `auth_time` claim, if the Identity Provider includes
+    /// that claim in the ID token, otherwise it is the time at which
+    /// the login completed.
+    fn authenticated_at(&self) -> Option<SystemTime>;
+
+    /// Gets the one-time flash message that was queued up for this
+    /// request (for example, by a
+    /// [login flash](crate::OpenIdConnectMiddleware::with_login_flash)),
+    /// or `None` if there is no such message. The message is removed
+    /// from the session before the request is processed, and so will
+    /// not be available to subsequent requests.
+    fn flash(&self) -> Option<String>;
+
+    /// Gets the one-time [login required
+    /// message](crate::OpenIdConnectMiddleware::with_login_required_message)
+    /// that was queued up when a previous request was sent to the login
+    /// process, or `None` if there is no such message. As with
+    /// [`flash()`](Self::flash), the message is removed from the session
+    /// before the request is processed.
+    fn login_flash(&self) -> Option<String>;
+
+    /// Returns `true` if this is the first request after the user
+    /// completed the login process (for example, in order to display a
+    /// "welcome back" message exactly once), `false` otherwise.
+    fn just_logged_in(&self) -> bool;
+
+    /// Returns `true` if the authenticated user's claims include the
+    /// given claim with the given value -- or, for multi-valued claims
+    /// such as `groups` or `roles`, if the claim contains the given
+    /// value -- which allows handlers to make fine-grained
+    /// authorization checks inline. Returns `false` if the session has
+    /// not been authenticated.
+    ///
+    /// The claims are those returned by [`claims()`](Self::claims). See
+    /// [`Claims::has_claim`](crate::authorization::Claims::has_claim) for
+    /// the way in which values are compared.
+    fn is_authorized_for(&self, claim: &str, value: &str) -> bool;
+
+    /// Gets the scopes and claims of the authenticated user, or `None`
+    /// if the session has not been authenticated.
+    ///
+    /// The claims are those of the ID token, updated with the [session
+    /// claims](crate::OpenIdConnectMiddleware::with_session_claims) from
+    /// the user info (or, for [bearer
+    /// tokens](crate::OpenIdConnectMiddleware::with_bearer_token_auth),
+    /// the claims of the token), and then with the claims added by the
+    /// [claim enricher](crate::OpenIdConnectMiddleware::with_claim_enricher).
+    fn claims(&self) -> Option<&Claims>;
+
+    /// Gets the authenticated user's email address (the `email` claim),
+    /// or `None` if the session has not been authenticated or the user
+    /// does not have an email address.
+    ///
+    /// This and the other standard claim accessors read the
+    /// [`claims()`](Self::claims) of the user.
+    fn email(&self) -> Option<&str>;
+
+    /// Gets the authenticated user's full name (the `name` claim).
+    fn name(&self) -> Option<&str>;
+
+    /// Gets the URL of the authenticated user's profile picture (the
+    /// `picture` claim).
+    fn picture(&self) -> Option<&str>;
+
+    /// Gets the authenticated user's locale (the `locale` claim), such
+    /// as `en-US`.
+    fn locale(&self) -> Option<&str>;
 }
 
 impl<State> OpenIdConnectRequestExt for Request<State>
@@ -56,29 +163,205 @@ where
     }
 
     fn user_id(&self) -> Option<String> {
+        self.user().map(|user| user.user_id.clone())
+    }
+
+    fn user(&self) -> Option<&AuthenticatedUser> {
         match self.auth_state() {
-            OpenIdConnectRequestExtData::Authenticated { user_id, .. } => Some(user_id.clone()),
+            OpenIdConnectRequestExtData::Authenticated { user, .. } => Some(user.as_ref()),
             _ => None,
         }
     }
 
     fn user_info(&self) -> Option<StandardClaims<CoreGenderClaim>> {
         match self.auth_state() {
-            OpenIdConnectRequestExtData::Authenticated { user_info, .. } => Some(user_info.clone()),
+            OpenIdConnectRequestExtData::Authenticated { user_info, .. } => {
+                Some(user_info.as_ref().clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn expires_at(&self) -> Option<SystemTime> {
+        match self.auth_state() {
+            OpenIdConnectRequestExtData::Authenticated { expires_at, .. } => *expires_at,
+            _ => None,
+        }
+    }
+
+    fn access_token_expires_in(&self) -> Option<Duration> {
+        match self.auth_state() {
+            OpenIdConnectRequestExtData::Authenticated { expires_in, .. } => *expires_in,
+            _ => None,
+        }
+    }
+
+    fn authenticated_at(&self) -> Option<SystemTime> {
+        match self.auth_state() {
+            OpenIdConnectRequestExtData::Authenticated {
+                authenticated_at, ..
+            } => Some(*authenticated_at),
             _ => None,
         }
     }
+
+    fn flash(&self) -> Option<String> {
+        self.ext::<FlashMessage>()
+            .map(|FlashMessage(message)| message.clone())
+    }
+
+    fn login_flash(&self) -> Option<String> {
+        self.ext::<LoginRequiredMessage>()
+            .map(|LoginRequiredMessage(message)| message.clone())
+    }
+
+    fn just_logged_in(&self) -> bool {
+        self.ext::<JustLoggedIn>().is_some()
+    }
+
+    fn is_authorized_for(&self, claim: &str, value: &str) -> bool {
+        matches!(self.claims(), Some(claims) if claims.has_claim(claim, value))
+    }
+
+    fn claims(&self) -> Option<&Claims> {
+        match self.auth_state() {
+            OpenIdConnectRequestExtData::Authenticated { claims, .. } => Some(claims.as_ref()),
+            _ => None,
+        }
+    }
+
+    fn email(&self) -> Option<&str> {
+        string_claim(self.claims(), "email")
+    }
+
+    fn name(&self) -> Option<&str> {
+        string_claim(self.claims(), "name")
+    }
+
+    fn picture(&self) -> Option<&str> {
+        string_claim(self.claims(), "picture")
+    }
+
+    fn locale(&self) -> Option<&str> {
+        string_claim(self.claims(), "locale")
+    }
+}
+
+pub(crate) struct FlashMessage(pub(crate) String);
+
+pub(crate) struct LoginRequiredMessage(pub(crate) String);
+
+pub(crate) struct JustLoggedIn;
+
+/// Scopes granted to the authenticated user.
+///
+/// The middleware attaches this extension to every authenticated
+/// request *and* to the corresponding response, which makes the scopes
+/// available to logging middleware (for example, in order to include
+/// the scopes in access logs) regardless of whether that middleware
+/// runs before or after the OpenID Connect middleware. Unauthenticated
+/// requests and responses do not have this extension.
+///
+/// ```
+/// use tide_openidconnect::GrantedScopes;
+/// # let res = tide::Response::new(200);
+///
+/// if let Some(GrantedScopes(scopes)) = res.ext::<GrantedScopes>() {
+///     tide::log::info!("Request used scopes {:?}", scopes);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrantedScopes(pub Vec<String>);
+
+/// The authenticated user, as identified by the Identity Provider.
+///
+/// The subject and issuer together uniquely identify the user. The
+/// remaining fields are commonly-used profile claims from the user
+/// info; any of them may be missing, depending on the provider and the
+/// [requested scopes](crate::OpenIdConnectMiddleware::with_scopes). The
+/// complete set of claims is available from
+/// [`user_info`](OpenIdConnectRequestExt::user_info).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AuthenticatedUser {
+    /// Identity Provider-specific user id (the `sub` claim).
+    pub subject: String,
+
+    /// User id of the user: the value of the configured [user id
+    /// claim](crate::OpenIdConnectMiddleware::with_user_id_claim), or
+    /// the subject if the user does not have that claim.
+    pub user_id: String,
+
+    /// Issuer of the ID token (the `iss` claim).
+    pub issuer: String,
+
+    /// The user's email address.
+    pub email: Option<String>,
+
+    /// `true` if the Identity Provider has verified the email address.
+    pub email_verified: Option<bool>,
+
+    /// The user's full name, in the provider's default language (or in
+    /// the first language that the provider returned).
+    pub name: Option<String>,
+
+    /// The name by which the user prefers to be addressed, such as a
+    /// username.
+    pub preferred_username: Option<String>,
+
+    /// Name of the [provider](crate::OpenIdConnectMiddleware::with_provider)
+    /// that authenticated the user, or `None` if the middleware was not
+    /// given a provider name.
+    pub provider: Option<String>,
+}
+
+impl AuthenticatedUser {
+    pub(crate) fn new(
+        subject: String,
+        user_id: Option<String>,
+        issuer: String,
+        provider: Option<String>,
+        user_info: &StandardClaims<CoreGenderClaim>,
+    ) -> Self {
+        Self {
+            user_id: user_id.unwrap_or_else(|| subject.clone()),
+            subject,
+            issuer,
+            provider,
+            email: user_info.email().map(|email| email.to_string()),
+            email_verified: user_info.email_verified(),
+            name: user_info.name().and_then(|name| {
+                name.get(None)
+                    .or_else(|| name.iter().next().map(|(_, name)| name))
+                    .map(|name| name.to_string())
+            }),
+            preferred_username: user_info
+                .preferred_username()
+                .map(|username| username.to_string()),
+        }
+    }
+}
+
+/// Returns the value of the given claim if it is a string.
+fn string_claim<'a>(claims: Option<&'a Claims>, claim: &str) -> Option<&'a str> {
+    claims?.get(claim)?.as_str()
 }
 
 pub(crate) enum OpenIdConnectRequestExtData {
     Unauthenticated {
         redirect_strategy: Arc<dyn RedirectStrategy>,
+        login_required_message: Option<String>,
+        login_required_session_key: String,
     },
     Authenticated {
         access_token: String,
         scopes: Vec<String>,
-        user_id: String,
-        user_info: StandardClaims<CoreGenderClaim>,
+        user: Box<AuthenticatedUser>,
+        user_info: Box<StandardClaims<CoreGenderClaim>>,
+        claims: Box<Claims>,
+        expires_at: Option<SystemTime>,
+        expires_in: Option<Duration>,
+        authenticated_at: SystemTime,
     },
 }
 
@@ -1,5 +1,6 @@
 use crate::request_ext::{OpenIdConnectRequestExtData, OpenIdConnectRequestExtInternal};
-use tide::{Middleware, Next, Request, Route};
+use std::sync::Arc;
+use tide::{Middleware, Next, Request, Route, StatusCode};
 
 /// Authorization extensions to Tide [Route](tide::Route) handles.
 ///
@@ -59,12 +60,26 @@ pub trait OpenIdConnectRouteExt {
     /// route, redirecting the browser to the login page if the request
     /// is not authenticated.
     fn authenticated(&mut self) -> &mut Self;
+
+    /// Requires authentication *and* the given OAuth 2.0 scope (as
+    /// granted in the token response) on the subsequent portions of
+    /// this route. Unauthenticated requests are handled in the same way
+    /// as by [`authenticated()`](OpenIdConnectRouteExt::authenticated),
+    /// whereas authenticated requests without the scope are rejected
+    /// with a `403 Forbidden` response.
+    fn require_scope(&mut self, scope: &str) -> &mut Self;
 }
 
 impl<'a, State: Clone + Send + Sync + 'static> OpenIdConnectRouteExt for Route<'a, State> {
     fn authenticated(&mut self) -> &mut Self {
         self.with(MustAuthenticateMiddleware {})
     }
+
+    fn require_scope(&mut self, scope: &str) -> &mut Self {
+        self.with(RequireScopeMiddleware {
+            scope: scope.to_string(),
+        })
+    }
 }
 
 struct MustAuthenticateMiddleware;
@@ -80,15 +95,98 @@ where
         // the browser to the login page.
         match req.auth_state() {
             OpenIdConnectRequestExtData::Authenticated { .. } => {
-                tide::log::debug!(
+                crate::log::event!(
+                    debug,
                     "Authenticated request; forwarding request to next item in middleware chain."
                 );
                 Ok(next.run(req).await)
             }
-            OpenIdConnectRequestExtData::Unauthenticated { redirect_strategy } => {
-                tide::log::debug!("Unauthenticated request; redirecting browser to login page.");
-                Ok(redirect_strategy.redirect())
+            OpenIdConnectRequestExtData::Unauthenticated { .. } => redirect_to_login(req),
+        }
+    }
+}
+
+struct RequireScopeMiddleware {
+    scope: String,
+}
+
+#[tide::utils::async_trait]
+impl<State> Middleware<State> for RequireScopeMiddleware
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // Unauthenticated requests go through the login process; the
+        // scopes can only be checked once the user has logged in.
+        match req.auth_state() {
+            OpenIdConnectRequestExtData::Authenticated { scopes, .. }
+                if scopes.contains(&self.scope) =>
+            {
+                Ok(next.run(req).await)
             }
+            OpenIdConnectRequestExtData::Authenticated { .. } => {
+                crate::log::event!(
+                    debug,
+                    "Request is missing a required scope; rejecting request.",
+                    { scope: self.scope }
+                );
+                Err(tide::http::Error::from_str(
+                    StatusCode::Forbidden,
+                    "Missing required scope.",
+                ))
+            }
+            OpenIdConnectRequestExtData::Unauthenticated { .. } => redirect_to_login(req),
         }
     }
 }
+
+/// Sends an unauthenticated request through the login process, using
+/// the redirect strategy chosen by the middleware.
+fn redirect_to_login<State>(mut req: Request<State>) -> tide::Result
+where
+    State: Clone + Send + Sync + 'static,
+{
+    crate::log::event!(
+        debug,
+        "Unauthenticated request; redirecting browser to login page."
+    );
+    let (redirect_strategy, login_required_message, login_required_session_key) =
+        match req.auth_state() {
+            OpenIdConnectRequestExtData::Unauthenticated {
+                redirect_strategy,
+                login_required_message,
+                login_required_session_key,
+            } => (
+                Arc::clone(redirect_strategy),
+                login_required_message.clone(),
+                login_required_session_key.clone(),
+            ),
+            OpenIdConnectRequestExtData::Authenticated { .. } => {
+                unreachable!("only unauthenticated requests are redirected to the login page")
+            }
+        };
+    queue_login_required_message(
+        &mut req,
+        &login_required_session_key,
+        login_required_message,
+    )?;
+    Ok(redirect_strategy.redirect())
+}
+
+/// Queues up the login required message (if any) for the next request,
+/// which is usually the application's sign in page.
+fn queue_login_required_message<State>(
+    req: &mut Request<State>,
+    login_required_session_key: &str,
+    login_required_message: Option<String>,
+) -> tide::Result<()>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    if let Some(login_required_message) = login_required_message {
+        req.session_mut()
+            .insert(login_required_session_key, login_required_message)
+            .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))?;
+    }
+    Ok(())
+}
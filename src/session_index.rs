@@ -0,0 +1,206 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use async_lock::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tide::sessions::{Session, SessionStore};
+
+/// Key of the session data in which an index record keeps the ids of
+/// the indexed sessions.
+const SESSION_IDS_KEY: &str = "session_ids";
+
+/// Value under which a session is indexed.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) enum IndexKey {
+    /// The [user id](crate::AuthenticatedUser::user_id) of the session's
+    /// user.
+    User(String),
+    /// The subject of the session's user.
+    Subject(String),
+    /// The Identity Provider's session id (the `sid` claim) of the
+    /// session.
+    ProviderSession(String),
+}
+
+impl IndexKey {
+    /// Returns the kind of key along with its value.
+    fn parts(&self) -> (&'static str, &str) {
+        match self {
+            Self::User(user_id) => ("user", user_id),
+            Self::Subject(subject) => ("sub", subject),
+            Self::ProviderSession(session_id) => ("sid", session_id),
+        }
+    }
+}
+
+/// Object-safe subset of [`SessionStore`], which allows the index to
+/// hold on to whichever store the application uses.
+#[tide::utils::async_trait]
+trait IndexStore: Send + Sync {
+    async fn load(&self, cookie_value: String) -> Result<Option<Session>, String>;
+    async fn store(&self, session: Session) -> Result<(), String>;
+    async fn destroy(&self, session: Session) -> Result<(), String>;
+}
+
+#[tide::utils::async_trait]
+impl<Store> IndexStore for Store
+where
+    Store: SessionStore,
+{
+    async fn load(&self, cookie_value: String) -> Result<Option<Session>, String> {
+        self.load_session(cookie_value)
+            .await
+            .map_err(|error| error.to_string())
+    }
+
+    async fn store(&self, session: Session) -> Result<(), String> {
+        self.store_session(session)
+            .await
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+
+    async fn destroy(&self, session: Session) -> Result<(), String> {
+        self.destroy_session(session)
+            .await
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// Index from users (and provider sessions) to the ids of their
+/// sessions, which allows the middleware to find -- and destroy --
+/// sessions other than the one of the current request.
+///
+/// The index is kept in the application's session store itself, as one
+/// record (a session without a cookie) per key, and so it is shared by
+/// every server instance that uses the same store. Updates to a record
+/// are serialized within this process, but not across instances.
+pub(crate) struct SessionIndex {
+    store: Arc<dyn IndexStore>,
+    updates: Mutex<()>,
+}
+
+impl SessionIndex {
+    pub(crate) fn new<Store>(store: Store) -> Self
+    where
+        Store: SessionStore,
+    {
+        Self {
+            store: Arc::new(store),
+            updates: Mutex::new(()),
+        }
+    }
+
+    /// Adds the session to the sessions that are indexed under the key.
+    /// `scope` separates the records of different middleware instances.
+    pub(crate) async fn insert(
+        &self,
+        scope: &str,
+        key: &IndexKey,
+        session_id: &str,
+    ) -> Result<(), String> {
+        self.update(scope, key, |session_ids| {
+            session_ids.insert(session_id.to_string());
+        })
+        .await
+    }
+
+    /// Removes the session from the sessions that are indexed under the
+    /// key.
+    pub(crate) async fn remove(
+        &self,
+        scope: &str,
+        key: &IndexKey,
+        session_id: &str,
+    ) -> Result<(), String> {
+        self.update(scope, key, |session_ids| {
+            session_ids.remove(session_id);
+        })
+        .await
+    }
+
+    /// Destroys every session that is indexed under the key, along with
+    /// the key's record, returning the number of sessions.
+    pub(crate) async fn destroy_sessions(
+        &self,
+        scope: &str,
+        key: &IndexKey,
+    ) -> Result<usize, String> {
+        let _guard = self.updates.lock().await;
+        let record = match self.store.load(record_cookie_value(scope, key)).await? {
+            Some(record) => record,
+            None => return Ok(0),
+        };
+        let session_ids = record
+            .get::<BTreeSet<String>>(SESSION_IDS_KEY)
+            .unwrap_or_default();
+        for session_id in &session_ids {
+            self.store.destroy(session_with_id(session_id)?).await?;
+        }
+        self.store.destroy(record).await?;
+        Ok(session_ids.len())
+    }
+
+    /// Saves a copy of the session to the store, ahead of the session
+    /// middleware, so that a failing store is noticed while the request
+    /// can still be failed. The copy keeps the session's change tracking
+    /// intact for the session middleware.
+    pub(crate) async fn save_session(&self, session: &Session) -> Result<(), String> {
+        let copy = serde_json::to_value(session)
+            .and_then(serde_json::from_value)
+            .map_err(|error| error.to_string())?;
+        self.store.store(copy).await
+    }
+
+    async fn update(
+        &self,
+        scope: &str,
+        key: &IndexKey,
+        f: impl FnOnce(&mut BTreeSet<String>) + Send,
+    ) -> Result<(), String> {
+        let _guard = self.updates.lock().await;
+        let cookie_value = record_cookie_value(scope, key);
+        let mut record = match self.store.load(cookie_value.clone()).await? {
+            Some(record) => record,
+            None => session_with_id(
+                &Session::id_from_cookie_value(&cookie_value).map_err(|error| error.to_string())?,
+            )?,
+        };
+        let mut session_ids = record
+            .get::<BTreeSet<String>>(SESSION_IDS_KEY)
+            .unwrap_or_default();
+        f(&mut session_ids);
+        if session_ids.is_empty() {
+            self.store.destroy(record).await
+        } else {
+            record
+                .insert(SESSION_IDS_KEY, session_ids)
+                .map_err(|error| error.to_string())?;
+            self.store.store(record).await
+        }
+    }
+}
+
+/// Returns the (deterministic) cookie value under which the store keeps
+/// the record for the key.
+fn record_cookie_value(scope: &str, key: &IndexKey) -> String {
+    let (kind, value) = key.parts();
+    let mut hasher = Sha256::new();
+    for part in ["tide-openidconnect session index", scope, kind, value] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    base64::encode(hasher.finalize())
+}
+
+/// Returns an (empty) session with the given id, which is all that
+/// session stores need in order to store or destroy a session.
+fn session_with_id(session_id: &str) -> Result<Session, String> {
+    serde_json::from_value(serde_json::json!({
+        "id": session_id,
+        "expiry": null,
+        "data": {},
+    }))
+    .map_err(|error| error.to_string())
+}
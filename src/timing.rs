@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use crate::error::ConfigError;
+
+/// Time limits applied to the login flow.
+///
+/// The policy combines two related settings:
+///
+/// - The *authorize TTL*, which is how long the browser has to complete
+///   the sign in process at the Identity Provider. The CSRF state and
+///   nonce generated by the login route are rejected by the callback
+///   once they are older than this.
+/// - The *leeway*, which is a grace period that accounts for clock skew
+///   between this server and the Identity Provider. The leeway extends
+///   the authorize TTL, and is also used when checking the expiration
+///   time of ID tokens.
+///
+/// The leeway may not be longer than the authorize TTL, since that would
+/// allow the grace period to dominate the actual time limit.
+///
+/// # Defaults
+///
+/// - authorize TTL: 10 minutes
+/// - leeway: 60 seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingPolicy {
+    authorize_ttl: Duration,
+    leeway: Duration,
+}
+
+impl TimingPolicy {
+    /// Create a new policy.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ConfigError::InconsistentTimingPolicy`] if the leeway
+    /// is longer than the authorize TTL.
+    pub fn new(authorize_ttl: Duration, leeway: Duration) -> Result<Self, ConfigError> {
+        if leeway > authorize_ttl {
+            return Err(ConfigError::InconsistentTimingPolicy {
+                authorize_ttl,
+                leeway,
+            });
+        }
+
+        Ok(Self {
+            authorize_ttl,
+            leeway,
+        })
+    }
+
+    /// Returns the maximum amount of time that the browser has to
+    /// complete the sign in process at the Identity Provider.
+    pub fn authorize_ttl(&self) -> Duration {
+        self.authorize_ttl
+    }
+
+    /// Returns the grace period that accounts for clock skew between
+    /// this server and the Identity Provider.
+    pub fn leeway(&self) -> Duration {
+        self.leeway
+    }
+
+    /// Returns the age at which authorization state (the CSRF state and
+    /// nonce) is no longer accepted by the callback.
+    pub(crate) fn authorize_state_lifetime(&self) -> Duration {
+        self.authorize_ttl.saturating_add(self.leeway)
+    }
+}
+
+impl Default for TimingPolicy {
+    fn default() -> Self {
+        Self {
+            authorize_ttl: Duration::from_secs(10 * 60),
+            leeway: Duration::from_secs(60),
+        }
+    }
+}
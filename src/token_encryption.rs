@@ -0,0 +1,74 @@
+use std::convert::TryInto;
+
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use rand::RngCore;
+
+/// Length of the random AES-GCM nonce that precedes each ciphertext.
+const NONCE_LEN: usize = 12;
+
+/// Key used to encrypt the access and refresh tokens before they are
+/// stored in the session.
+///
+/// Each token is encrypted with AES-256-GCM under a random nonce, and is
+/// bound to the name of the session field in which it is stored (so that
+/// an access token cannot be swapped into the refresh token field, for
+/// example). The encrypted token is stored as the base64url encoding of
+/// the nonce followed by the ciphertext.
+#[derive(Clone)]
+pub(crate) struct TokenEncryptionKey([u8; 32]);
+
+impl std::fmt::Debug for TokenEncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("TokenEncryptionKey").finish_non_exhaustive()
+    }
+}
+
+impl TokenEncryptionKey {
+    pub(crate) fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// Encrypts the token that is stored in the given session field.
+    pub(crate) fn encrypt(&self, field: &str, token: &str) -> Option<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let ciphertext = Aes256Gcm::new(&self.0.into())
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: token.as_bytes(),
+                    aad: field.as_bytes(),
+                },
+            )
+            .ok()?;
+
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(ciphertext);
+        Some(base64::encode_config(encrypted, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Decrypts the token that was stored in the given session field,
+    /// returning `None` if the token was not encrypted with this key
+    /// (or for this field).
+    pub(crate) fn decrypt(&self, field: &str, encrypted: &str) -> Option<String> {
+        let encrypted = base64::decode_config(encrypted, base64::URL_SAFE_NO_PAD).ok()?;
+        if encrypted.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().ok()?;
+
+        let token = Aes256Gcm::new(&self.0.into())
+            .decrypt(
+                &nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: field.as_bytes(),
+                },
+            )
+            .ok()?;
+        String::from_utf8(token).ok()
+    }
+}
@@ -0,0 +1,102 @@
+use serde_json::json;
+
+use tide_openidconnect::authorization::{authorize, AuthzError, Claims, Requirements};
+
+fn claims() -> Claims {
+    Claims::new(
+        vec!["openid".to_string(), "reports:read".to_string()],
+        json!({
+            "sub": "id",
+            "email_verified": true,
+            "tenant_id": "acme",
+            "groups": ["staff", "reporting"],
+        }),
+    )
+}
+
+#[test]
+fn empty_requirements_authorize_everyone() {
+    assert_eq!(authorize(&claims(), &Requirements::new()), Ok(()));
+    assert_eq!(authorize(&Claims::default(), &Requirements::new()), Ok(()));
+}
+
+#[test]
+fn scopes_must_have_been_granted() {
+    assert_eq!(
+        authorize(&claims(), &Requirements::new().with_scope("reports:read")),
+        Ok(())
+    );
+    assert_eq!(
+        authorize(&claims(), &Requirements::new().with_scope("reports:write")),
+        Err(AuthzError::MissingScope("reports:write".to_string()))
+    );
+}
+
+#[test]
+fn claims_must_match_or_contain_the_value() {
+    // Single-valued claims (strings, booleans, numbers) must match.
+    assert_eq!(
+        authorize(
+            &claims(),
+            &Requirements::new().with_claim("tenant_id", "acme")
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        authorize(
+            &claims(),
+            &Requirements::new().with_claim("email_verified", "true")
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        authorize(
+            &claims(),
+            &Requirements::new().with_claim("tenant_id", "other")
+        ),
+        Err(AuthzError::MissingClaim {
+            claim: "tenant_id".to_string(),
+            value: "other".to_string(),
+        })
+    );
+
+    // Multi-valued claims must contain the value.
+    assert_eq!(
+        authorize(
+            &claims(),
+            &Requirements::new().with_claim("groups", "staff")
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        authorize(
+            &claims(),
+            &Requirements::new().with_claim("groups", "admin")
+        ),
+        Err(AuthzError::MissingClaim {
+            claim: "groups".to_string(),
+            value: "admin".to_string(),
+        })
+    );
+
+    // Missing claims never match.
+    assert!(authorize(&claims(), &Requirements::new().with_claim("roles", "staff")).is_err());
+}
+
+#[test]
+fn all_requirements_must_be_satisfied() {
+    let requirements = Requirements::new()
+        .with_scope("reports:read")
+        .with_claim("groups", "reporting")
+        .with_claim("tenant_id", "acme");
+    assert_eq!(authorize(&claims(), &requirements), Ok(()));
+
+    // Scopes are checked before claims.
+    let requirements = requirements
+        .with_claim("groups", "admin")
+        .with_scope("reports:write");
+    assert_eq!(
+        authorize(&claims(), &requirements),
+        Err(AuthzError::MissingScope("reports:write".to_string()))
+    );
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use surf::http::headers::LOCATION;
 
@@ -12,10 +12,11 @@ pub struct ParsedAuthorizeUrl {
     pub state: Option<String>,
     pub nonce: Option<String>,
     pub redirect_uri: String,
+    pub extra_params: BTreeMap<String, String>,
 }
 
-impl ParsedAuthorizeUrl {
-    pub fn default() -> Self {
+impl Default for ParsedAuthorizeUrl {
+    fn default() -> Self {
         Self {
             host: "localhost".to_owned(),
             path: "/authorization".to_owned(),
@@ -25,9 +26,12 @@ impl ParsedAuthorizeUrl {
             state: None,
             nonce: None,
             redirect_uri: "http://localhost/callback".to_string(),
+            extra_params: BTreeMap::new(),
         }
     }
+}
 
+impl ParsedAuthorizeUrl {
     pub fn from_response(res: &surf::Response) -> Self {
         Self::from_url(res.header(LOCATION).unwrap().get(0).unwrap().as_str())
     }
@@ -45,9 +49,30 @@ impl ParsedAuthorizeUrl {
             state: Some(query.get("state").unwrap().to_owned()),
             nonce: Some(query.get("nonce").unwrap().to_owned()),
             redirect_uri: query.get("redirect_uri").unwrap().to_owned(),
+            extra_params: query
+                .iter()
+                .filter(|(k, _)| {
+                    ![
+                        "response_type",
+                        "client_id",
+                        "scope",
+                        "state",
+                        "nonce",
+                        "redirect_uri",
+                    ]
+                    .contains(&k.as_str())
+                })
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
         }
     }
 
+    pub fn with_extra_param(mut self, name: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        self.extra_params
+            .insert(name.as_ref().to_owned(), value.as_ref().to_owned());
+        self
+    }
+
     pub fn with_nonce(self, nonce: Option<String>) -> Self {
         Self { nonce, ..self }
     }
@@ -0,0 +1,31 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tide_openidconnect::clock::Clock;
+
+/// Clock that starts at the current time, but then only moves forward
+/// when the test advances it.
+#[derive(Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(SystemTime::now())),
+        }
+    }
+}
+
+impl MockClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
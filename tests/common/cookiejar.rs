@@ -3,6 +3,7 @@ use std::sync::Arc;
 use async_lock::Mutex;
 use tide::http::headers::{COOKIE, SET_COOKIE};
 
+#[derive(Clone)]
 pub struct SessionCookieJarMiddleware {
     session_cookie: Arc<Mutex<Option<tide::http::Cookie<'static>>>>,
 }
@@ -1,9 +1,10 @@
 use http_types::{headers::LOCATION, StatusCode};
-use tide::sessions::{MemoryStore, SessionMiddleware};
+use tide::sessions::{MemoryStore, SessionMiddleware, SessionStore};
 
 use tide_openidconnect::{ClientId, ClientSecret, IssuerUrl, OpenIdConnectRequestExt, RedirectUrl};
 
 pub mod authorizeurl;
+pub mod clock;
 pub mod cookiejar;
 pub mod oidc_emulator;
 
@@ -20,6 +21,10 @@ pub fn get_config(issuer_url: &IssuerUrl) -> tide_openidconnect::Config {
 }
 
 pub fn create_test_server() -> tide::Server<()> {
+    create_test_server_with_store(MemoryStore::new())
+}
+
+pub fn create_test_server_with_store(store: impl SessionStore) -> tide::Server<()> {
     // Create the Tide server and our (required-by-OpenIdConnectMiddleware)
     // session middleware. We do *not* add the OpenIdConnectMiddleware
     // in this function; we let the caller do that so that it can configure
@@ -27,7 +32,7 @@ pub fn create_test_server() -> tide::Server<()> {
     let mut app = tide::new();
 
     app.with(
-        SessionMiddleware::new(MemoryStore::new(), &SECRET)
+        SessionMiddleware::new(store, &SECRET)
             .with_same_site_policy(tide::http::cookies::SameSite::Lax),
     );
 
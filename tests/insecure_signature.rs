@@ -0,0 +1,93 @@
+use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::cookiejar::SessionCookieJarMiddleware;
+use crate::common::oidc_emulator::OpenIdConnectEmulator;
+use crate::common::{assert_redirect, assert_response, create_test_server, get_config};
+use http_types::StatusCode;
+use std::sync::Mutex;
+use tide_testing::TideTestingExt;
+
+use tide_openidconnect::{OpenIdConnectMiddleware, RedirectUrl};
+
+pub mod common;
+
+/// Logger that keeps the messages of all warnings, so that tests can
+/// check for them. This is the only test in this file, since there can
+/// only be one logger per process.
+struct WarningLog(Mutex<Vec<String>>);
+
+impl log::Log for WarningLog {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static WARNINGS: WarningLog = WarningLog(Mutex::new(Vec::new()));
+
+#[async_std::test]
+async fn skipping_signature_verification_accepts_unverifiable_tokens() -> http_types::Result<()> {
+    log::set_logger(&WARNINGS).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_insecure_skip_signature_verification(true),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Enabling the option is loudly announced.
+            #[cfg(not(feature = "tracing"))]
+            assert!(WARNINGS
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|warning| warning.contains("signature verification is disabled")));
+
+            // The middleware does not know the key that signs the token
+            // (since it was rotated after the JWKS was cached), but the
+            // login succeeds regardless.
+            emu.rotate_signing_key().await;
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            assert_response(
+                &mut client.get("/").await?,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // The rest of the token is still verified.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token(
+                    "atoken",
+                    "openid",
+                    "id",
+                    &authorize_url.with_nonce(Some("BADNONCE".to_string())),
+                )
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,110 @@
+// Log records are only inspected through Tide's logger; the `tracing`
+// flavor of the events is not captured by this test.
+#![cfg(not(feature = "tracing"))]
+
+use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::cookiejar::SessionCookieJarMiddleware;
+use crate::common::oidc_emulator::OpenIdConnectEmulator;
+use crate::common::{assert_redirect, create_test_server};
+use openidconnect::url::form_urlencoded;
+use std::sync::Mutex;
+use tide_testing::TideTestingExt;
+
+use tide_openidconnect::{ClientId, OpenIdConnectMiddleware, RedirectUrl};
+
+pub mod common;
+
+/// Logger that keeps the `url` values of the middleware's log records,
+/// so that tests can check for them. This is the only test in this
+/// file, since there can only be one logger per process.
+struct UrlLog(Mutex<Vec<String>>);
+
+struct UrlVisitor<'a>(&'a Mutex<Vec<String>>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for UrlVisitor<'_> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        if key.as_str() == "url" {
+            self.0.lock().unwrap().push(value.to_string());
+        }
+        Ok(())
+    }
+}
+
+impl log::Log for UrlLog {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.target().starts_with("tide_openidconnect")
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            let _ = record.key_values().visit(&mut UrlVisitor(&self.0));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static URLS: UrlLog = UrlLog(Mutex::new(Vec::new()));
+
+#[async_std::test]
+async fn logged_urls_redact_the_login_secrets() -> http_types::Result<()> {
+    log::set_logger(&URLS).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            // Public clients use PKCE, and so the authorize URL contains
+            // all of the secrets.
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new_public(
+                    emu.issuer_url(),
+                    ClientId::new("CLIENT-ID".to_string()),
+                    RedirectUrl::new("http://localhost/callback".to_string()).unwrap(),
+                )
+                .await,
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(&callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Both the authorize URL and the callback URL were logged...
+            let urls = URLS.0.lock().unwrap().clone();
+            assert_eq!(urls.len(), 2, "unexpected URLs: {:?}", urls);
+            let (logged_authorize_url, logged_callback_url) = (&urls[0], &urls[1]);
+            assert!(logged_authorize_url.contains("client_id=CLIENT-ID"));
+            for param in ["state", "nonce", "code_challenge"] {
+                assert!(logged_authorize_url.contains(&format!("&{}=REDACTED", param)));
+            }
+            for param in ["state", "code"] {
+                assert!(logged_callback_url.contains(&format!("{}=REDACTED", param)));
+            }
+
+            // ...without any of the secrets themselves.
+            let state = authorize_url.state.unwrap();
+            let nonce = authorize_url.nonce.unwrap();
+            let (_, query) = callback_url.split_once('?').unwrap();
+            let code = form_urlencoded::parse(query.as_bytes())
+                .find(|(name, _)| name == "code")
+                .map(|(_, code)| code.into_owned())
+                .unwrap();
+            for url in &urls {
+                assert!(!url.contains(&state), "state leaked in {}", url);
+                assert!(!url.contains(&nonce), "nonce leaked in {}", url);
+                assert!(!url.contains(&code), "code leaked in {}", url);
+            }
+
+            Ok(())
+        })
+        .await
+}
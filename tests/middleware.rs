@@ -1,11 +1,35 @@
 use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::clock::MockClock;
 use crate::common::cookiejar::SessionCookieJarMiddleware;
-use crate::common::oidc_emulator::OpenIdConnectEmulator;
-use crate::common::{assert_redirect, assert_response, create_test_server, get_config};
-use http_types::StatusCode;
+use crate::common::oidc_emulator::{
+    OpenIdConnectEmulator, BACKCHANNEL_LOGOUT_EVENT, CLIENT_ASSERTION_KEY, ID_TOKEN_ENCRYPTION_KEY,
+    REGISTERED_CLIENT_SECRET, SESSION_ID,
+};
+use crate::common::{
+    assert_redirect, assert_response, create_test_server, create_test_server_with_store, get_config,
+};
+use http_types::{headers::LOCATION, Method, StatusCode};
+use openidconnect::core::{CoreResponseType, CoreSubjectIdentifierType};
+use openidconnect::registration::EmptyAdditionalClientMetadata;
+use openidconnect::url::{form_urlencoded, Url};
+use openidconnect::{
+    AuthUrl, EmptyAdditionalProviderMetadata, JsonWebKeySetUrl, ResponseTypes, TokenUrl,
+};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tide::sessions::{CookieStore, MemoryStore, Session, SessionMiddleware, SessionStore};
 use tide_testing::TideTestingExt;
 
-use tide_openidconnect::{OpenIdConnectMiddleware, RedirectUrl};
+use tide_openidconnect::{
+    AuthenticationFlow, CallbackResponse, ClientAssertionKey, ClientId, ClientSecret, ConfigError,
+    CoreClientAuthMethod, CoreClientRegistrationRequest, CoreJwsSigningAlgorithm,
+    CoreProviderMetadata, ForwardedProtoMiddleware, IdTokenDecryptionKey, IssuerUrl, MetricEvent,
+    NonceMode, OpenIdConnectConfig, OpenIdConnectError, OpenIdConnectMiddleware,
+    OpenIdConnectRequestExt, RedirectUrl, ReloginBehavior, ResponseMode, SessionBinding,
+    TimingPolicy,
+};
 
 pub mod common;
 
@@ -19,6 +43,74 @@ async fn middleware_can_be_initialized() -> tide::Result<()> {
         .await
 }
 
+#[async_std::test]
+async fn middleware_exposes_its_issuer_and_client_id() -> tide::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mw = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await;
+            assert_eq!(mw.issuer_url(), &emu.issuer_url());
+            assert_eq!(mw.client_id(), &ClientId::new("CLIENT-ID".to_string()));
+
+            // The values are available before the (lazy) discovery as well.
+            let mw = OpenIdConnectMiddleware::lazy(&get_config(&emu.issuer_url()));
+            assert_eq!(mw.issuer_url(), &emu.issuer_url());
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn health_check_reports_provider_connectivity() -> tide::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mw = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await;
+            assert_eq!(mw.health_check().await, Ok(()));
+
+            emu.fail_jwks_requests(1);
+            assert!(matches!(
+                mw.health_check().await,
+                Err(OpenIdConnectError::ProviderUnavailable(_))
+            ));
+            assert_eq!(mw.health_check().await, Ok(()));
+
+            // Lazy middleware reports failed discoveries as well.
+            emu.fail_discovery_requests(1);
+            let mw = OpenIdConnectMiddleware::lazy(&get_config(&emu.issuer_url()));
+            assert!(matches!(
+                mw.health_check().await,
+                Err(OpenIdConnectError::ProviderUnavailable(_))
+            ));
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn middleware_rejects_plain_session_stores() -> tide::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mw = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await;
+
+            // Server-side stores keep only the (signed) session id in the
+            // cookie, and are always accepted.
+            assert_eq!(mw.validate_session_store(&MemoryStore::new()), Ok(()));
+
+            // Cookie stores put the access token in the cookie, and are
+            // rejected by default...
+            assert!(matches!(
+                mw.validate_session_store(&CookieStore::new()),
+                Err(ConfigError::UnsignedSessionStore { .. })
+            ));
+
+            // ...unless the application explicitly allows them.
+            let mw = mw.with_require_signed_session(false);
+            assert_eq!(mw.validate_session_store(&CookieStore::new()), Ok(()));
+
+            Ok(())
+        })
+        .await
+}
+
 #[async_std::test]
 async fn middleware_provides_login_route() -> http_types::Result<()> {
     OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
@@ -210,29 +302,216 @@ async fn redirect_route_errors_on_missing_session_data() -> http_types::Result<(
         .await
 }
 
+/// Records the [`OpenIdConnectError`] (if any) attached to each response.
+#[derive(Clone, Default)]
+struct CallbackErrors(Arc<Mutex<Vec<OpenIdConnectError>>>);
+
+impl CallbackErrors {
+    fn take(&self) -> Vec<OpenIdConnectError> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+#[tide::utils::async_trait]
+impl tide::Middleware<()> for CallbackErrors {
+    async fn handle(&self, req: tide::Request<()>, next: tide::Next<'_, ()>) -> tide::Result {
+        let res = next.run(req).await;
+        if let Some(error) = res
+            .error()
+            .and_then(|error| error.downcast_ref::<OpenIdConnectError>())
+        {
+            self.0.lock().unwrap().push(error.clone());
+        }
+        Ok(res)
+    }
+}
+
 #[async_std::test]
-#[should_panic(
-    expected = "request session not initialized, did you enable tide::sessions::SessionMiddleware?"
-)]
-async fn redirect_route_panics_on_missing_session_middleware() {
-    let _result = OpenIdConnectEmulator::new(
-        RedirectUrl::new("http://localhost/callback".to_string()).unwrap(),
-    )
-    .run_with_emulator(|emu| async move {
-        let mut app = tide::new();
-        // Note: *No* session middleware was added to the server.
-        app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
-        let client = app.client().with(SessionCookieJarMiddleware::default());
+async fn redirect_route_errors_on_missing_session_middleware() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let errors = CallbackErrors::default();
+            let mut app = tide::new();
+            // Note: *No* session middleware was added to the server.
+            app.with(errors.clone());
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
 
-        // Make a request to the callback path, which should panic as
-        // soon as the OpenID Connect Middleware tries to access the
-        // request session.
-        let _res = client.get("/callback?code=12345&state=CSRFSTATE").await;
+            // Make a request to the callback path, which fails (instead
+            // of panicking) since there is no session.
+            let res = client.get("/callback?code=12345&state=CSRFSTATE").await?;
+            assert_eq!(res.status(), StatusCode::InternalServerError);
+            assert!(matches!(
+                errors.take()[..],
+                [OpenIdConnectError::SessionUnavailable(_)]
+            ));
 
-        // Unreachable, but required to satisfy `run_with_emulator`.
-        Ok(())
-    })
-    .await;
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn callback_failures_can_be_downcast_to_their_variants() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let errors = CallbackErrors::default();
+            let mut app = tide::new();
+            app.with(SessionMiddleware::new(
+                MemoryStore::new(),
+                b"tide-openidconnect-callback-failures-secret",
+            ));
+            app.with(errors.clone());
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_token_exchange_retries(0),
+            );
+            app.at("/").get(|_| async { Ok("Welcome") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Mismatched CSRF state.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let res = client
+                .get("/callback?code=12345&state=BADCSRFSTATE")
+                .await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert!(matches!(
+                errors.take()[..],
+                [OpenIdConnectError::CsrfMismatch]
+            ));
+
+            // Error returned by the provider.
+            let res = client
+                .get(format!(
+                    "/callback?error=access_denied&state={}",
+                    authorize_url.state.as_ref().unwrap()
+                ))
+                .await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert!(matches!(
+                &errors.take()[..],
+                [OpenIdConnectError::ProviderError { error, .. }] if error == "access_denied"
+            ));
+
+            // Unknown authorization code.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let res = client
+                .get(format!(
+                    "/callback?code=12345&state={}",
+                    authorize_url.state.as_ref().unwrap()
+                ))
+                .await?;
+            assert_eq!(res.status(), StatusCode::InternalServerError);
+            assert!(matches!(
+                errors.take()[..],
+                [OpenIdConnectError::TokenExchange(_)]
+            ));
+
+            // ID token with a different nonce.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token(
+                    "atoken",
+                    "openid",
+                    "id",
+                    &authorize_url.with_nonce(Some("BADNONCE".to_string())),
+                )
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert!(matches!(
+                errors.take()[..],
+                [OpenIdConnectError::NonceMismatch]
+            ));
+
+            // ID token signed with an unknown key.
+            emu.rotate_signing_key().await;
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert!(matches!(
+                errors.take()[..],
+                [OpenIdConnectError::NoMatchingKey]
+            ));
+
+            // Token response without an ID token.
+            emu.omit_id_tokens();
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::BadGateway);
+            assert!(matches!(
+                errors.take()[..],
+                [OpenIdConnectError::MissingIdToken]
+            ));
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn error_renderer_renders_callback_failures() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let errors = CallbackErrors::default();
+            let mut app = tide::new();
+            app.with(SessionMiddleware::new(
+                MemoryStore::new(),
+                b"tide-openidconnect-error-renderer-secret",
+            ));
+            app.with(errors.clone());
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_error_renderer(|error| {
+                        let mut res = tide::Response::new(error.status());
+                        res.set_body(
+                            tide::Body::from_json(&serde_json::json!({
+                                "error": error.to_string(),
+                            }))
+                            .unwrap(),
+                        );
+                        res
+                    }),
+            );
+            app.at("/").get(|_| async { Ok("Welcome") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The rendered response is returned for a mismatched CSRF state,
+            // and the error itself is still available to outer middleware.
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let mut res = client
+                .get("/callback?code=12345&state=BADCSRFSTATE")
+                .await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            let body: serde_json::Value = res.body_json().await?;
+            assert_eq!(
+                body,
+                serde_json::json!({
+                    "error": OpenIdConnectError::CsrfMismatch.to_string(),
+                })
+            );
+            assert!(matches!(
+                errors.take()[..],
+                [OpenIdConnectError::CsrfMismatch]
+            ));
+
+            Ok(())
+        })
+        .await
 }
 
 #[async_std::test]
@@ -296,63 +575,66 @@ async fn login_and_destructive_logout() -> http_types::Result<()> {
 }
 
 #[async_std::test]
-async fn login_and_auth_only_logout() -> http_types::Result<()> {
+async fn frontchannel_logout_clears_the_matching_session() -> http_types::Result<()> {
     OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
         .run_with_emulator(|emu| async move {
             let mut app = create_test_server();
             app.with(
                 OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
                     .await
-                    .with_logout_destroys_session(false),
+                    .with_frontchannel_logout_path("/frontchannel-logout"),
             );
             let client = app.client().with(SessionCookieJarMiddleware::default());
 
-            // Request our test route; this is the first (unauthenticated) visit.
-            let mut res = client.get("/").await?;
-            assert_response(&mut res, "unauthed visits=1").await;
-
-            // Login and confirm that we are told to redirect to the identity
-            // provider to begin the sign in process.
             let res = client.get("/login").await?;
-            assert_eq!(res.status(), StatusCode::Found);
             let authorize_url = ParsedAuthorizeUrl::from_response(&res);
-            assert_eq!(
-                authorize_url.clone().with_nonce(None).with_state(None),
-                ParsedAuthorizeUrl::default(),
-            );
-
-            // Simulate the sign in process in the identity provider by
-            // adding a token to our emulator.
             let callback_url = emu
                 .add_token("atoken", "openid", "id", &authorize_url)
                 .await;
-
-            // Complete the sign in process by redirecting back to our
-            // application's callback URL.
             let res = client.get(callback_url).await?;
             assert_redirect(&res, "/");
 
-            // Requesting our test route now shows that we are authenticated
-            // (and also that we retained the pre-login session state).
+            let frontchannel_logout_url = |iss: &str, sid: &str| {
+                format!(
+                    "/frontchannel-logout?{}",
+                    form_urlencoded::Serializer::new(String::new())
+                        .append_pair("iss", iss)
+                        .append_pair("sid", sid)
+                        .finish()
+                )
+            };
+
+            // Requests from a different issuer are rejected.
+            let res = client
+                .get(frontchannel_logout_url("https://example.com/", SESSION_ID))
+                .await?;
+            assert_eq!(res.status(), StatusCode::BadRequest);
+
+            // Requests for a different provider session are ignored.
+            let res = client
+                .get(frontchannel_logout_url(
+                    emu.issuer_url().as_str(),
+                    "other-session",
+                ))
+                .await?;
+            assert_eq!(res.status(), StatusCode::Ok);
             let mut res = client.get("/").await?;
             assert_response(
                 &mut res,
-                "authed visits=2 access_token=atoken scopes=[\"openid\"] userid=id",
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
             )
             .await;
 
-            // Log the user out of the application, which redirects us to
-            // the logout landing path.
-            let res = client.get("/logout").await?;
-            assert_redirect(&res, "/");
-
-            // Just as in the very beginning, navigating to our test route
-            // should show that the request is no longer authenticated.
-            // However, unlike the test that uses the default logout options,
-            // this test asks the middleware to clear only its auth state
-            // and so our "visits" counter was retained.
+            // Requests for the session's provider session log it out.
+            let res = client
+                .get(frontchannel_logout_url(
+                    emu.issuer_url().as_str(),
+                    SESSION_ID,
+                ))
+                .await?;
+            assert_eq!(res.status(), StatusCode::Ok);
             let mut res = client.get("/").await?;
-            assert_response(&mut res, "unauthed visits=3").await;
+            assert_response(&mut res, "unauthed visits=1").await;
 
             Ok(())
         })
@@ -360,35 +642,3668 @@ async fn login_and_auth_only_logout() -> http_types::Result<()> {
 }
 
 #[async_std::test]
-async fn logout_can_clear_idp_state() -> http_types::Result<()> {
-    // tide::log::with_level(tide::log::LevelFilter::Warn);
+async fn backchannel_logout_invalidates_the_matching_session() -> http_types::Result<()> {
     OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
         .run_with_emulator(|emu| async move {
-            let mut app = create_test_server();
-            let config = tide_openidconnect::Config {
-                idp_logout_url: Some("http://idp.logout".to_string()),
-                ..get_config(&emu.issuer_url())
+            let store = MemoryStore::new();
+            let mut app = create_test_server_with_store(store.clone());
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_backchannel_logout_path("/backchannel-logout")
+                    .with_session_store(store),
+            );
+
+            // Log two users in (to the same provider session).
+            let mut clients = vec![];
+            for (access_token, userid) in [("atoken", "id"), ("btoken", "other")] {
+                let client = app.client().with(SessionCookieJarMiddleware::default());
+                let res = client.get("/login").await?;
+                let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                let callback_url = emu
+                    .add_token(access_token, "openid", userid, &authorize_url)
+                    .await;
+                let res = client.get(callback_url).await?;
+                assert_redirect(&res, "/");
+                clients.push(client);
+            }
+
+            // The provider POSTs the logout token directly to the app
+            // (and so without the browser's session cookie).
+            let backchannel_logout = |logout_token: String| {
+                app.post("/backchannel-logout")
+                    .content_type("application/x-www-form-urlencoded")
+                    .body(
+                        form_urlencoded::Serializer::new(String::new())
+                            .append_pair("logout_token", &logout_token)
+                            .finish(),
+                    )
             };
-            app.with(OpenIdConnectMiddleware::new(&config).await);
+            let claims = |iss: &str, logged_out: serde_json::Value| {
+                let mut claims = serde_json::json!({
+                    "iss": iss,
+                    "aud": "CLIENT-ID",
+                    "iat": chrono::Utc::now().timestamp(),
+                    "jti": "logout-1",
+                    "events": { BACKCHANNEL_LOGOUT_EVENT: {} },
+                });
+                claims
+                    .as_object_mut()
+                    .unwrap()
+                    .extend(logged_out.as_object().unwrap().clone());
+                claims
+            };
+
+            // Tokens from a different issuer are rejected.
+            let logout_token = emu
+                .create_logout_token(claims(
+                    "https://example.com/",
+                    serde_json::json!({ "sid": SESSION_ID }),
+                ))
+                .await;
+            let res = backchannel_logout(logout_token).await?;
+            assert_eq!(res.status(), StatusCode::BadRequest);
+            let mut res = clients[0].get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // Valid tokens without a provider session destroy the
+            // user's sessions...
+            let logout_token = emu
+                .create_logout_token(claims(
+                    emu.issuer_url().as_str(),
+                    serde_json::json!({ "sub": "other" }),
+                ))
+                .await;
+            let res = backchannel_logout(logout_token).await?;
+            assert_eq!(res.status(), StatusCode::Ok);
+            let mut res = clients[1].get("/").await?;
+            assert_response(&mut res, "unauthed visits=1").await;
+            let mut res = clients[0].get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=2 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // ...and otherwise the provider session's sessions.
+            let logout_token = emu
+                .create_logout_token(claims(
+                    emu.issuer_url().as_str(),
+                    serde_json::json!({ "sid": SESSION_ID }),
+                ))
+                .await;
+            let res = backchannel_logout(logout_token).await?;
+            assert_eq!(res.status(), StatusCode::Ok);
+            let mut res = clients[0].get("/").await?;
+            assert_response(&mut res, "unauthed visits=1").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn backchannel_logout_requires_the_session_store() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_backchannel_logout_path("/backchannel-logout"),
+            );
+
+            // Even valid tokens are rejected, since the sessions cannot
+            // be found.
+            let logout_token = emu
+                .create_logout_token(serde_json::json!({
+                    "iss": emu.issuer_url().as_str(),
+                    "aud": "CLIENT-ID",
+                    "iat": chrono::Utc::now().timestamp(),
+                    "jti": "logout-1",
+                    "events": { BACKCHANNEL_LOGOUT_EVENT: {} },
+                    "sid": SESSION_ID,
+                }))
+                .await;
+            let res = app
+                .post("/backchannel-logout")
+                .content_type("application/x-www-form-urlencoded")
+                .body(
+                    form_urlencoded::Serializer::new(String::new())
+                        .append_pair("logout_token", &logout_token)
+                        .finish(),
+                )
+                .await?;
+            assert_eq!(res.status(), StatusCode::BadRequest);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn session_cookie_domain_is_used_for_the_login() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = tide::new();
+            app.with(
+                SessionMiddleware::new(MemoryStore::new(), b"secrets must be >= 32 bytes long")
+                    .with_cookie_domain("example.com")
+                    .with_same_site_policy(tide::http::cookies::SameSite::Lax),
+            );
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
             let client = app.client().with(SessionCookieJarMiddleware::default());
 
-            // Log the user in.
+            // The login state is kept in the session cookie, which is
+            // shared with the parent domain...
             let res = client.get("/login").await?;
-            assert_eq!(res.status(), StatusCode::Found);
-            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let session_cookie =
+                tide::http::Cookie::parse(res.header("Set-Cookie").unwrap().to_string())?;
+            assert_eq!(session_cookie.domain(), Some("example.com"));
+            assert_eq!(res.header("Set-Cookie").unwrap().iter().count(), 1);
 
+            // ...and the session cookie remains the only cookie once the
+            // login has completed.
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
             let callback_url = emu
                 .add_token("atoken", "openid", "id", &authorize_url)
                 .await;
-
             let res = client.get(callback_url).await?;
             assert_redirect(&res, "/");
+            let set_cookies: Vec<_> = res
+                .header("Set-Cookie")
+                .map(|values| values.iter().map(|value| value.to_string()).collect())
+                .unwrap_or_default();
+            assert!(set_cookies
+                .iter()
+                .all(|set_cookie| set_cookie.starts_with("tide.sid=")
+                    && set_cookie.contains("Domain=example.com")));
 
-            // Now log out; the default configuration would take us back
-            // to the logout landing path, but in this test we have enabled
-            // IdP logout and so we are redirected to that URL instead.
-            let res = client.get("/logout").await?;
-            assert_redirect(&res, "http://idp.logout");
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn login_state_is_kept_in_the_session() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_random_source({
+                        let counter = AtomicUsize::new(0);
+                        move || format!("random{}", counter.fetch_add(1, Ordering::SeqCst))
+                    }),
+            );
+            let client = app.client();
+
+            // The authorize URL only contains an opaque state, which is
+            // not related to the nonce...
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(authorize_url.state.as_deref(), Some("random0"));
+            assert_eq!(authorize_url.nonce.as_deref(), Some("random1"));
+
+            // ...and the only cookie that needs to survive the round trip
+            // through the Identity Provider is the session cookie.
+            let session_cookie = tide::http::Cookie::parse(
+                res.header("Set-Cookie")
+                    .unwrap()
+                    .get(0)
+                    .unwrap()
+                    .to_string(),
+            )?;
+            assert_eq!(session_cookie.name(), "tide.sid");
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client
+                .get(callback_url)
+                .header(
+                    "Cookie",
+                    format!("{}={}", session_cookie.name(), session_cookie.value()),
+                )
+                .await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn destructive_logout_removes_the_session_cookie() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let session_cookie =
+                tide::http::Cookie::parse(res.header("Set-Cookie").unwrap().to_string())?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Browsers only delete a cookie if the removal cookie has the
+            // same name and path as the original cookie (and an expiry in
+            // the past).
+            let res = client.get("/logout").await?;
+            assert_redirect(&res, "/");
+            let removal_cookie =
+                tide::http::Cookie::parse(res.header("Set-Cookie").unwrap().to_string())?;
+            assert_eq!(removal_cookie.name(), session_cookie.name());
+            assert_eq!(removal_cookie.path(), session_cookie.path());
+            assert_eq!(removal_cookie.value(), "");
+            assert_eq!(removal_cookie.max_age(), Some(time::Duration::zero()));
+            assert!(removal_cookie.expires().unwrap() < time::OffsetDateTime::now_utc());
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn login_and_auth_only_logout() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_logout_destroys_session(false),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Request our test route; this is the first (unauthenticated) visit.
+            let mut res = client.get("/").await?;
+            assert_response(&mut res, "unauthed visits=1").await;
+
+            // Login and confirm that we are told to redirect to the identity
+            // provider to begin the sign in process.
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(
+                authorize_url.clone().with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default(),
+            );
+
+            // Simulate the sign in process in the identity provider by
+            // adding a token to our emulator.
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+
+            // Complete the sign in process by redirecting back to our
+            // application's callback URL.
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Requesting our test route now shows that we are authenticated
+            // (and also that we retained the pre-login session state).
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=2 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // Log the user out of the application, which redirects us to
+            // the logout landing path.
+            let res = client.get("/logout").await?;
+            assert_redirect(&res, "/");
+
+            // Just as in the very beginning, navigating to our test route
+            // should show that the request is no longer authenticated.
+            // However, unlike the test that uses the default logout options,
+            // this test asks the middleware to clear only its auth state
+            // and so our "visits" counter was retained.
+            let mut res = client.get("/").await?;
+            assert_response(&mut res, "unauthed visits=3").await;
+
+            Ok(())
+        })
+        .await
+}
+
+/// Installs a middleware that is shared with the application's route
+/// handlers.
+struct SharedMiddleware(Arc<OpenIdConnectMiddleware>);
+
+#[tide::utils::async_trait]
+impl tide::Middleware<()> for SharedMiddleware {
+    async fn handle(&self, req: tide::Request<()>, next: tide::Next<'_, ()>) -> tide::Result {
+        tide::Middleware::handle(&*self.0, req, next).await
+    }
+}
+
+#[async_std::test]
+async fn login_can_be_completed_by_the_application() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let middleware = Arc::new(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_intercept_callback(false),
+            );
+            let mut app = create_test_server();
+            app.with(SharedMiddleware(Arc::clone(&middleware)));
+            app.at("/callback").get(move |mut req: tide::Request<()>| {
+                let middleware = Arc::clone(&middleware);
+                async move {
+                    Ok(match middleware.complete_login(&mut req).await {
+                        Ok(user) => format!("welcome {}", user.subject),
+                        Err(OpenIdConnectError::ReplayedCallback) => "replayed".to_string(),
+                        Err(error) => format!("failed: {}", error),
+                    })
+                }
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The application's handler responds to the callback...
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            assert_response(&mut client.get(&callback_url).await?, "welcome id").await;
+
+            // ...after the login has been stored in the session...
+            assert_response(
+                &mut client.get("/").await?,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // ...and each callback can only be completed once.
+            assert_response(&mut client.get(&callback_url).await?, "replayed").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn logout_can_clear_idp_state() -> http_types::Result<()> {
+    // tide::log::with_level(tide::log::LevelFilter::Warn);
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            let config = tide_openidconnect::Config {
+                idp_logout_url: Some("http://idp.logout".to_string()),
+                ..get_config(&emu.issuer_url())
+            };
+            app.with(OpenIdConnectMiddleware::new(&config).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Log the user in.
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Now log out; the default configuration would take us back
+            // to the logout landing path, but in this test we have enabled
+            // IdP logout and so we are redirected to that URL instead,
+            // along with the ID token as a hint.
+            let res = client.get("/logout").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let location = res.header(LOCATION).unwrap().get(0).unwrap().to_string();
+            let (idp_logout_url, query) = location.split_once('?').unwrap();
+            assert_eq!(idp_logout_url, "http://idp.logout/");
+            let id_token_hint = match form_urlencoded::parse(query.as_bytes()).collect::<Vec<_>>()[..]
+            {
+                [(ref name, ref value)] if name == "id_token_hint" => value.to_string(),
+                _ => panic!("Expected only an ID token hint, found `{}` instead.", query),
+            };
+            let claims: serde_json::Value = serde_json::from_slice(
+                &base64::decode_config(
+                    id_token_hint.split('.').nth(1).unwrap(),
+                    base64::URL_SAFE_NO_PAD,
+                )
+                .unwrap(),
+            )?;
+            assert_eq!(claims["sub"], "id");
+            assert_eq!(claims["aud"], serde_json::json!(["CLIENT-ID"]));
+
+            // Logging out without a session has no ID token to pass on.
+            let res = client.get("/logout").await?;
+            assert_redirect(&res, "http://idp.logout");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn logout_url_can_be_adapted_to_the_provider() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            let config = tide_openidconnect::Config {
+                idp_logout_url: Some("http://idp.logout/v2/logout".to_string()),
+                ..get_config(&emu.issuer_url())
+            };
+            app.with(
+                OpenIdConnectMiddleware::new(&config)
+                    .await
+                    .with_logout_url_fn(|mut url| {
+                        // Replace the standard parameters with the ones
+                        // that this (Auth0-like) provider expects.
+                        let had_id_token_hint =
+                            url.query_pairs().any(|(name, _)| name == "id_token_hint");
+                        url.query_pairs_mut()
+                            .clear()
+                            .append_pair("returnTo", "http://localhost/")
+                            .append_pair("client_id", "CLIENT-ID")
+                            .append_pair("hinted", &had_id_token_hint.to_string());
+                        url
+                    }),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let res = client.get("/logout").await?;
+            assert_redirect(
+                &res,
+                "http://idp.logout/v2/logout?returnTo=http%3A%2F%2Flocalhost%2F&client_id=CLIENT-ID&hinted=true",
+            );
+
+            // The function also applies to logouts without an ID token.
+            let res = client.get("/logout").await?;
+            assert_redirect(
+                &res,
+                "http://idp.logout/v2/logout?returnTo=http%3A%2F%2Flocalhost%2F&client_id=CLIENT-ID&hinted=false",
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn rotated_signing_keys_require_jwks_refresh() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Rotate the provider's signing key *after* the middleware has
+            // cached the JWKS.
+            emu.rotate_signing_key().await;
+
+            // The login fails, because the (default) refresh interval has
+            // not elapsed and so the middleware does not know about the
+            // new key.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn rotated_signing_keys_verify_after_jwks_refresh() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_jwks_refresh_interval(Duration::ZERO),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Rotate the provider's signing key *after* the middleware has
+            // cached the JWKS.
+            emu.rotate_signing_key().await;
+
+            // The cached JWKS is always stale, so the middleware re-fetches
+            // the key set during the callback and verifies the token that
+            // was signed with the new key.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn jwks_refresh_interval_follows_the_clock() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let clock = MockClock::default();
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_clock(clock.clone())
+                    .with_jwks_refresh_interval(Duration::from_secs(10 * 60)),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The middleware does not know about the rotated key until the
+            // refresh interval has elapsed...
+            emu.rotate_signing_key().await;
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            // ...which it has as soon as the clock says so.
+            clock.advance(Duration::from_secs(11 * 60));
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn unknown_signing_keys_trigger_a_jwks_refetch() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let clock = MockClock::default();
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_clock(clock.clone()),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The cached key set does not contain the rotated key, but the
+            // re-fetched key set does -- well before the refresh interval
+            // has elapsed.
+            emu.rotate_signing_key().await;
+            clock.advance(Duration::from_secs(2 * 60));
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn empty_jwks_fails_logins_with_no_matching_key() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            emu.publish_empty_jwks();
+            let errors = CallbackErrors::default();
+            let mut app = tide::new();
+            app.with(SessionMiddleware::new(
+                MemoryStore::new(),
+                b"tide-openidconnect-empty-jwks-secret",
+            ));
+            app.with(errors.clone());
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            app.at("/").get(|_| async { Ok("Welcome") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The ID token cannot be verified without any keys, and the
+            // failure says so (instead of being a generic verification
+            // failure).
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert_eq!(errors.take(), vec![OpenIdConnectError::NoMatchingKey]);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn acr_values_and_login_hint_are_added_to_authorize_url() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_acr_values(&["mfa", "phr"]),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The ACR values are added to every authorize URL.
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(
+                authorize_url.with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default().with_extra_param("acr_values", "mfa phr"),
+            );
+
+            // The login hint is passed through from the login request
+            // (and is properly encoded in the authorize URL).
+            let res = client
+                .get("/login?login_hint=user%2Btag%40example.com%26prompt%3Dnone")
+                .await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(
+                authorize_url.with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default()
+                    .with_extra_param("acr_values", "mfa phr")
+                    .with_extra_param("login_hint", "user+tag@example.com&prompt=none"),
+            );
+
+            // Login hints that could not possibly be usernames are rejected.
+            let res = client.get("/login?login_hint=user%0Aname").await?;
+            assert_eq!(res.status(), StatusCode::BadRequest);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn offline_access_is_added_to_authorize_url() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_scopes(&["profile"])
+                    .with_offline_access(true),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(
+                authorize_url.with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default()
+                    .with_scopes("openid profile offline_access")
+                    .with_extra_param("prompt", "consent"),
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn extra_authorize_params_are_added_to_authorize_url() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_extra_authorize_params(vec![
+                        ("organization".to_string(), "org_123".to_string()),
+                        ("invitation".to_string(), "a b&c=d".to_string()),
+                    ]),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(
+                authorize_url.with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default()
+                    .with_extra_param("organization", "org_123")
+                    .with_extra_param("invitation", "a b&c=d"),
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn random_source_generates_the_state_and_nonce() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let calls_in_source = Arc::clone(&calls);
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_random_source(move || {
+                        let call = calls_in_source.fetch_add(1, Ordering::SeqCst);
+                        format!("custom-random-{}", call)
+                    }),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+            assert_eq!(authorize_url.state.as_deref(), Some("custom-random-0"));
+            assert_eq!(authorize_url.nonce.as_deref(), Some("custom-random-1"));
+
+            // The login completes with the custom state and nonce.
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+#[should_panic(expected = "managed by the middleware")]
+async fn extra_authorize_params_cannot_override_the_state() {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                .await
+                .with_extra_authorize_params(vec![("state".to_string(), "fixed".to_string())]);
+
+            Ok(())
+        })
+        .await
+        .unwrap();
+}
+
+#[test]
+fn timing_policy_leeway_cannot_exceed_authorize_ttl() {
+    assert_eq!(
+        TimingPolicy::new(Duration::from_secs(30), Duration::from_secs(60)),
+        Err(ConfigError::InconsistentTimingPolicy {
+            authorize_ttl: Duration::from_secs(30),
+            leeway: Duration::from_secs(60),
+        })
+    );
+
+    let policy = TimingPolicy::new(Duration::from_secs(60), Duration::from_secs(30)).unwrap();
+    assert_eq!(policy.authorize_ttl(), Duration::from_secs(60));
+    assert_eq!(policy.leeway(), Duration::from_secs(30));
+}
+
+#[async_std::test]
+async fn timing_policy_accepts_huge_durations() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            // Durations this long must not overflow when the leeway is
+            // added to the authorize TTL or applied to the clock.
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_timing_policy(TimingPolicy::new(Duration::MAX, Duration::MAX).unwrap()),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn timing_policy_limits_authorization_state_lifetime() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            // No time at all to complete the sign in process, and no
+            // leeway: the callback is rejected.
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_timing_policy(TimingPolicy::new(Duration::ZERO, Duration::ZERO).unwrap()),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            // The leeway extends the authorize TTL, and so the same flow
+            // succeeds once the policy includes a grace period.
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_timing_policy(
+                        TimingPolicy::new(Duration::from_secs(60), Duration::from_secs(60))
+                            .unwrap(),
+                    ),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn callback_path_ignores_trailing_slash() -> http_types::Result<()> {
+    // The redirect URL has a trailing slash, but the Identity Provider
+    // redirects the browser to the path without the slash.
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            let config = tide_openidconnect::Config {
+                redirect_url: RedirectUrl::new("http://localhost/callback/".to_string()).unwrap(),
+                ..get_config(&emu.issuer_url())
+            };
+            app.with(OpenIdConnectMiddleware::new(&config).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            assert!(callback_url.starts_with("/callback?"));
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await?;
+
+    // The redirect URL does *not* have a trailing slash, but the browser
+    // is redirected to the path with the slash.
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback/".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            assert!(callback_url.starts_with("/callback/?"));
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+/// Returns the message with which the function panics.
+fn panic_message<T>(f: impl FnOnce() -> T) -> String {
+    let panic = std::panic::catch_unwind(AssertUnwindSafe(f))
+        .err()
+        .expect("expected a panic");
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast::<&str>().unwrap().to_string(),
+    }
+}
+
+#[async_std::test]
+async fn login_path_colliding_with_callback_path_is_an_error() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mw = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await;
+            assert!(panic_message(|| mw.with_login_path("/callback/"))
+                .contains("collides with the path of the redirect URL"));
+
+            Ok(())
+        })
+        .await
+}
+
+#[test]
+fn default_login_path_colliding_with_callback_path_is_an_error() {
+    let mut config = get_config(&IssuerUrl::new("http://localhost/".to_string()).unwrap());
+    config.redirect_url = RedirectUrl::new("http://localhost/login".to_string()).unwrap();
+    assert!(panic_message(|| OpenIdConnectMiddleware::lazy(&config))
+        .contains("collides with the path of the redirect URL"));
+}
+
+#[async_std::test]
+async fn login_path_can_be_moved_away_from_the_callback_path_in_the_config(
+) -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/login".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let config: OpenIdConnectConfig = serde_json::from_value(serde_json::json!({
+                "issuer_url": emu.issuer_url(),
+                "client_id": "CLIENT-ID",
+                "client_secret": "CLIENT-SECRET",
+                "redirect_url": "http://localhost/login",
+                "login_path": "/signin",
+            }))?;
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::from_config(&config).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/signin").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn middleware_can_be_created_from_config() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let config: OpenIdConnectConfig = serde_json::from_value(serde_json::json!({
+                "issuer_url": emu.issuer_url(),
+                "client_id": "CLIENT-ID",
+                "client_secret": "CLIENT-SECRET",
+                "redirect_url": "http://localhost/callback",
+                "scopes": ["profile"],
+                "login_path": "/signin",
+                "logout_path": "/signout",
+                "logout_landing_path": "/bye",
+                "require_signed_session": false,
+            }))?;
+
+            let mw = OpenIdConnectMiddleware::from_config(&config).await;
+            assert_eq!(mw.validate_session_store(&CookieStore::new()), Ok(()));
+
+            let mut app = create_test_server();
+            app.with(mw);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The configured login path and scopes are used.
+            let res = client.get("/signin").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(
+                authorize_url.with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default().with_scopes("openid profile"),
+            );
+
+            // As are the logout path and logout landing path.
+            let res = client.get("/signout").await?;
+            assert_redirect(&res, "/bye");
+
+            // The default login path is no longer intercepted.
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::NotFound);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn middleware_can_be_created_from_a_complete_config() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let config: OpenIdConnectConfig = serde_json::from_value(serde_json::json!({
+                "issuer_url": emu.issuer_url(),
+                "client_id": "CLIENT-ID",
+                "client_secret": "CLIENT-SECRET",
+                "redirect_url": "http://localhost/callback",
+                "idp_logout_url": "https://idp.example.com/logout",
+                "provider_name": "emulator",
+                "session_namespace": "emulator.oidc",
+                "mount_path": "/",
+                "scope_path": "/",
+                "scopes": ["profile"],
+                "offline_access": true,
+                "acr_values": ["mfa"],
+                "resources": ["https://api.example.com/"],
+                "session_claims": ["email"],
+                "user_id_claim": "email",
+                "additional_audiences": ["other-client"],
+                "login_path": "/signin",
+                "login_landing_path": "/welcome",
+                "return_param": "next",
+                "intercept_callback": true,
+                "login_flash": "Welcome back!",
+                "login_required_message": "Please sign in to continue.",
+                "logout_path": "/signout",
+                "frontchannel_logout_path": "/frontchannel-logout",
+                "backchannel_logout_path": "/backchannel-logout",
+                "logout_destroys_session": false,
+                "logout_landing_path": "/bye",
+                "login_rejected_path": "/rejected",
+                "public_paths": ["/healthz"],
+                "require_signed_session": false,
+                "strict_authentication": true,
+                "bearer_token_auth": true,
+                "bearer_audience": "https://api.example.com",
+                "trusted_proxy": true,
+                "htmx_support": true,
+                "trace_user": true,
+                "require_email_verified": true,
+                "require_requested_scopes": true,
+                "auth_method": "client_secret_post",
+                "par": true,
+                "verify_at_hash": true,
+            }))?;
+
+            // The client secret is not revealed by the config's debug
+            // output.
+            assert!(!format!("{:?}", config).contains("CLIENT-SECRET"));
+
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::from_config(&config).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The authorization request is pushed to the provider.
+            let res = client.get("/signin").await?;
+            let location = openidconnect::url::Url::parse(res.header(LOCATION).unwrap().as_str())?;
+            let (_, request_uri) = location
+                .query_pairs()
+                .find(|(name, _)| name == "request_uri")
+                .unwrap();
+            let authorize_url =
+                ParsedAuthorizeUrl::from_url(emu.pushed_authorize_url(&request_uri).await.unwrap());
+            assert_eq!(
+                authorize_url.with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default()
+                    .with_scopes("openid profile offline_access")
+                    .with_extra_param("prompt", "consent")
+                    .with_extra_param("acr_values", "mfa")
+                    .with_extra_param("resource", "https://api.example.com/"),
+            );
+
+            // The logout paths are intercepted.
+            let res = client.get("/frontchannel-logout").await?;
+            assert_eq!(res.status(), StatusCode::Ok);
+            let res = client.post("/backchannel-logout").await?;
+            assert_eq!(res.status(), StatusCode::BadRequest);
+            let res = client.get("/signout").await?;
+            assert_redirect(&res, "https://idp.example.com/logout");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn tokens_are_encrypted_in_the_session() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            emu.issue_refresh_tokens();
+            let clock = MockClock::default();
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_clock(clock.clone())
+                    .with_token_encryption_key([7; 32]),
+            );
+            app.at("/raw-session")
+                .get(|req: tide::Request<()>| async move {
+                    Ok(req.session().get_raw("tide.oidc").unwrap_or_default())
+                });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // The stored tokens are encrypted...
+            let raw_session = client.get("/raw-session").recv_string().await?;
+            assert!(raw_session.contains("\"access_token\""));
+            assert!(!raw_session.contains("atoken"));
+
+            // ...but are decrypted for the application, and for the
+            // token refresh.
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+            clock.advance(Duration::from_secs(2 * 60 * 60));
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=2 access_token=atoken-1 scopes=[\"openid\"] userid=id",
+            )
+            .await;
+            let raw_session = client.get("/raw-session").recv_string().await?;
+            assert!(!raw_session.contains("atoken"));
+
+            Ok(())
+        })
+        .await
+}
+
+/// Session store that records the cookie value of every new session, so
+/// that tests can look the sessions up outside of a request.
+#[derive(Debug, Clone)]
+struct RecordingSessionStore {
+    store: MemoryStore,
+    cookie_values: Arc<Mutex<Vec<String>>>,
+}
+
+#[tide::utils::async_trait]
+impl SessionStore for RecordingSessionStore {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        self.store.load_session(cookie_value).await
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        let cookie_value = self.store.store_session(session).await?;
+        if let Some(cookie_value) = &cookie_value {
+            self.cookie_values
+                .lock()
+                .unwrap()
+                .push(cookie_value.clone());
+        }
+        Ok(cookie_value)
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        self.store.destroy_session(session).await
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        self.store.clear_store().await
+    }
+}
+
+#[async_std::test]
+async fn session_activity_can_be_checked_outside_of_a_request() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let store = RecordingSessionStore {
+                store: MemoryStore::new(),
+                cookie_values: Arc::default(),
+            };
+            let middleware =
+                Arc::new(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let mut app = tide::new();
+            app.with(
+                SessionMiddleware::new(store.clone(), b"secrets must be >= 32 bytes long")
+                    .with_same_site_policy(tide::http::cookies::SameSite::Lax),
+            );
+            app.with(SharedMiddleware(Arc::clone(&middleware)));
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The session is not active until the login completes...
+            let res = client.get("/login").await?;
+            let cookie_value = store.cookie_values.lock().unwrap()[0].clone();
+            assert!(
+                !middleware
+                    .is_subject_session_active(&store, &cookie_value)
+                    .await
+            );
+
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            assert!(
+                middleware
+                    .is_subject_session_active(&store, &cookie_value)
+                    .await
+            );
+
+            // ...and no longer active once the logout has destroyed it.
+            client.get("/logout").await?;
+            assert!(
+                !middleware
+                    .is_subject_session_active(&store, &cookie_value)
+                    .await
+            );
+            assert!(
+                !middleware
+                    .is_subject_session_active(&store, "unknown")
+                    .await
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn expired_strict_authentication_sessions_are_not_active() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let store = RecordingSessionStore {
+                store: MemoryStore::new(),
+                cookie_values: Arc::default(),
+            };
+            let clock = MockClock::default();
+            let middleware = Arc::new(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_strict_authentication(true)
+                    .with_session_binding(SessionBinding::UserAgent)
+                    .with_clock(clock.clone()),
+            );
+            let mut app = tide::new();
+            app.with(
+                SessionMiddleware::new(store.clone(), b"secrets must be >= 32 bytes long")
+                    .with_same_site_policy(tide::http::cookies::SameSite::Lax),
+            );
+            app.with(SharedMiddleware(Arc::clone(&middleware)));
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let cookie_value = store.cookie_values.lock().unwrap()[0].clone();
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // The bound session is active (outside of a request, there is
+            // no client to compare the binding with)...
+            assert!(
+                middleware
+                    .is_subject_session_active(&store, &cookie_value)
+                    .await
+            );
+
+            // ...until its access token expires; the emulator's access
+            // tokens expire after an hour, and no refresh token was
+            // issued.
+            clock.advance(Duration::from_secs(2 * 60 * 60));
+            assert!(
+                !middleware
+                    .is_subject_session_active(&store, &cookie_value)
+                    .await
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn user_sessions_can_be_revoked() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let store = MemoryStore::new();
+            let middleware = Arc::new(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_session_store(store.clone()),
+            );
+            let mut app = create_test_server_with_store(store);
+            app.with(SharedMiddleware(Arc::clone(&middleware)));
+            app.at("/logout-everywhere")
+                .get(move |mut req: tide::Request<()>| {
+                    let middleware = Arc::clone(&middleware);
+                    async move {
+                        let user_id = req.user_id().unwrap();
+                        middleware.revoke_user_sessions(&user_id).await?;
+                        req.session_mut().destroy();
+                        Ok("revoked")
+                    }
+                });
+
+            // Log the user in to two sessions, and another user in to a
+            // third session.
+            let mut clients = vec![];
+            for (access_token, userid) in [("atoken", "id"), ("btoken", "id"), ("ctoken", "other")]
+            {
+                let client = app.client().with(SessionCookieJarMiddleware::default());
+                let res = client.get("/login").await?;
+                let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                let callback_url = emu
+                    .add_token(access_token, "openid", userid, &authorize_url)
+                    .await;
+                let res = client.get(callback_url).await?;
+                assert_redirect(&res, "/");
+                clients.push(client);
+            }
+
+            // Revoke the user's sessions from the first session, which
+            // logs the user out of both of their sessions, but not the
+            // other user.
+            assert_response(&mut clients[0].get("/logout-everywhere").await?, "revoked").await;
+            assert_response(&mut clients[0].get("/").await?, "unauthed visits=1").await;
+            assert_response(&mut clients[1].get("/").await?, "unauthed visits=1").await;
+            assert_response(
+                &mut clients[2].get("/").await?,
+                "authed visits=1 access_token=ctoken scopes=[\"openid\"] userid=other",
+            )
+            .await;
+
+            // The user can log in again after the revocation.
+            let res = clients[1].get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("dtoken", "openid", "id", &authorize_url)
+                .await;
+            let res = clients[1].get(callback_url).await?;
+            assert_redirect(&res, "/");
+            assert_response(
+                &mut clients[1].get("/").await?,
+                "authed visits=2 access_token=dtoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn user_sessions_cannot_be_revoked_without_a_session_store() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let middleware = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await;
+            assert!(matches!(
+                middleware.revoke_user_sessions("id").await,
+                Err(OpenIdConnectError::SessionUnavailable(_))
+            ));
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn cookie_session_store_is_rejected_by_the_middleware() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let config = get_config(&emu.issuer_url());
+
+            // The store that is given to the middleware is checked without
+            // calling `validate_session_store`...
+            let mw = OpenIdConnectMiddleware::new(&config).await;
+            let panic = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                mw.with_session_store(CookieStore::new())
+            }))
+            .unwrap_err();
+            assert!(panic
+                .downcast_ref::<String>()
+                .unwrap()
+                .contains("with_require_signed_session(false)"));
+
+            // ...in whichever order the middleware is configured.
+            let mw = OpenIdConnectMiddleware::new(&config)
+                .await
+                .with_require_signed_session(false)
+                .with_session_store(CookieStore::new());
+            let panic =
+                std::panic::catch_unwind(AssertUnwindSafe(|| mw.with_require_signed_session(true)))
+                    .unwrap_err();
+            assert!(panic
+                .downcast_ref::<String>()
+                .unwrap()
+                .contains("with_require_signed_session(false)"));
+
+            // Explicitly allowed cookie stores are accepted, but their
+            // sessions cannot be revoked.
+            let mw = OpenIdConnectMiddleware::new(&config)
+                .await
+                .with_require_signed_session(false)
+                .with_session_store(CookieStore::new());
+            assert!(matches!(
+                mw.revoke_user_sessions("id").await,
+                Err(OpenIdConnectError::SessionUnavailable(_))
+            ));
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn callback_can_respond_with_json() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_callback_response(CallbackResponse::Json),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let mut res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Ok);
+            let body: serde_json::Value = res.body_json().await?;
+            assert_eq!(
+                body,
+                serde_json::json!({ "authenticated": true, "redirect": "/" })
+            );
+
+            // The session was populated all the same.
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn login_can_be_initiated_with_post() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_login_method(Method::Post),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // GET requests to the login path are no longer intercepted.
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::NotFound);
+
+            // POST requests start the login process.
+            let res = client.post("/login").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(
+                authorize_url.clone().with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default(),
+            );
+
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn redirect_status_can_be_configured() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_login_method(Method::Post)
+                    .with_redirect_status(StatusCode::SeeOther),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.post("/login").await?;
+            assert_eq!(res.status(), StatusCode::SeeOther);
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::SeeOther);
+            assert_eq!(res.header(LOCATION).unwrap().as_str(), "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+#[should_panic(expected = "Redirect status must be a redirection status code")]
+async fn redirect_status_must_be_a_redirection() {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                .await
+                .with_redirect_status(StatusCode::Ok);
+
+            Ok(())
+        })
+        .await
+        .unwrap();
+}
+
+#[async_std::test]
+async fn idle_sessions_require_login() -> tide::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let clock = MockClock::default();
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_idle_timeout(Duration::from_secs(15 * 60))
+                    .with_clock(clock.clone()),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Activity within the idle timeout keeps the session alive,
+            // even if the total session length exceeds the timeout.
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+            clock.advance(Duration::from_secs(10 * 60));
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=2 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+            clock.advance(Duration::from_secs(10 * 60));
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=3 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // Going idle for longer than the timeout forces a new login
+            // (but retains the rest of the session).
+            clock.advance(Duration::from_secs(16 * 60));
+            let res = client.get("/").await?;
+            assert_redirect(&res, "/login");
+
+            let mut res = client.get("/").await?;
+            assert_response(&mut res, "unauthed visits=4").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn changed_user_agent_requires_login() -> tide::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_session_binding(SessionBinding::UserAgent),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").header("User-Agent", "Firefox").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client
+                .get(callback_url)
+                .header("User-Agent", "Firefox")
+                .await?;
+            assert_redirect(&res, "/");
+
+            // The session remains authenticated for the same client...
+            let mut res = client.get("/").header("User-Agent", "Firefox").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // ...but a different client must log in again (which clears
+            // the authentication state for the original client as well).
+            let res = client.get("/").header("User-Agent", "curl").await?;
+            assert_redirect(&res, "/login");
+
+            let mut res = client.get("/").header("User-Agent", "Firefox").await?;
+            assert_response(&mut res, "unauthed visits=2").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn public_paths_bypass_authentication() -> tide::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let clock = MockClock::default();
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_idle_timeout(Duration::from_secs(15 * 60))
+                    .with_public_paths(&["/healthz", "/metrics"])
+                    .with_clock(clock.clone()),
+            );
+            app.at("/healthz").get(|req: tide::Request<()>| async move {
+                Ok(format!("healthy authed={}", req.is_authenticated()))
+            });
+            app.at("/metrics/*").get(|_| async { Ok("metrics") });
+            app.at("/metrics-old").get(|_| async { Ok("old metrics") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Idle sessions are sent back through the login process,
+            // except on the public paths (and the paths below them).
+            clock.advance(Duration::from_secs(16 * 60));
+            let mut res = client.get("/healthz").await?;
+            assert_response(&mut res, "healthy authed=false").await;
+            let mut res = client.get("/metrics/http").await?;
+            assert_response(&mut res, "metrics").await;
+            let res = client.get("/metrics-old").await?;
+            assert_redirect(&res, "/login");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn access_token_audience_must_match_requested_resource() -> http_types::Result<()> {
+    // Unsigned JWT access tokens with `aud` claims of
+    // `"https://api.example.com"` and `["https://other.example.com"]`.
+    const API_ACCESS_TOKEN: &str =
+        "eyJhbGciOiJub25lIn0.eyJhdWQiOiJodHRwczovL2FwaS5leGFtcGxlLmNvbSJ9.sig";
+    const OTHER_ACCESS_TOKEN: &str =
+        "eyJhbGciOiJub25lIn0.eyJhdWQiOlsiaHR0cHM6Ly9vdGhlci5leGFtcGxlLmNvbSJdfQ.sig";
+
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_resource("https://api.example.com"),
+            );
+
+            // The requested resource is added to the authorize URL.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(
+                authorize_url.clone().with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default()
+                    .with_extra_param("resource", "https://api.example.com"),
+            );
+
+            // Access tokens for the requested resource are accepted.
+            let callback_url = emu
+                .add_token(API_ACCESS_TOKEN, "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Access tokens for any other audience are rejected.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token(OTHER_ACCESS_TOKEN, "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            let mut res = client.get("/").await?;
+            assert_response(&mut res, "unauthed visits=1").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn multiple_resources_can_be_requested() -> http_types::Result<()> {
+    // Unsigned JWT access token with an `aud` claim of
+    // `["https://other.example.com"]`.
+    const OTHER_ACCESS_TOKEN: &str =
+        "eyJhbGciOiJub25lIn0.eyJhdWQiOlsiaHR0cHM6Ly9vdGhlci5leGFtcGxlLmNvbSJdfQ.sig";
+
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_resource("https://api.example.com")
+                    .with_resource("https://other.example.com"),
+            );
+
+            // Each requested resource is added to the authorize URL.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let location = res.header(LOCATION).unwrap().get(0).unwrap().to_string();
+            let resources: Vec<String> = Url::parse(&location)?
+                .query_pairs()
+                .filter(|(name, _)| name == "resource")
+                .map(|(_, value)| value.into_owned())
+                .collect();
+            assert_eq!(
+                resources,
+                vec!["https://api.example.com", "https://other.example.com"]
+            );
+
+            // Access tokens for any of the requested resources are
+            // accepted.
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token(OTHER_ACCESS_TOKEN, "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn multiple_resources_can_be_configured() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let config: OpenIdConnectConfig = serde_json::from_value(serde_json::json!({
+                "issuer_url": emu.issuer_url(),
+                "client_id": "CLIENT-ID",
+                "client_secret": "CLIENT-SECRET",
+                "redirect_url": "http://localhost/callback",
+                "resources": ["https://api.example.com", "https://other.example.com"],
+            }))?;
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::from_config(&config).await);
+
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let location = res.header(LOCATION).unwrap().get(0).unwrap().to_string();
+            let resources: Vec<String> = Url::parse(&location)?
+                .query_pairs()
+                .filter(|(name, _)| name == "resource")
+                .map(|(_, value)| value.into_owned())
+                .collect();
+            assert_eq!(
+                resources,
+                vec!["https://api.example.com", "https://other.example.com"]
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn additional_audiences_must_be_trusted() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            emu.add_audience("https://api.example.com").await;
+
+            // ID tokens with an untrusted audience are rejected...
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            // ...but are accepted once the audience has been configured.
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_additional_audiences(&["https://api.example.com"]),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("btoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=btoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn interstitial_login_sets_cookies_before_redirecting() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_interstitial_login(true),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The login route returns a page (along with the session
+            // cookie) that navigates to the authorize URL.
+            let mut res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert!(res.header("Set-Cookie").is_some());
+            assert!(res.header("Location").is_none());
+
+            let body = res.body_string().await?;
+            let authorize_url = body
+                .split("window.location.replace(\"")
+                .nth(1)
+                .and_then(|s| s.split('"').next())
+                .expect("Authorize URL not found in the interstitial page.");
+            let authorize_url = ParsedAuthorizeUrl::from_url(authorize_url);
+            assert_eq!(
+                authorize_url.clone().with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default(),
+            );
+
+            // The login completes as usual.
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn login_hooks_observe_login_outcomes() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let logins = Arc::new(Mutex::new(Vec::new()));
+            let failures = Arc::new(Mutex::new(Vec::new()));
+
+            let mut app = create_test_server();
+            app.with({
+                let logins = logins.clone();
+                let failures = failures.clone();
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .on_login(move |claims| {
+                        logins.lock().unwrap().push(claims.subject().to_string())
+                    })
+                    .on_login_failure(move |error| failures.lock().unwrap().push(error.clone()))
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // A successful login invokes the login hook exactly once.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            client.get("/").await?;
+
+            assert_eq!(*logins.lock().unwrap(), vec!["id".to_string()]);
+            assert_eq!(*failures.lock().unwrap(), vec![]);
+
+            // A failed login invokes the failure hook instead.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res)
+                .with_state(Some("forged-state".to_string()));
+            let callback_url = emu
+                .add_token("btoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            assert_eq!(*logins.lock().unwrap(), vec!["id".to_string()]);
+            assert_eq!(
+                *failures.lock().unwrap(),
+                vec![OpenIdConnectError::CsrfMismatch]
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn public_clients_use_pkce_instead_of_a_client_secret() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new_public(
+                    emu.issuer_url(),
+                    ClientId::new("CLIENT-ID".to_string()),
+                    RedirectUrl::new("http://localhost/callback".to_string()).unwrap(),
+                )
+                .await,
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The authorize URL includes a PKCE code challenge.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(
+                authorize_url
+                    .extra_params
+                    .get("code_challenge_method")
+                    .map(String::as_str),
+                Some("S256")
+            );
+            assert!(authorize_url.extra_params.contains_key("code_challenge"));
+
+            // The emulator verifies the code verifier, and the token
+            // request does not include a client secret.
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            assert_eq!(emu.client_authentications().await, vec![false]);
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn middleware_can_be_initialized_from_a_metadata_url() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let config = get_config(&emu.issuer_url());
+            let metadata_url = emu
+                .issuer_url()
+                .join("tenant/v2.0/openid-configuration")
+                .unwrap();
+
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::from_metadata_url(
+                    metadata_url.as_str(),
+                    config.client_id,
+                    config.client_secret,
+                    config.redirect_url,
+                )
+                .await,
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn middleware_can_be_initialized_from_provider_metadata() -> http_types::Result<()> {
+    // No emulator: the middleware must not make any requests to the
+    // Identity Provider in order to start a login.
+    let provider_metadata = CoreProviderMetadata::new(
+        IssuerUrl::new("https://idp.example.com/".to_string())?,
+        AuthUrl::new("https://idp.example.com/authorize".to_string())?,
+        JsonWebKeySetUrl::new("https://idp.example.com/jwks".to_string())?,
+        vec![ResponseTypes::new(vec![CoreResponseType::Code])],
+        vec![CoreSubjectIdentifierType::Public],
+        vec![CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256],
+        EmptyAdditionalProviderMetadata {},
+    )
+    .set_token_endpoint(Some(TokenUrl::new(
+        "https://idp.example.com/token".to_string(),
+    )?));
+
+    let mut app = create_test_server();
+    app.with(OpenIdConnectMiddleware::from_provider_metadata(
+        provider_metadata,
+        ClientId::new("CLIENT-ID".to_string()),
+        ClientSecret::new("CLIENT-SECRET".to_string()),
+        RedirectUrl::new("http://localhost/callback".to_string())?,
+    ));
+    let client = app.client().with(SessionCookieJarMiddleware::default());
+
+    let res = client.get("/login").await?;
+    assert_eq!(res.status(), StatusCode::Found);
+    let location = res.header(LOCATION).unwrap().get(0).unwrap().to_string();
+    assert!(location.starts_with("https://idp.example.com/authorize?"));
+    assert!(location.contains("client_id=CLIENT-ID"));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn missing_id_token_is_a_bad_gateway() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            emu.omit_id_tokens();
+
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::BadGateway);
+
+            let mut res = client.get("/").await?;
+            assert_response(&mut res, "unauthed visits=1").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn id_tokens_from_another_issuer_are_rejected() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+
+            // Tokens are signed with the provider's usual keys, but name
+            // another tenant (or a slightly different spelling of the
+            // configured issuer URL) as their issuer.
+            let issuer_url = emu.issuer_url();
+            for token_issuer in [
+                format!("{}other-tenant/", issuer_url.as_str()),
+                issuer_url.as_str().trim_end_matches('/').to_string(),
+            ] {
+                emu.issue_tokens_as(IssuerUrl::new(token_issuer)?).await;
+                let client = app.client().with(SessionCookieJarMiddleware::default());
+                let res = client.get("/login").await?;
+                let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                let callback_url = emu
+                    .add_token("atoken", "openid", "id", &authorize_url)
+                    .await;
+                let res = client.get(callback_url).await?;
+                assert_eq!(res.status(), StatusCode::Unauthorized);
+
+                let mut res = client.get("/").await?;
+                assert_response(&mut res, "unauthed visits=1").await;
+            }
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn failed_token_exchanges_are_retried() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The first token request fails with a `503`, but the retry
+            // succeeds.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            emu.fail_token_requests(1);
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Logins fail once the retries have been used up.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            emu.fail_token_requests(3);
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::InternalServerError);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn token_exchange_retries_are_limited() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_token_exchange_retries(u32::MAX),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The login fails after five retries, even though the sixth
+            // retry would have succeeded.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            emu.fail_token_requests(6);
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::InternalServerError);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn access_token_hash_can_be_verified() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_verify_at_hash(true),
+            );
+
+            // ID tokens whose `at_hash` matches the access token are
+            // accepted...
+            emu.include_access_token_hashes();
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // ...whereas mismatched hashes are rejected.
+            emu.tamper_access_token_hashes();
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("btoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            let mut res = client.get("/").await?;
+            assert_response(&mut res, "unauthed visits=1").await;
+
+            // The hash is not verified by default.
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("ctoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn id_tokens_without_a_nonce_require_optional_nonce_mode() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            emu.omit_nonces();
+
+            // The nonce is required by default.
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            // The same token is accepted once the nonce is optional.
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_nonce_verification(NonceMode::Optional),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn encrypted_id_tokens_are_decrypted_and_verified() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            emu.encrypt_id_tokens();
+
+            // Encrypted ID tokens are rejected without a decryption key.
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::InternalServerError);
+
+            // They are decrypted (and then verified) with the key.
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_id_token_decryption_key(
+                        IdTokenDecryptionKey::from_rsa_pem(ID_TOKEN_ENCRYPTION_KEY).unwrap(),
+                    ),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[test]
+fn id_token_decryption_key_must_be_an_rsa_key() {
+    assert_eq!(
+        IdTokenDecryptionKey::from_rsa_pem("not a key").unwrap_err(),
+        ConfigError::InvalidDecryptionKey
+    );
+}
+
+#[async_std::test]
+async fn token_requests_use_the_configured_auth_method() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            for auth_method in [
+                None,
+                Some(CoreClientAuthMethod::ClientSecretPost),
+                Some(CoreClientAuthMethod::PrivateKeyJwt),
+            ] {
+                let mut middleware = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_client_assertion_key(
+                        ClientAssertionKey::from_rsa_pem(CLIENT_ASSERTION_KEY)
+                            .unwrap()
+                            .with_key_id("client-key"),
+                    );
+                if let Some(auth_method) = auth_method {
+                    middleware = middleware.with_auth_method(auth_method);
+                }
+                let mut app = create_test_server();
+                app.with(middleware);
+                let client = app.client().with(SessionCookieJarMiddleware::default());
+
+                let res = client.get("/login").await?;
+                let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                let callback_url = emu
+                    .add_token("atoken", "openid", "id", &authorize_url)
+                    .await;
+                let res = client.get(callback_url).await?;
+                assert_redirect(&res, "/");
+            }
+
+            // The client secret is sent in the Authorization header by
+            // default, and in the request body if configured; the client
+            // assertion (which the emulator verifies) replaces the secret.
+            assert_eq!(
+                emu.client_auth_methods().await,
+                vec![
+                    "client_secret_basic",
+                    "client_secret_post",
+                    "private_key_jwt"
+                ]
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn private_key_jwt_requires_a_client_assertion_key() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_auth_method(CoreClientAuthMethod::PrivateKeyJwt),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::InternalServerError);
+
+            // The token request was never sent.
+            assert!(emu.client_auth_methods().await.is_empty());
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+#[should_panic(expected = "`client_secret_jwt` is not supported")]
+async fn unsupported_auth_methods_are_an_error() {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                .await
+                .with_auth_method(CoreClientAuthMethod::ClientSecretJwt);
+
+            Ok(())
+        })
+        .await
+        .unwrap();
+}
+
+#[async_std::test]
+async fn middleware_can_register_its_client() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let registration_request =
+                CoreClientRegistrationRequest::new(vec![], EmptyAdditionalClientMetadata {})
+                    .set_token_endpoint_auth_method(Some(CoreClientAuthMethod::ClientSecretPost));
+            let middleware = OpenIdConnectMiddleware::register(
+                emu.issuer_url(),
+                registration_request,
+                RedirectUrl::new("http://localhost/callback".to_string()).unwrap(),
+            )
+            .await;
+
+            // The redirect URL was registered, and the middleware uses
+            // the returned credentials.
+            assert_eq!(
+                emu.registrations().await[0]["redirect_uris"],
+                serde_json::json!(["http://localhost/callback"])
+            );
+            assert_eq!(middleware.client_id().as_str(), "CLIENT-ID");
+            assert_eq!(
+                middleware.client_secret().unwrap().secret(),
+                REGISTERED_CLIENT_SECRET
+            );
+
+            let mut app = create_test_server();
+            app.with(middleware);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            assert_response(
+                &mut client.get("/").await?,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // The token request used the registered secret and the
+            // registered authentication method.
+            assert_eq!(emu.client_secrets().await, vec![REGISTERED_CLIENT_SECRET]);
+            assert_eq!(emu.client_auth_methods().await, vec!["client_secret_post"]);
+
+            Ok(())
+        })
+        .await
+}
+
+#[test]
+fn client_assertion_key_must_be_an_rsa_key() {
+    assert_eq!(
+        ClientAssertionKey::from_rsa_pem("not a key").unwrap_err(),
+        ConfigError::InvalidClientAssertionKey
+    );
+}
+
+#[async_std::test]
+async fn lazy_middleware_retries_failed_discovery() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            emu.fail_discovery_requests(1);
+            let clock = MockClock::default();
+
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::lazy(&get_config(&emu.issuer_url()))
+                    .with_clock(clock.clone()),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Discovery fails, and is not retried until the backoff
+            // period has elapsed.
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+
+            // The next attempt succeeds.
+            clock.advance(Duration::from_secs(2));
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn unresponsive_token_endpoint_is_a_bad_gateway() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            emu.stall_token_requests();
+
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_http_timeout(Duration::from_secs(1)),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let started = Instant::now();
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::BadGateway);
+            assert!(started.elapsed() < Duration::from_secs(10));
+
+            let mut res = client.get("/").await?;
+            assert_response(&mut res, "unauthed visits=1").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn form_post_callbacks_complete_the_login() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_response_mode(ResponseMode::FormPost),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The form_post response mode is requested from the provider.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(
+                authorize_url.clone().with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default().with_extra_param("response_mode", "form_post"),
+            );
+
+            // The provider then POSTs the authorization response to the
+            // callback path.
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let (callback_path, callback_query) = callback_url.split_once('?').unwrap();
+            let res = client
+                .post(callback_path)
+                .content_type("application/x-www-form-urlencoded")
+                .body(callback_query)
+                .await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn hybrid_flow_is_requested_in_the_authorize_url() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_authentication_flow(AuthenticationFlow::Hybrid(vec![
+                        CoreResponseType::Code,
+                        CoreResponseType::IdToken,
+                    ]))
+                    .with_response_mode(ResponseMode::FormPost),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The configured response type is requested from the provider.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_eq!(
+                authorize_url.clone().with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl {
+                    response_type: "code id_token".to_string(),
+                    ..ParsedAuthorizeUrl::default()
+                }
+                .with_extra_param("response_mode", "form_post"),
+            );
+
+            // A front-channel ID token that cannot be verified fails the
+            // login before the code is exchanged.
+            let callback_body = format!(
+                "code=12345&state={}&id_token=not.a.token",
+                authorize_url.state.as_ref().unwrap()
+            );
+            let res = client
+                .post("/callback")
+                .content_type("application/x-www-form-urlencoded")
+                .body(callback_body)
+                .await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+#[should_panic(expected = "must return an authorization code")]
+async fn implicit_flow_is_rejected() {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                .await
+                .with_authentication_flow(AuthenticationFlow::Implicit(false));
+
+            Ok(())
+        })
+        .await
+        .unwrap();
+}
+
+#[async_std::test]
+async fn form_post_session_cookies_are_secure_over_https() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = tide::new();
+            app.with(
+                SessionMiddleware::new(MemoryStore::new(), b"secrets must be >= 32 bytes long")
+                    .with_same_site_policy(tide::http::cookies::SameSite::None),
+            );
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_response_mode(ResponseMode::FormPost),
+            );
+            let client = app.client();
+
+            // The `SameSite=None` session cookie that carries the login
+            // state to the (cross-site) form_post callback is marked as
+            // `Secure` when the login request arrives over https.
+            let res = client.get("https://localhost/login").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let cookie = res.header("Set-Cookie").unwrap().last().as_str();
+            assert!(cookie.contains("SameSite=None"), "{}", cookie);
+            assert!(cookie.contains("Secure"), "{}", cookie);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn forwarded_proto_https_produces_secure_cookies() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = tide::new();
+            app.with(ForwardedProtoMiddleware);
+            app.with(SessionMiddleware::new(
+                MemoryStore::new(),
+                b"secrets must be >= 32 bytes long",
+            ));
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_trusted_proxy(true),
+            );
+            let client = app.client();
+
+            // The proxy terminated TLS and forwarded the request over
+            // plain http, but the session cookie is still marked Secure.
+            let res = client
+                .get("http://localhost/login")
+                .header("X-Forwarded-Proto", "https")
+                .await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let cookie = res.header("Set-Cookie").unwrap().last().as_str();
+            assert!(cookie.contains("Secure"), "{}", cookie);
+
+            // Requests that the proxy received over http are not.
+            let res = client
+                .get("http://localhost/login")
+                .header("X-Forwarded-Proto", "http")
+                .await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let cookie = res.header("Set-Cookie").unwrap().last().as_str();
+            assert!(!cookie.contains("Secure"), "{}", cookie);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn trusted_proxy_requires_forwarded_proto_middleware() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = tide::new();
+            app.with(SessionMiddleware::new(
+                MemoryStore::new(),
+                b"secrets must be >= 32 bytes long",
+            ));
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_trusted_proxy(true),
+            );
+            let client = app.client();
+
+            // Without ForwardedProtoMiddleware, the session middleware
+            // would issue a cookie without the Secure attribute, and so
+            // the request is rejected (without any session cookie)
+            // instead.
+            let res = client
+                .get("http://localhost/login")
+                .header("X-Forwarded-Proto", "https")
+                .await?;
+            assert_eq!(res.status(), StatusCode::InternalServerError);
+            assert!(res.header("Set-Cookie").is_none());
+
+            // Requests that the proxy received over http are unaffected.
+            let res = client
+                .get("http://localhost/login")
+                .header("X-Forwarded-Proto", "http")
+                .await?;
+            assert_eq!(res.status(), StatusCode::Found);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn authorization_requests_can_be_pushed() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_par(true),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The browser is sent to the authorization endpoint with only
+            // the client id and the request URI of the pushed request...
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let location = openidconnect::url::Url::parse(res.header(LOCATION).unwrap().as_str())?;
+            assert_eq!(location.path(), "/authorization");
+            let params: Vec<(String, String)> = location.query_pairs().into_owned().collect();
+            assert_eq!(params.len(), 2);
+            assert_eq!(
+                params[0],
+                ("client_id".to_string(), "CLIENT-ID".to_string())
+            );
+            assert_eq!(params[1].0, "request_uri");
+
+            // ...which the provider resolves to the full authorization
+            // request.
+            let authorize_url =
+                ParsedAuthorizeUrl::from_url(emu.pushed_authorize_url(&params[1].1).await.unwrap());
+            assert_eq!(
+                authorize_url.clone().with_nonce(None).with_state(None),
+                ParsedAuthorizeUrl::default(),
+            );
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn failed_pushed_authorization_requests_are_a_bad_gateway() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            // The emulator rejects the registered client's secret.
+            let mut config = get_config(&emu.issuer_url());
+            config.client_secret = ClientSecret::new("WRONG-SECRET".to_string());
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&config).await.with_par(true));
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            assert_eq!(res.status(), StatusCode::BadGateway);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn transient_login_state_is_kept_in_the_session() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new_public(
+                    emu.issuer_url(),
+                    ClientId::new("CLIENT-ID".to_string()),
+                    RedirectUrl::new("http://localhost/callback".to_string()).unwrap(),
+                )
+                .await,
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The CSRF state, nonce, and PKCE verifier are all stored in
+            // the session; the only cookie is the session cookie.
+            let res = client.get("/login").await?;
+            let cookie_names: Vec<String> = res
+                .header("Set-Cookie")
+                .unwrap()
+                .iter()
+                .map(|value| {
+                    tide::http::Cookie::parse(value.to_string())
+                        .unwrap()
+                        .name()
+                        .to_string()
+                })
+                .collect();
+            assert_eq!(cookie_names, vec!["tide.sid".to_string()]);
+
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn interleaved_logins_both_complete() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Start two logins (in two tabs, for example) before
+            // completing either of them.
+            let res = client.get("/login").await?;
+            let first_authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let res = client.get("/login").await?;
+            let second_authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            assert_ne!(first_authorize_url.state, second_authorize_url.state);
+
+            // Both logins complete, in the order in which they were
+            // started.
+            let first_callback_url = emu
+                .add_token("token-a", "openid", "alice", &first_authorize_url)
+                .await;
+            let second_callback_url = emu
+                .add_token("token-b", "openid", "bob", &second_authorize_url)
+                .await;
+
+            let res = client.get(&first_callback_url).await?;
+            assert_redirect(&res, "/");
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=token-a scopes=[\"openid\"] userid=alice",
+            )
+            .await;
+
+            let res = client.get(&second_callback_url).await?;
+            assert_redirect(&res, "/");
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=2 access_token=token-b scopes=[\"openid\"] userid=bob",
+            )
+            .await;
+
+            // Each login can only be completed once.
+            let res = client.get(&first_callback_url).await?;
+            assert_redirect(&res, "/");
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=3 access_token=token-b scopes=[\"openid\"] userid=bob",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn replayed_callbacks_redirect_instead_of_failing() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(&callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Replaying the completed callback goes to the landing path
+            // (without exchanging the code again).
+            let res = client.get(&callback_url).await?;
+            assert_redirect(&res, "/");
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await?;
+
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            emu.omit_id_tokens();
+
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(&callback_url).await?;
+            assert_eq!(res.status(), StatusCode::BadGateway);
+
+            // Replaying a failed callback starts the login over.
+            let res = client.get(&callback_url).await?;
+            assert_redirect(&res, "/login");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn metrics_hook_reports_callback_timings() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let events: Arc<Mutex<Vec<MetricEvent>>> = Arc::default();
+
+            let mut app = create_test_server();
+            let metric_events = events.clone();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_metrics(move |event| metric_events.lock().unwrap().push(event)),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let events = events.lock().unwrap();
+            assert!(matches!(
+                events.first(),
+                Some(MetricEvent::AuthorizeRedirect { .. })
+            ));
+            let token_exchange = events.iter().find_map(|event| match event {
+                MetricEvent::TokenExchange { duration } => Some(*duration),
+                _ => None,
+            });
+            assert!(token_exchange.unwrap() > Duration::ZERO);
+            assert!(matches!(
+                events.last(),
+                Some(MetricEvent::Callback { duration, success: true }) if *duration > Duration::ZERO
+            ));
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn expired_access_tokens_are_refreshed_with_rotated_refresh_tokens() -> tide::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            emu.issue_refresh_tokens();
+
+            let clock = MockClock::default();
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_clock(clock.clone()),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // The emulator invalidates each refresh token once it has
+            // been used, so the second refresh only succeeds if the
+            // middleware stored the rotated refresh token.
+            clock.advance(Duration::from_secs(2 * 60 * 60));
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=2 access_token=atoken-1 scopes=[\"openid\"] userid=id",
+            )
+            .await;
+            clock.advance(Duration::from_secs(2 * 60 * 60));
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=3 access_token=atoken-2 scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // A rejected refresh token (which is how providers respond to
+            // refresh token reuse) clears the authentication state.
+            emu.revoke_refresh_tokens().await;
+            clock.advance(Duration::from_secs(2 * 60 * 60));
+            let mut res = client.get("/").await?;
+            assert_response(&mut res, "unauthed visits=4").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn callback_works_under_a_mount_path() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(
+        RedirectUrl::new("http://localhost/app/callback".to_string()).unwrap(),
+    )
+    .run_with_emulator(|emu| async move {
+        let mut config = get_config(&emu.issuer_url());
+        config.redirect_url =
+            RedirectUrl::new("http://localhost/app/callback".to_string()).unwrap();
+
+        // Nest the application (and its session cookie) under `/app`.
+        let mut nested = tide::new();
+        nested.with(
+            SessionMiddleware::new(MemoryStore::new(), b"secrets must be >= 32 bytes long")
+                .with_cookie_path("/app")
+                .with_same_site_policy(tide::http::cookies::SameSite::Lax),
+        );
+        nested.with(
+            OpenIdConnectMiddleware::new(&config)
+                .await
+                .with_mount_path("/app")
+                .with_login_landing_path("/app/"),
+        );
+        let mut app = tide::new();
+        app.at("/app").nest(nested);
+        let client = app.client().with(SessionCookieJarMiddleware::default());
+
+        let res = client.get("/app/login").await?;
+        let cookie = tide::http::Cookie::parse(res.header("Set-Cookie").unwrap().to_string())?;
+        assert_eq!(cookie.path(), Some("/app"));
+
+        let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+        assert_eq!(authorize_url.redirect_uri, "http://localhost/app/callback");
+        let callback_url = emu
+            .add_token("atoken", "openid", "id", &authorize_url)
+            .await;
+        let res = client.get(callback_url).await?;
+        assert_redirect(&res, "/app/");
+
+        Ok(())
+    })
+    .await
+}
+
+#[async_std::test]
+async fn session_cookie_path_is_set_on_the_session_middleware() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(
+        RedirectUrl::new("http://localhost/app/callback".to_string()).unwrap(),
+    )
+    .run_with_emulator(|emu| async move {
+        let mut config = get_config(&emu.issuer_url());
+        config.redirect_url =
+            RedirectUrl::new("http://localhost/app/callback".to_string()).unwrap();
+
+        // The middleware sets no cookies of its own, so the only cookie of
+        // a login is the session cookie, whose path (`/` unless configured
+        // otherwise) belongs to Tide's session middleware.
+        for (cookie_path, expected_path) in [(None, "/"), (Some("/app"), "/app")] {
+            let mut sessions =
+                SessionMiddleware::new(MemoryStore::new(), b"secrets must be >= 32 bytes long")
+                    .with_same_site_policy(tide::http::cookies::SameSite::Lax);
+            if let Some(cookie_path) = cookie_path {
+                sessions = sessions.with_cookie_path(cookie_path);
+            }
+            let mut nested = tide::new();
+            nested.with(sessions);
+            nested.with(
+                OpenIdConnectMiddleware::new(&config)
+                    .await
+                    .with_mount_path("/app"),
+            );
+            let mut app = tide::new();
+            app.at("/app").nest(nested);
+
+            let res = app.get("/app/login").await?;
+            assert_eq!(res.status(), StatusCode::Found);
+            let set_cookies = res.header("Set-Cookie").unwrap();
+            assert_eq!(set_cookies.iter().count(), 1);
+            let cookie = tide::http::Cookie::parse(set_cookies.as_str().to_string())?;
+            assert_eq!(cookie.name(), "tide.sid");
+            assert_eq!(cookie.path(), Some(expected_path));
+        }
+
+        Ok(())
+    })
+    .await
+}
+
+#[async_std::test]
+async fn middleware_can_be_scoped_to_a_route() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(
+        RedirectUrl::new("http://localhost/app/callback".to_string()).unwrap(),
+    )
+    .run_with_emulator(|emu| async move {
+        let mut config = get_config(&emu.issuer_url());
+        config.redirect_url =
+            RedirectUrl::new("http://localhost/app/callback".to_string()).unwrap();
+
+        // Only attach the middleware to the routes below `/app`.
+        let mut app = tide::new();
+        app.with(
+            SessionMiddleware::new(MemoryStore::new(), b"secrets must be >= 32 bytes long")
+                .with_same_site_policy(tide::http::cookies::SameSite::Lax),
+        );
+        app.at("/login").get(|_| async { Ok("unscoped login") });
+        app.at("/app")
+            .with(
+                OpenIdConnectMiddleware::new(&config)
+                    .await
+                    .with_scope_path("/app")
+                    .with_login_landing_path("/app/home"),
+            )
+            .at("*")
+            .get(
+                |req: tide::Request<()>| async move { Ok(format!("user_id={:?}", req.user_id())) },
+            );
+        let client = app.client().with(SessionCookieJarMiddleware::default());
+
+        // Paths outside of the route are not intercepted.
+        let mut res = client.get("/login").await?;
+        assert_response(&mut res, "unscoped login").await;
+
+        // The login and callback paths are relative to the route.
+        let res = client.get("/app/login").await?;
+        let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+        assert_eq!(authorize_url.redirect_uri, "http://localhost/app/callback");
+        let callback_url = emu
+            .add_token("atoken", "openid", "id", &authorize_url)
+            .await;
+        let res = client.get(callback_url).await?;
+        assert_redirect(&res, "/app/home");
+
+        let mut res = client.get("/app/home").await?;
+        assert_response(&mut res, "user_id=Some(\"id\")").await;
+
+        Ok(())
+    })
+    .await
+}
+
+#[async_std::test]
+async fn es256_id_tokens_are_accepted_when_advertised() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            emu.use_es256_signing_key().await;
+
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // The same token is rejected if the application only allows
+            // RS256 signatures.
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_allowed_algorithms(&[CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256]),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn multiple_providers_complete_logins_independently() -> http_types::Result<()> {
+    let google = OpenIdConnectEmulator::new(RedirectUrl::new(
+        "http://localhost/callback/google".to_string(),
+    )?);
+    let sso = OpenIdConnectEmulator::new(RedirectUrl::new(
+        "http://localhost/callback/sso".to_string(),
+    )?);
+    google
+        .run_with_emulator(|google| async move {
+            sso.run_with_emulator(|sso| async move {
+                let mut app = create_test_server();
+                for (provider, emu) in [("google", google), ("sso", sso)] {
+                    let mut config = get_config(&emu.issuer_url());
+                    config.redirect_url =
+                        RedirectUrl::new(format!("http://localhost/callback/{}", provider))?;
+                    app.with(
+                        OpenIdConnectMiddleware::new(&config)
+                            .await
+                            .with_provider(provider)
+                            .with_login_path(&format!("/login/{}", provider)),
+                    );
+                }
+                app.at("/provider")
+                    .get(|req: tide::Request<()>| async move {
+                        Ok(format!(
+                            "{:?}",
+                            req.user().map(|user| (&user.provider, &user.issuer))
+                        ))
+                    });
+                let client = app.client().with(SessionCookieJarMiddleware::default());
+
+                // Start a login with each provider before completing
+                // either of them.
+                let res = client.get("/login/google").await?;
+                let google_authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                let res = client.get("/login/sso").await?;
+                let sso_authorize_url = ParsedAuthorizeUrl::from_response(&res);
+
+                let sso_callback_url = sso
+                    .add_token("sso-token", "openid", "sso-user", &sso_authorize_url)
+                    .await;
+                let google_callback_url = google
+                    .add_token(
+                        "google-token",
+                        "openid",
+                        "google-user",
+                        &google_authorize_url,
+                    )
+                    .await;
+
+                let res = client.get(sso_callback_url).await?;
+                assert_redirect(&res, "/");
+                assert_response(
+                    &mut client.get("/provider").await?,
+                    format!("Some((Some(\"sso\"), {:?}))", sso.issuer_url().as_str()),
+                )
+                .await;
+
+                let res = client.get(google_callback_url).await?;
+                assert_redirect(&res, "/");
+                assert_response(
+                    &mut client.get("/provider").await?,
+                    format!(
+                        "Some((Some(\"google\"), {:?}))",
+                        google.issuer_url().as_str()
+                    ),
+                )
+                .await;
+
+                Ok(())
+            })
+            .await
+        })
+        .await
+}
+
+#[async_std::test]
+async fn session_namespaces_keep_middleware_state_apart() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            // Two applications share a session store (and session cookie),
+            // but keep their authentication state in different namespaces.
+            let store = MemoryStore::new();
+            let mut apps = vec![];
+            for session_namespace in ["app-a", "app-b"] {
+                let mut app = tide::new();
+                app.with(SessionMiddleware::new(
+                    store.clone(),
+                    b"secrets must be >= 32 bytes long",
+                ));
+                app.with(
+                    OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                        .await
+                        .with_session_namespace(session_namespace),
+                );
+                app.at("/").get(|req: tide::Request<()>| async move {
+                    Ok(format!("{:?}", req.user_id()))
+                });
+                app.at("/session-keys")
+                    .get(|req: tide::Request<()>| async move {
+                        let session = serde_json::to_value(req.session())?;
+                        let mut keys: Vec<String> = session["data"]
+                            .as_object()
+                            .map(|data| data.keys().cloned().collect())
+                            .unwrap_or_default();
+                        keys.sort();
+                        Ok(keys.join(","))
+                    });
+                apps.push(app);
+            }
+            let cookie_jar = SessionCookieJarMiddleware::default();
+            let client_a = apps[0].client().with(cookie_jar.clone());
+            let client_b = apps[1].client().with(cookie_jar);
+
+            let res = client_a.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client_a.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Only the application that completed the login sees it.
+            assert_response(&mut client_a.get("/").await?, "Some(\"id\")").await;
+            assert_response(&mut client_b.get("/").await?, "None").await;
+            assert_response(
+                &mut client_b.get("/session-keys").await?,
+                "app-a,app-a.pending",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn landing_paths_are_emitted_intact() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            for callback_response in [CallbackResponse::Redirect, CallbackResponse::Json] {
+                let mut app = create_test_server();
+                app.with(
+                    OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                        .await
+                        .with_callback_response(callback_response)
+                        .with_login_landing_path("/app?tab=home#/dashboard")
+                        .with_logout_landing_path("/#/goodbye"),
+                );
+                let client = app.client().with(SessionCookieJarMiddleware::default());
+
+                // The query string and fragment survive the callback...
+                let res = client.get("/login").await?;
+                let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                let callback_url = emu
+                    .add_token("atoken", "openid", "id", &authorize_url)
+                    .await;
+                let mut res = client.get(callback_url).await?;
+                if callback_response == CallbackResponse::Redirect {
+                    assert_redirect(&res, "/app?tab=home#/dashboard");
+                } else {
+                    let body: serde_json::Value = res.body_json().await?;
+                    assert_eq!(body["redirect"], "/app?tab=home#/dashboard");
+                }
+
+                // ...the redirect for users that are already logged in...
+                let res = client.get("/login").await?;
+                assert_redirect(&res, "/app?tab=home#/dashboard");
+
+                // ...and the logout.
+                let res = client.get("/logout").await?;
+                assert_redirect(&res, "/#/goodbye");
+            }
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn login_returns_to_the_requested_path() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_login_landing_path("/landing"),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Paths on other sites are rejected...
+            for return_to in [
+                "https%3A%2F%2Fevil.example%2F",
+                "%2F%2Fevil.example%2F",
+                "%2F%5Cevil.example%2F",
+            ] {
+                let res = client
+                    .get(format!("/login?return_to={}", return_to))
+                    .await?;
+                assert_eq!(res.status(), StatusCode::BadRequest);
+            }
+
+            // ...whereas paths on this site are returned to instead of the
+            // login landing path...
+            let res = client
+                .get("/login?return_to=%2Freports%3Fyear%3D2024")
+                .await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/reports?year=2024");
+
+            // ...including by users that are already logged in.
+            let res = client.get("/login?return_to=/settings").await?;
+            assert_redirect(&res, "/settings");
+            let res = client.get("/login").await?;
+            assert_redirect(&res, "/landing");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn authenticated_users_skip_the_login_process() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_login_landing_path("/landing"),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/landing");
+
+            // Logging in again goes straight to the landing path...
+            let res = client.get("/login").await?;
+            assert_redirect(&res, "/landing");
+
+            // ...unless the application forces reauthentication.
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_relogin_behavior(ReloginBehavior::ForceReauth),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("btoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let res = client.get("/login").await?;
+            assert_eq!(
+                ParsedAuthorizeUrl::from_response(&res)
+                    .with_nonce(None)
+                    .with_state(None),
+                ParsedAuthorizeUrl::default(),
+            );
 
             Ok(())
         })
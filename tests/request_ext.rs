@@ -0,0 +1,1010 @@
+use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::clock::MockClock;
+use crate::common::cookiejar::SessionCookieJarMiddleware;
+use crate::common::oidc_emulator::OpenIdConnectEmulator;
+use crate::common::{assert_redirect, assert_response, create_test_server, get_config};
+use async_std::sync::{Arc, Mutex};
+use http_types::StatusCode;
+use openidconnect::{
+    EndUserEmail, EndUserName, EndUserPictureUrl, EndUserUsername, LanguageTag, LocalizedClaim,
+    StandardClaims, SubjectIdentifier,
+};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tide::{Middleware, Next, Request};
+use tide_testing::TideTestingExt;
+
+use tide_openidconnect::{
+    GrantedScopes, OpenIdConnectMiddleware, OpenIdConnectRequestExt, RedirectUrl,
+};
+
+pub mod common;
+
+#[derive(Clone, Default)]
+struct AccessLogMiddleware(Arc<Mutex<Vec<Option<GrantedScopes>>>>);
+
+#[tide::utils::async_trait]
+impl Middleware<()> for AccessLogMiddleware {
+    async fn handle(&self, req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+        let res = next.run(req).await;
+        self.0
+            .lock()
+            .await
+            .push(res.ext::<GrantedScopes>().cloned());
+        Ok(res)
+    }
+}
+
+#[async_std::test]
+async fn login_flash_is_available_once() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_login_flash("Welcome back"),
+            );
+            app.at("/flash")
+                .get(|req: Request<()>| async move { Ok(format!("flash={:?}", req.flash())) });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // No flash before the login.
+            assert_response(&mut client.get("/flash").await?, "flash=None").await;
+
+            // Log in.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // The first request after the login gets the flash message...
+            assert_response(
+                &mut client.get("/flash").await?,
+                "flash=Some(\"Welcome back\")",
+            )
+            .await;
+
+            // ...but it has been consumed by the time the next request
+            // arrives.
+            assert_response(&mut client.get("/flash").await?, "flash=None").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn just_logged_in_is_only_set_after_the_callback() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            app.at("/welcome").get(|req: Request<()>| async move {
+                Ok(format!("just_logged_in={}", req.just_logged_in()))
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            assert_response(&mut client.get("/welcome").await?, "just_logged_in=false").await;
+
+            // Log in.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Only the first request after the login sees the flag.
+            assert_response(&mut client.get("/welcome").await?, "just_logged_in=true").await;
+            assert_response(&mut client.get("/welcome").await?, "just_logged_in=false").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn claims_validator_can_reject_logins() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_logout_landing_path("/loggedout")
+                    .with_claims_validator(|claims| match claims.email_verified() {
+                        Some(true) => Ok(()),
+                        _ => Err("Please verify your email address.".to_string()),
+                    }),
+            );
+            app.at("/flash")
+                .get(|req: Request<()>| async move { Ok(format!("flash={:?}", req.flash())) });
+
+            // Users with a verified email address can log in.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let claims = StandardClaims::new(SubjectIdentifier::new("verified".to_string()))
+                .set_email_verified(Some(true));
+            let callback_url = emu
+                .add_token_with_claims("atoken", "openid", claims, &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Users without a verified email address are sent to the
+            // logout landing path, along with the validator's message.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let claims = StandardClaims::new(SubjectIdentifier::new("unverified".to_string()))
+                .set_email_verified(Some(false));
+            let callback_url = emu
+                .add_token_with_claims("btoken", "openid", claims, &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/loggedout");
+
+            assert_response(
+                &mut client.get("/flash").await?,
+                "flash=Some(\"Please verify your email address.\")",
+            )
+            .await;
+            assert_response(&mut client.get("/").await?, "unauthed visits=1").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn logins_can_require_a_verified_email_address() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_require_email_verified(true)
+                    .with_login_rejected_path("/unverified"),
+            );
+            app.at("/flash")
+                .get(|req: Request<()>| async move { Ok(format!("flash={:?}", req.flash())) });
+
+            // Users with a verified email address can log in.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let claims = StandardClaims::new(SubjectIdentifier::new("verified".to_string()))
+                .set_email_verified(Some(true));
+            let callback_url = emu
+                .add_token_with_claims("atoken", "openid", claims, &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            assert_response(
+                &mut client.get("/").await?,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=verified",
+            )
+            .await;
+
+            // Users with an unverified email address (or without the
+            // claim) are sent to the login rejected path.
+            for email_verified in [Some(false), None] {
+                let client = app.client().with(SessionCookieJarMiddleware::default());
+                let res = client.get("/login").await?;
+                let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                let claims = StandardClaims::new(SubjectIdentifier::new("unverified".to_string()))
+                    .set_email_verified(email_verified);
+                let callback_url = emu
+                    .add_token_with_claims("btoken", "openid", claims, &authorize_url)
+                    .await;
+                let res = client.get(callback_url).await?;
+                assert_redirect(&res, "/unverified");
+
+                assert_response(
+                    &mut client.get("/flash").await?,
+                    "flash=Some(\"Email address has not been verified.\")",
+                )
+                .await;
+                assert_response(&mut client.get("/").await?, "unauthed visits=1").await;
+            }
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn logins_can_require_the_requested_scopes() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            for require_requested_scopes in [false, true] {
+                let mut app = create_test_server();
+                app.with(
+                    OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                        .await
+                        .with_scopes(&["profile", "email"])
+                        .with_require_requested_scopes(require_requested_scopes)
+                        .with_login_rejected_path("/rejected"),
+                );
+                app.at("/flash")
+                    .get(|req: Request<()>| async move { Ok(format!("flash={:?}", req.flash())) });
+
+                // Logins that were granted all of the requested scopes
+                // always succeed.
+                let client = app.client().with(SessionCookieJarMiddleware::default());
+                let res = client.get("/login").await?;
+                let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                let callback_url = emu
+                    .add_token("atoken", "openid profile email", "id", &authorize_url)
+                    .await;
+                let res = client.get(callback_url).await?;
+                assert_redirect(&res, "/");
+
+                // The provider did not grant the "profile" scope, which
+                // is only a problem if the requested scopes are required.
+                let client = app.client().with(SessionCookieJarMiddleware::default());
+                let res = client.get("/login").await?;
+                let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                let callback_url = emu
+                    .add_token("btoken", "openid email", "id", &authorize_url)
+                    .await;
+                let res = client.get(callback_url).await?;
+                if require_requested_scopes {
+                    assert_redirect(&res, "/rejected");
+                    assert_response(
+                        &mut client.get("/flash").await?,
+                        "flash=Some(\"Requested scopes were not granted: profile\")",
+                    )
+                    .await;
+                    assert_response(&mut client.get("/").await?, "unauthed visits=1").await;
+                } else {
+                    assert_redirect(&res, "/");
+                    assert_response(
+                        &mut client.get("/").await?,
+                        "authed visits=1 access_token=btoken scopes=[\"openid\", \"email\"] userid=id",
+                    )
+                    .await;
+                }
+            }
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn granted_scopes_round_trip_through_the_session() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_scopes(&["profile", "email"]),
+            );
+
+            // The provider only granted some of the requested scopes.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid email", "id", &authorize_url)
+                .await;
+            client.get(callback_url).await?;
+            assert_response(
+                &mut client.get("/").await?,
+                "authed visits=1 access_token=atoken scopes=[\"openid\", \"email\"] userid=id",
+            )
+            .await;
+
+            // The provider granted all of the requested scopes (and so did
+            // not include the scopes in the token response).
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu.add_token("btoken", "", "id", &authorize_url).await;
+            client.get(callback_url).await?;
+            assert_response(
+                &mut client.get("/").await?,
+                "authed visits=1 access_token=btoken scopes=[\"openid\", \"profile\", \"email\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn token_expiry_and_authentication_time_are_available() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            app.at("/times").get(|req: Request<()>| async move {
+                let secs = |t: Option<SystemTime>| {
+                    t.map_or("-".to_string(), |t| {
+                        t.duration_since(UNIX_EPOCH).unwrap().as_secs().to_string()
+                    })
+                };
+                Ok(format!(
+                    "{} {}",
+                    secs(req.expires_at()),
+                    secs(req.authenticated_at())
+                ))
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Unauthenticated requests do not have timing information.
+            assert_response(&mut client.get("/times").await?, "- -").await;
+
+            // Log in, noting the time before and after the callback.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let before = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            let after = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+            // The emulator issues tokens that expire in an hour, and the
+            // authentication time is the time of the login.
+            let body = client.get("/times").recv_string().await?;
+            let (expires_at, authenticated_at) = body.split_once(' ').unwrap();
+            let expires_at: u64 = expires_at.parse()?;
+            let authenticated_at: u64 = authenticated_at.parse()?;
+            let expires_in = Duration::from_secs(3600).as_secs();
+            assert!((before + expires_in..=after + expires_in).contains(&expires_at));
+            assert!((before..=after).contains(&authenticated_at));
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn access_token_remaining_lifetime_is_available() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let clock = MockClock::default();
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_clock(clock.clone()),
+            );
+            app.at("/expires-in").get(|req: Request<()>| async move {
+                Ok(format!("{:?}", req.access_token_expires_in()))
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Unauthenticated requests do not have an access token.
+            assert_response(&mut client.get("/expires-in").await?, "None").await;
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // The emulator issues tokens that expire in an hour, and no
+            // refresh token, so the remaining lifetime counts down to
+            // zero.
+            assert_response(&mut client.get("/expires-in").await?, "Some(3600s)").await;
+            clock.advance(Duration::from_secs(45 * 60));
+            assert_response(&mut client.get("/expires-in").await?, "Some(900s)").await;
+            clock.advance(Duration::from_secs(30 * 60));
+            assert_response(&mut client.get("/expires-in").await?, "Some(0ns)").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn granted_scopes_are_available_to_logging_middleware() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+
+            // "Access log" middleware that runs *before* the OpenID Connect
+            // middleware, and so can only see the response.
+            let access_log = AccessLogMiddleware::default();
+            app.with(access_log.clone());
+
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+
+            // Handlers (and any middleware that runs *after* the OpenID
+            // Connect middleware) can see the request extension.
+            app.at("/scopes").get(|req: Request<()>| async move {
+                Ok(format!("{:?}", req.ext::<GrantedScopes>()))
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Unauthenticated requests do not have any granted scopes.
+            assert_response(&mut client.get("/scopes").await?, "None").await;
+            assert_eq!(access_log.0.lock().await.pop(), Some(None));
+
+            // Log in.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid profile", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Authenticated requests provide the granted scopes on both the
+            // request and the response.
+            let granted_scopes = GrantedScopes(vec!["openid".to_string(), "profile".to_string()]);
+            assert_response(
+                &mut client.get("/scopes").await?,
+                format!("{:?}", Some(&granted_scopes)),
+            )
+            .await;
+            assert_eq!(access_log.0.lock().await.pop(), Some(Some(granted_scopes)));
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn strict_authentication_rejects_expired_tokens() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let clock = MockClock::default();
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_strict_authentication(true)
+                    .with_clock(clock.clone()),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // The emulator's access tokens expire after an hour, and no
+            // refresh token was issued.
+            clock.advance(Duration::from_secs(2 * 60 * 60));
+            let mut res = client.get("/").await?;
+            assert_response(&mut res, "unauthed visits=2").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn authenticated_user_is_populated_after_login() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            app.at("/user").get(|req: Request<()>| async move {
+                Ok(match req.user() {
+                    Some(user) => format!(
+                        "subject={} issuer={} email={:?} email_verified={:?} name={:?} preferred_username={:?}",
+                        user.subject,
+                        user.issuer,
+                        user.email,
+                        user.email_verified,
+                        user.name,
+                        user.preferred_username,
+                    ),
+                    None => "unauthed".to_string(),
+                })
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            assert_response(&mut client.get("/user").await?, "unauthed").await;
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let mut name = LocalizedClaim::new();
+            name.insert(None, EndUserName::new("Bilbo Baggins".to_string()));
+            let claims = StandardClaims::new(SubjectIdentifier::new("bilbo".to_string()))
+                .set_email(Some(EndUserEmail::new("bilbo@example.com".to_string())))
+                .set_email_verified(Some(true))
+                .set_name(Some(name))
+                .set_preferred_username(Some(EndUserUsername::new("bilbo.b".to_string())));
+            let callback_url = emu
+                .add_token_with_claims("atoken", "openid", claims, &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            assert_response(
+                &mut client.get("/user").await?,
+                format!(
+                    "subject=bilbo issuer={} email=Some(\"bilbo@example.com\") email_verified=Some(true) name=Some(\"Bilbo Baggins\") preferred_username=Some(\"bilbo.b\")",
+                    emu.issuer_url().as_str()
+                ),
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn standard_claims_have_typed_accessors() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            app.at("/profile").get(|req: Request<()>| async move {
+                Ok(format!(
+                    "email={:?} name={:?} picture={:?} locale={:?}",
+                    req.email(),
+                    req.name(),
+                    req.picture(),
+                    req.locale(),
+                ))
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            assert_response(
+                &mut client.get("/profile").await?,
+                "email=None name=None picture=None locale=None",
+            )
+            .await;
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let mut name = LocalizedClaim::new();
+            name.insert(None, EndUserName::new("Bilbo Baggins".to_string()));
+            let mut picture = LocalizedClaim::new();
+            picture.insert(
+                None,
+                EndUserPictureUrl::new("https://example.com/bilbo.png".to_string()),
+            );
+            let claims = StandardClaims::new(SubjectIdentifier::new("bilbo".to_string()))
+                .set_email(Some(EndUserEmail::new("bilbo@example.com".to_string())))
+                .set_name(Some(name))
+                .set_picture(Some(picture))
+                .set_locale(Some(LanguageTag::new("en-GB".to_string())));
+            let callback_url = emu
+                .add_token_with_claims("atoken", "openid", claims, &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            assert_response(
+                &mut client.get("/profile").await?,
+                "email=Some(\"bilbo@example.com\") name=Some(\"Bilbo Baggins\") picture=Some(\"https://example.com/bilbo.png\") locale=Some(\"en-GB\")",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn user_id_can_be_drawn_from_another_claim() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_user_id_claim("email"),
+            );
+
+            // Users with an email address are identified by that address.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let claims = StandardClaims::new(SubjectIdentifier::new("bilbo".to_string()))
+                .set_email(Some(EndUserEmail::new("bilbo@example.com".to_string())));
+            let callback_url = emu
+                .add_token_with_claims("atoken", "openid", claims, &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            assert_response(
+                &mut client.get("/").await?,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=bilbo@example.com",
+            )
+            .await;
+
+            // Users without an email address fall back to the subject.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("btoken", "openid", "frodo", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            assert_response(
+                &mut client.get("/").await?,
+                "authed visits=1 access_token=btoken scopes=[\"openid\"] userid=frodo",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn user_id_claim_must_be_a_string() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_user_id_claim("email_verified"),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let claims = StandardClaims::new(SubjectIdentifier::new("bilbo".to_string()))
+                .set_email_verified(Some(true));
+            let callback_url = emu
+                .add_token_with_claims("atoken", "openid", claims, &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert_response(&mut client.get("/").await?, "unauthed visits=1").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn only_the_selected_claims_are_stored_in_the_session() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_session_claims(&["email"]),
+            );
+            app.at("/claims").get(|req: Request<()>| async move {
+                let claims = serde_json::to_value(req.user_info())?;
+                let mut names: Vec<String> = claims
+                    .as_object()
+                    .map(|fields| fields.keys().cloned().collect())
+                    .unwrap_or_default();
+                names.sort();
+                Ok(names.join(","))
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let mut name = LocalizedClaim::new();
+            name.insert(None, EndUserName::new("Bilbo Baggins".to_string()));
+            let claims = StandardClaims::new(SubjectIdentifier::new("bilbo".to_string()))
+                .set_email(Some(EndUserEmail::new("bilbo@example.com".to_string())))
+                .set_name(Some(name))
+                .set_preferred_username(Some(EndUserUsername::new("bilbo.b".to_string())));
+            let callback_url = emu
+                .add_token_with_claims("atoken", "openid", claims, &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            assert_response(&mut client.get("/claims").await?, "email,sub").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn user_info_claims_are_refreshed_after_the_interval() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let clock = MockClock::default();
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_clock(clock.clone())
+                    .with_claims_refresh_interval(Duration::from_secs(600)),
+            );
+            app.at("/email").get(|req: Request<()>| async move {
+                Ok(format!(
+                    "{:?}",
+                    req.user_info().and_then(|claims| claims.email().cloned())
+                ))
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let claims = StandardClaims::new(SubjectIdentifier::new("bilbo".to_string()))
+                .set_email(Some(EndUserEmail::new("bilbo@example.com".to_string())));
+            let callback_url = emu
+                .add_token_with_claims("atoken", "openid", claims, &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            assert_eq!(emu.userinfo_requests(), 1);
+
+            // The user updates their profile at the provider, but the
+            // claims are not refreshed until the interval has elapsed.
+            let claims = StandardClaims::new(SubjectIdentifier::new("bilbo".to_string()))
+                .set_email(Some(EndUserEmail::new("baggins@example.com".to_string())));
+            emu.update_claims("atoken", claims).await;
+            clock.advance(Duration::from_secs(300));
+            assert_response(
+                &mut client.get("/email").await?,
+                "Some(EndUserEmail(\"bilbo@example.com\"))",
+            )
+            .await;
+            assert_eq!(emu.userinfo_requests(), 1);
+
+            // Failed refreshes keep the existing claims (and are retried
+            // on the next request).
+            clock.advance(Duration::from_secs(301));
+            emu.fail_userinfo_requests(1);
+            assert_response(
+                &mut client.get("/email").await?,
+                "Some(EndUserEmail(\"bilbo@example.com\"))",
+            )
+            .await;
+            assert_eq!(emu.userinfo_requests(), 2);
+
+            assert_response(
+                &mut client.get("/email").await?,
+                "Some(EndUserEmail(\"baggins@example.com\"))",
+            )
+            .await;
+            assert_eq!(emu.userinfo_requests(), 3);
+
+            // The interval restarts after a successful refresh.
+            assert_response(
+                &mut client.get("/email").await?,
+                "Some(EndUserEmail(\"baggins@example.com\"))",
+            )
+            .await;
+            assert_eq!(emu.userinfo_requests(), 3);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn requests_can_authenticate_with_a_bearer_token() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_bearer_token_auth(true),
+            );
+            let client = app.client();
+
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let claims = |issuer: &str, audience: &str| {
+                serde_json::json!({
+                    "iss": issuer,
+                    "aud": audience,
+                    "sub": "reporting-service",
+                    "iat": now,
+                    "exp": now + 3600,
+                    "scope": "openid reports:read",
+                })
+            };
+
+            // A valid token authenticates the request, without a login.
+            let bearer_token = emu
+                .create_bearer_token(claims(emu.issuer_url().as_str(), "CLIENT-ID"))
+                .await;
+            let mut res = client
+                .get("/")
+                .header("Authorization", format!("Bearer {}", bearer_token))
+                .await?;
+            assert_response(
+                &mut res,
+                format!(
+                    "authed visits=1 access_token={} scopes=[\"openid\", \"reports:read\"] userid=reporting-service",
+                    bearer_token
+                ),
+            )
+            .await;
+
+            // Invalid tokens are rejected outright, including ID tokens
+            // that were issued for this client.
+            for bearer_token in [
+                emu.create_id_token(claims(emu.issuer_url().as_str(), "CLIENT-ID"))
+                    .await,
+                emu.create_bearer_token(claims(emu.issuer_url().as_str(), "OTHER-CLIENT"))
+                    .await,
+                emu.create_bearer_token(claims("https://example.com/", "CLIENT-ID"))
+                    .await,
+                "not-a-jwt".to_string(),
+            ] {
+                let res = client
+                    .get("/")
+                    .header("Authorization", format!("Bearer {}", bearer_token))
+                    .await?;
+                assert_eq!(res.status(), StatusCode::Unauthorized);
+                assert_eq!(
+                    res.header("WWW-Authenticate").map(|values| values.as_str()),
+                    Some("Bearer error=\"invalid_token\"")
+                );
+            }
+
+            // Requests without a bearer token use the session, as usual.
+            assert_response(&mut client.get("/").await?, "unauthed visits=1").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn bearer_tokens_must_be_issued_for_the_bearer_audience() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_bearer_token_auth(true)
+                    .with_bearer_audience("https://api.example.com"),
+            );
+            let client = app.client();
+
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let claims = |audience: &str| {
+                serde_json::json!({
+                    "iss": emu.issuer_url().as_str(),
+                    "aud": audience,
+                    "sub": "reporting-service",
+                    "iat": now,
+                    "exp": now + 3600,
+                })
+            };
+
+            // Tokens for the API are accepted...
+            let bearer_token = emu
+                .create_bearer_token(claims("https://api.example.com"))
+                .await;
+            let res = client
+                .get("/")
+                .header("Authorization", format!("Bearer {}", bearer_token))
+                .await?;
+            assert_eq!(res.status(), StatusCode::Ok);
+
+            // ...whereas tokens for the client are not.
+            let bearer_token = emu.create_bearer_token(claims("CLIENT-ID")).await;
+            let res = client
+                .get("/")
+                .header("Authorization", format!("Bearer {}", bearer_token))
+                .await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn authorization_checks_use_the_stored_claims() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            app.at("/authorized").get(|req: Request<()>| async move {
+                Ok(format!(
+                    "staff={} admin={} sub={}",
+                    req.is_authorized_for("groups", "staff"),
+                    req.is_authorized_for("groups", "admin"),
+                    req.is_authorized_for("sub", "bilbo"),
+                ))
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Unauthenticated requests are not authorized for anything.
+            assert_response(
+                &mut client.get("/authorized").await?,
+                "staff=false admin=false sub=false",
+            )
+            .await;
+
+            emu.include_id_token_claims(serde_json::json!({ "groups": ["staff", "users"] }))
+                .await;
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "bilbo", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            assert_response(
+                &mut client.get("/authorized").await?,
+                "staff=true admin=false sub=true",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn enriched_claims_are_available_after_login() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_claim_enricher(|claims| {
+                        let mut enriched = HashMap::new();
+                        enriched.insert(
+                            "app_role".to_string(),
+                            serde_json::json!(format!("editor-{}", claims.subject().as_str())),
+                        );
+                        enriched
+                    }),
+            );
+            app.at("/role").get(|req: Request<()>| async move {
+                Ok(format!(
+                    "{:?} {}",
+                    req.claims().and_then(|claims| claims.get("app_role")),
+                    req.is_authorized_for("app_role", "editor-bilbo"),
+                ))
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "bilbo", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // The enriched claim was stored in the session, and so is
+            // available to every subsequent request.
+            assert_response(
+                &mut client.get("/role").await?,
+                "Some(String(\"editor-bilbo\")) true",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
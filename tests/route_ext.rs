@@ -6,8 +6,10 @@ use http_types::StatusCode;
 use tide::Request;
 use tide_testing::TideTestingExt;
 
+use tide_openidconnect::redirect_strategy::HttpRedirect;
 use tide_openidconnect::{
     OpenIdConnectMiddleware, OpenIdConnectRequestExt, OpenIdConnectRouteExt, RedirectUrl,
+    UnauthenticatedBehavior,
 };
 
 pub mod common;
@@ -97,3 +99,195 @@ async fn authentication_protects_subsequent_verbs() -> http_types::Result<()> {
         })
         .await
 }
+
+#[async_std::test]
+async fn authenticated_routes_can_return_unauthorized() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_unauthenticated_response(UnauthenticatedBehavior::Unauthorized),
+            );
+            app.at("/needsauth")
+                .authenticated()
+                .get(|_req: Request<()>| async move { Ok("authed") });
+
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Unauthenticated requests are rejected instead of redirected.
+            let res = client.get("/needsauth").await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            // The client can still choose to go through the login process.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/needsauth").await?;
+            assert_response(&mut res, "authed").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn unauthenticated_response_can_be_negotiated() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_unauthenticated_response(UnauthenticatedBehavior::Negotiate),
+            );
+            app.at("/needsauth")
+                .authenticated()
+                .get(|_req: Request<()>| async move { Ok("authed") });
+
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Browsers are sent through the login process...
+            let res = client
+                .get("/needsauth")
+                .header("Accept", "text/html,application/xhtml+xml,*/*;q=0.8")
+                .await?;
+            assert_redirect(&res, "/login");
+
+            // ...whereas API clients are told that they are unauthorized.
+            let res = client
+                .get("/needsauth")
+                .header("Accept", "application/json")
+                .await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn htmx_requests_are_redirected_with_a_header() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_htmx_support(true),
+            );
+            app.at("/needsauth")
+                .authenticated()
+                .get(|_req: Request<()>| async move { Ok("authed") });
+
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // HTMX requests are told to navigate to the login path...
+            let res = client
+                .get("/needsauth")
+                .header("HX-Request", "true")
+                .await?;
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert_eq!(res["HX-Redirect"], "/login");
+
+            // ...whereas other requests are redirected as usual.
+            let res = client.get("/needsauth").await?;
+            assert_redirect(&res, "/login");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn scoped_routes_require_the_granted_scope() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            app.at("/admin/report")
+                .require_scope("reports:read")
+                .get(|_req: Request<()>| async move { Ok("report") });
+            app.at("/admin/users")
+                .require_scope("users:write")
+                .get(|_req: Request<()>| async move { Ok("users") });
+
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Unauthenticated requests are sent through the login process.
+            assert_redirect(&client.get("/admin/report").await?, "/login");
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid reports:read", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Only the route whose scope was granted is accessible.
+            assert_response(&mut client.get("/admin/report").await?, "report").await;
+            let res = client.get("/admin/users").await?;
+            assert_eq!(res.status(), StatusCode::Forbidden);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn login_required_message_is_available_once() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_unauthenticated_redirect_strategy(HttpRedirect::new("/signin"))
+                    .with_login_required_message("Please sign in to continue."),
+            );
+            app.at("/needsauth")
+                .authenticated()
+                .get(|_| async { Ok("authed") });
+            app.at("/signin").get(|req: Request<()>| async move {
+                Ok(format!("login_flash={:?}", req.login_flash()))
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The message is queued up by the redirect to the sign in
+            // page, and is only available to the sign in page once.
+            let res = client.get("/needsauth").await?;
+            assert_redirect(&res, "/signin");
+            let mut res = client.get("/signin").await?;
+            assert_response(
+                &mut res,
+                "login_flash=Some(\"Please sign in to continue.\")",
+            )
+            .await;
+            let mut res = client.get("/signin").await?;
+            assert_response(&mut res, "login_flash=None").await;
+
+            // Completing the login discards a message that was never
+            // displayed.
+            let res = client.get("/needsauth").await?;
+            assert_redirect(&res, "/signin");
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            let mut res = client.get("/signin").await?;
+            assert_response(&mut res, "login_flash=None").await;
+
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,110 @@
+use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::cookiejar::SessionCookieJarMiddleware;
+use crate::common::get_config;
+use crate::common::oidc_emulator::OpenIdConnectEmulator;
+use http_types::{headers::LOCATION, StatusCode};
+use std::sync::Mutex;
+use tide::sessions::{MemoryStore, Session, SessionMiddleware, SessionStore};
+use tide_testing::TideTestingExt;
+
+use tide_openidconnect::{OpenIdConnectMiddleware, RedirectUrl};
+
+pub mod common;
+
+/// Logger that keeps the messages of all errors, so that tests can
+/// check for them. This is the only test in this file, since there can
+/// only be one logger per process.
+struct ErrorLog(Mutex<Vec<String>>);
+
+impl log::Log for ErrorLog {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= log::Level::Error
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static ERRORS: ErrorLog = ErrorLog(Mutex::new(Vec::new()));
+
+/// Session store that fails to save sessions that contain the
+/// middleware's authentication state, as a Redis store would if Redis
+/// went down during a login.
+#[derive(Debug, Clone)]
+struct FailingSessionStore(MemoryStore);
+
+#[tide::utils::async_trait]
+impl SessionStore for FailingSessionStore {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        self.0.load_session(cookie_value).await
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        if session.get_raw("tide.oidc").is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                "session store is unavailable",
+            )
+            .into());
+        }
+        self.0.store_session(session).await
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        self.0.destroy_session(session).await
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        self.0.clear_store().await
+    }
+}
+
+#[async_std::test]
+async fn session_store_failures_fail_the_callback() -> http_types::Result<()> {
+    log::set_logger(&ERRORS).unwrap();
+    log::set_max_level(log::LevelFilter::Error);
+
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let store = FailingSessionStore(MemoryStore::new());
+            let mut app = tide::new();
+            app.with(
+                SessionMiddleware::new(store.clone(), b"secrets must be >= 32 bytes long")
+                    .with_same_site_policy(tide::http::cookies::SameSite::Lax),
+            );
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_session_store(store),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+
+            // The browser is not sent to the landing page, since the
+            // login was not saved, and the failure is reported by the
+            // middleware itself.
+            let res = client.get(callback_url).await?;
+            assert_eq!(res.status(), StatusCode::InternalServerError);
+            assert!(res.header(LOCATION).is_none());
+            #[cfg(not(feature = "tracing"))]
+            assert!(ERRORS
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|error| error.contains("Failed to save the session")));
+
+            Ok(())
+        })
+        .await
+}
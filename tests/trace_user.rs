@@ -0,0 +1,138 @@
+#![cfg(feature = "tracing")]
+
+use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::cookiejar::SessionCookieJarMiddleware;
+use crate::common::oidc_emulator::OpenIdConnectEmulator;
+use crate::common::{assert_redirect, create_test_server, get_config};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tide::{Middleware, Next, Request};
+use tide_testing::TideTestingExt;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Instrument, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+use tide_openidconnect::{OpenIdConnectMiddleware, RedirectUrl};
+
+pub mod common;
+
+/// Subscriber that keeps the values recorded in the `user_id` field of
+/// any span, so that tests can check for them. This is the only test in
+/// this file, since there can only be one global subscriber per process.
+#[derive(Default)]
+struct UserIdRecorder {
+    next_id: AtomicU64,
+    metadata: Mutex<HashMap<u64, &'static Metadata<'static>>>,
+    user_ids: Mutex<Vec<String>>,
+}
+
+thread_local! {
+    static ENTERED_SPANS: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
+}
+
+struct UserIdVisitor<'a>(&'a Mutex<Vec<String>>);
+
+impl Visit for UserIdVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "user_id" {
+            self.0.lock().unwrap().push(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "user_id" {
+            self.0.lock().unwrap().push(format!("{:?}", value));
+        }
+    }
+}
+
+impl Subscriber for &'static UserIdRecorder {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        self.metadata.lock().unwrap().insert(id, span.metadata());
+        Id::from_u64(id)
+    }
+
+    fn record(&self, _span: &Id, values: &Record<'_>) {
+        values.record(&mut UserIdVisitor(&self.user_ids));
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        ENTERED_SPANS.with(|spans| spans.borrow_mut().push(span.clone()));
+    }
+
+    fn exit(&self, _span: &Id) {
+        ENTERED_SPANS.with(|spans| spans.borrow_mut().pop());
+    }
+
+    fn current_span(&self) -> Current {
+        match ENTERED_SPANS.with(|spans| spans.borrow().last().cloned()) {
+            Some(id) => {
+                let metadata = self.metadata.lock().unwrap()[&id.into_u64()];
+                Current::new(id, metadata)
+            }
+            None => Current::none(),
+        }
+    }
+}
+
+/// Middleware that runs each request in a span with a `user_id` field.
+struct RequestSpanMiddleware;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequestSpanMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let span = tracing::info_span!("request", user_id = tracing::field::Empty);
+        Ok(next.run(req).instrument(span).await)
+    }
+}
+
+#[async_std::test]
+async fn authenticated_user_id_is_recorded_on_the_span() -> http_types::Result<()> {
+    let recorder: &'static UserIdRecorder = Box::leak(Box::default());
+    tracing::subscriber::set_global_default(recorder).unwrap();
+
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(RequestSpanMiddleware);
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_trace_user(true),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Nothing is recorded for unauthenticated requests...
+            client.get("/").await?;
+            assert!(recorder.user_ids.lock().unwrap().is_empty());
+
+            // ...whereas authenticated requests record the user id.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "bilbo", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+            client.get("/").await?;
+            assert_eq!(
+                *recorder.user_ids.lock().unwrap(),
+                vec!["bilbo".to_string()]
+            );
+
+            Ok(())
+        })
+        .await
+}